@@ -0,0 +1,63 @@
+//! Compares running the content transforms on a whole chapter at once
+//! with running them on each paragraph as a separate fragment, as was done before.
+//! Run with `cargo bench --bench whole_chapter`.
+
+#[macro_use]
+extern crate criterion;
+extern crate reqwest;
+extern crate rust_wildbow_scraper;
+extern crate scraper;
+
+use criterion::Criterion;
+use reqwest::Url;
+use rust_wildbow_scraper::cached_client::CachedClient;
+use rust_wildbow_scraper::transforms::{Pipeline, TransformContext, TransformOptions, TransformRegistry, DEFAULT_TRANSFORMS};
+use rust_wildbow_scraper::xml_utils::XmlSerializable;
+use scraper::{Html, Selector};
+use std::collections::HashMap;
+
+/// Number of paragraphs in the chapter, about as many as in the longest chapters.
+const PARAGRAPHS: usize = 2000;
+
+fn whole_chapter(c: &mut Criterion) {
+    let paragraph = r#"<p style="padding-left:40px;">Taylor <em>ran</em>, <span>the swarm</span> following <strong>close</strong> behind.</p>"#;
+    let chapter = paragraph.repeat(PARAGRAPHS);
+    let paragraph_selector = Selector::parse("p").unwrap();
+    let pipeline = Pipeline::from_names(&TransformRegistry::new(), DEFAULT_TRANSFORMS, &TransformOptions::default()).unwrap();
+    let client = CachedClient::new(None).unwrap();
+    let page_url = Url::parse("https://example.com/").unwrap();
+    let mut images = HashMap::new();
+    let mut missing_images = Vec::new();
+    let mut context = TransformContext {
+        client: &client,
+        images: &mut images,
+        missing_images: &mut missing_images,
+        page_url: &page_url,
+    };
+
+    let mut group = c.benchmark_group(format!("{PARAGRAPHS} paragraphs"));
+    group.sample_size(10);
+    group.bench_function("per paragraph", |b| {
+        b.iter(|| {
+            let doc = Html::parse_fragment(&chapter);
+            doc.select(&paragraph_selector)
+                .map(|paragraph| {
+                    let mut fragment = Html::parse_fragment(&paragraph.inner_xml());
+                    pipeline.apply(&mut fragment, &mut context).unwrap();
+                    fragment.root_element().inner_xml()
+                })
+                .collect::<String>()
+        })
+    });
+    group.bench_function("whole chapter", |b| {
+        b.iter(|| {
+            let mut doc = Html::parse_fragment(&chapter);
+            pipeline.apply(&mut doc, &mut context).unwrap();
+            doc.root_element().inner_xml()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, whole_chapter);
+criterion_main!(benches);
@@ -0,0 +1,319 @@
+use dom_manipulation::DomOperation;
+use easy_error::{err_msg, Error};
+use ego_tree::NodeId;
+use markup5ever::Attribute;
+use scraper::{ElementRef, Html, Node, Selector};
+use std::collections::HashSet;
+use std::str::FromStr;
+#[cfg(test)]
+use transforms::apply_offline;
+use transforms::{Transform, TransformContext, CONTENT_ELEMENT_SELECTOR};
+use xml_utils::{html_attr_name, html_elem_name, FilterableTree};
+
+/// What to do with author’s notes found in chapter content.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AuthorNotes {
+    /// Remove them from the book.
+    #[default]
+    Drop,
+    /// Keep them in place, wrapped in a distinctly styled `<aside class="author-note">`.
+    Aside,
+    /// Move them to an “Author’s Notes” appendix at the end of the book.
+    /// The transform marks them the same way as for `Aside`, they are moved during serialization.
+    Appendix,
+}
+
+impl FromStr for AuthorNotes {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Self::Drop),
+            "aside" => Ok(Self::Aside),
+            "appendix" => Ok(Self::Appendix),
+            _ => Err(err_msg(format!("Unknown author’s notes mode {s}, expected one of: drop, aside, appendix"))),
+        }
+    }
+}
+
+/// Kinds of paragraphs that are not part of the story.
+/// Sharing and related posts widgets are recognized by their containers instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BoilerplateKind {
+    /// “Last Chapter / Next Chapter” links.
+    Navigation,
+    /// Patreon, PayPal and web fiction ranking pitches.
+    Donation,
+    /// Announcements that a sequel has started.
+    SequelBanner,
+    /// Notes from the author about schedule, thanks or content warnings.
+    AuthorNote,
+}
+
+lazy_static! {
+    static ref WIDGET_SELECTOR: Selector = Selector::parse("div.sharedaddy, div.sd-block, div.jp-relatedposts, div#jp-post-flair, div.wpcnt, div.wordads-ad-wrapper, div.jetpack-likes-widget-wrapper").unwrap();
+    static ref LINK_SELECTOR: Selector = Selector::parse("a").unwrap();
+    static ref IMAGE_SELECTOR: Selector = Selector::parse("img").unwrap();
+}
+
+/// Link labels used for navigating between chapters, compared after lower-casing and trimming arrows.
+const NAVIGATION_LABELS: &[&str] = &[
+    "next", "next chapter", "last", "last chapter", "previous", "previous chapter", "prev",
+    "first chapter", "table of contents", "index", "home",
+];
+
+/// Hosts of links asking readers for support.
+const DONATION_HOSTS: &[&str] = &["patreon.com", "paypal.com", "paypal.me", "ko-fi.com", "topwebfiction.com", "webfictionguide.com"];
+
+/// Phrases (lower-cased) that mark a short paragraph with a link as asking readers for support.
+const DONATION_PHRASES: &[&str] = &["patreon", "paypal", "donate", "donation", "ko-fi", "topwebfiction", "vote for"];
+
+/// Longest paragraph, in words, recognized as a donation pitch by its wording rather than by where it links.
+const MAX_PITCH_WORDS: usize = 40;
+
+/// Phrases (lower-cased) announcing a sequel.
+const SEQUEL_PHRASES: &[&str] = &["sequel is live", "sequel is up", "sequel is now live", "sequel is now up", "the sequel to"];
+
+/// Prefixes (lower-cased, with straight apostrophes) that start an author’s note.
+const AUTHOR_NOTE_PREFIXES: &[&str] = &[
+    "author's note", "authors note", "author note", "a/n", "wildbow's note", "wildbow note", "schedule note",
+];
+
+/// Prefixes (lower-cased) of author’s notes warning about the story that follows them, which are a single paragraph.
+const WARNING_PREFIXES: &[&str] = &["content warning", "trigger warning"];
+
+/// Most elements between the start of an author’s note and the rule ending it.
+const MAX_NOTE_ELEMENTS: usize = 3;
+
+/// Lower-cased text of the element with typographic apostrophes normalized.
+fn normalized_text<'a, I: Iterator<Item = &'a str>>(text: I) -> String {
+    text.collect::<String>().trim().to_lowercase().replace('’', "'")
+}
+
+/// Whether the text contains nothing but whitespace and separators.
+fn is_only_separators(text: &str) -> bool {
+    text.chars().all(|c| c.is_whitespace() || c.is_ascii_punctuation() || "«»‹›←→◄►|•·–—".contains(c))
+}
+
+fn is_navigation_label(text: &str) -> bool {
+    let label = text.trim_matches(|c: char| c.is_whitespace() || c.is_ascii_punctuation() || "«»‹›←→◄►".contains(c));
+    NAVIGATION_LABELS.contains(&label)
+}
+
+/// Determines what kind of boilerplate, if any, a content paragraph is.
+pub fn classify(elem: ElementRef) -> Option<BoilerplateKind> {
+    let text = normalized_text(elem.text());
+    let links: Vec<ElementRef> = elem.select(&LINK_SELECTOR).collect();
+    let has_image = elem.select(&IMAGE_SELECTOR).next().is_some();
+
+    let text_outside_links = elem.text_filter(|elem| elem.name != html_elem_name("a")).collect::<String>();
+    if !has_image
+        && !links.is_empty()
+        && is_only_separators(&text_outside_links)
+        && links.iter().all(|link| is_navigation_label(&normalized_text(link.text())))
+    {
+        return Some(BoilerplateKind::Navigation);
+    }
+
+    if AUTHOR_NOTE_PREFIXES.iter().chain(WARNING_PREFIXES).any(|prefix| text.starts_with(prefix)) {
+        return Some(BoilerplateKind::AuthorNote);
+    }
+
+    let links_to_donation = links.iter().any(|link| {
+        link.value().attr("href").is_some_and(|href| DONATION_HOSTS.iter().any(|host| href.contains(host)))
+    });
+    // The phrases alone also appear in the story, so they only count in short paragraphs built around a link,
+    // and never in dialogue.
+    let is_donation_pitch = !links.is_empty()
+        && text.split_whitespace().count() <= MAX_PITCH_WORDS
+        && !text.contains(['“', '”', '"'])
+        && DONATION_PHRASES.iter().any(|phrase| text.contains(phrase));
+    if !has_image && (links_to_donation || is_donation_pitch) {
+        return Some(BoilerplateKind::Donation);
+    }
+
+    if !links.is_empty() && SEQUEL_PHRASES.iter().any(|phrase| text.contains(phrase)) {
+        return Some(BoilerplateKind::SequelBanner);
+    }
+
+    None
+}
+
+fn is_rule(node: &Node) -> bool {
+    matches!(node, Node::Element(elem) if elem.name == html_elem_name("hr"))
+}
+
+/// Paragraph starting an author’s note together with the sibling elements following it that belong to the note:
+/// up to a horizontal rule closely following it, or to the end of the parent for a note after the last rule.
+/// Other notes, like a content warning at the start of the chapter, are only the paragraph itself,
+/// so that the story after them is kept.
+fn author_note_block(start: ElementRef) -> Vec<ElementRef> {
+    let mut block = vec![start];
+    if WARNING_PREFIXES.iter().any(|prefix| normalized_text(start.text()).starts_with(prefix)) {
+        return block;
+    }
+    let following: Vec<_> = start.next_siblings().filter(|sibling| matches!(sibling.value(), Node::Element(_))).collect();
+    let extends = match following.iter().position(|sibling| is_rule(sibling.value())) {
+        Some(elements) => elements <= MAX_NOTE_ELEMENTS,
+        None => start.prev_siblings().any(|sibling| is_rule(sibling.value())),
+    };
+    if !extends {
+        return block;
+    }
+    for sibling in following {
+        match sibling.value() {
+            node if is_rule(node) => break,
+            _ => {
+                let sibling = ElementRef::wrap(sibling).unwrap();
+                // Other boilerplate will be removed on its own.
+                let is_boilerplate = if CONTENT_ELEMENT_SELECTOR.matches(&sibling) {
+                    classify(sibling).is_some()
+                } else {
+                    sibling.select(&CONTENT_ELEMENT_SELECTOR).any(|p| classify(p).is_some())
+                };
+                if !is_boilerplate {
+                    block.push(sibling);
+                }
+            }
+        }
+    }
+    block
+}
+
+/// Removes navigation links, donation pitches, sequel banners and share widgets,
+/// and drops author’s notes or sets them aside, depending on the configuration.
+pub struct Boilerplate {
+    author_notes: AuthorNotes,
+}
+
+impl Boilerplate {
+    pub fn new(author_notes: AuthorNotes) -> Self {
+        Self { author_notes }
+    }
+}
+
+impl Transform for Boilerplate {
+    fn name(&self) -> &'static str {
+        "boilerplate"
+    }
+
+    fn operations(&self, doc: &Html, _context: &mut TransformContext) -> Result<Vec<DomOperation<NodeId>>, Error> {
+        let mut ops = Vec::new();
+        let mut handled = HashSet::new();
+
+        for widget in doc.select(&WIDGET_SELECTOR) {
+            if handled.insert(widget.id()) {
+                ops.push(DomOperation::RemoveElement { node_id: widget.id() });
+            }
+        }
+
+        for paragraph in doc.select(&CONTENT_ELEMENT_SELECTOR) {
+            if handled.contains(&paragraph.id()) || paragraph.ancestors().any(|ancestor| handled.contains(&ancestor.id())) {
+                continue;
+            }
+
+            match classify(paragraph) {
+                Some(BoilerplateKind::AuthorNote) => {
+                    let block = author_note_block(paragraph);
+                    handled.extend(block.iter().map(|elem| elem.id()));
+                    match self.author_notes {
+                        AuthorNotes::Drop => {
+                            ops.extend(block.iter().map(|elem| DomOperation::RemoveElement { node_id: elem.id() }));
+                        }
+                        AuthorNotes::Aside | AuthorNotes::Appendix => {
+                            ops.push(DomOperation::WrapElements {
+                                node_ids: block.iter().map(|elem| elem.id()).collect(),
+                                name: html_elem_name("aside"),
+                                attrs: vec![Attribute {
+                                    name: html_attr_name("class"),
+                                    value: "author-note".into(),
+                                }],
+                            });
+                        }
+                    }
+                }
+                Some(_) => {
+                    handled.insert(paragraph.id());
+                    ops.push(DomOperation::RemoveElement { node_id: paragraph.id() });
+                }
+                None => {}
+            }
+        }
+
+        Ok(ops)
+    }
+}
+
+#[test]
+fn test_classify() {
+    let p_selector = Selector::parse("p").unwrap();
+    let classify_fragment = |html: &str| classify(Html::parse_fragment(html).select(&p_selector).next().unwrap());
+
+    assert_eq!(classify_fragment(r#"<p><a href="/1-1">Last Chapter</a> | <a href="/1-3">Next Chapter</a></p>"#), Some(BoilerplateKind::Navigation));
+    assert_eq!(classify_fragment(r#"<p><a href="/1-3">Next →</a></p>"#), Some(BoilerplateKind::Navigation));
+    assert_eq!(classify_fragment(r#"<p>Consider supporting me on <a href="https://www.patreon.com/wildbow">Patreon</a>.</p>"#), Some(BoilerplateKind::Donation));
+    assert_eq!(classify_fragment(r#"<p><a href="https://www.parahumans.net/">Ward, the sequel to Worm, is now live!</a></p>"#), Some(BoilerplateKind::SequelBanner));
+    assert_eq!(classify_fragment(r#"<p>Author’s note: Sorry about the delay.</p>"#), Some(BoilerplateKind::AuthorNote));
+    assert_eq!(classify_fragment(r#"<p>“Next,” she said, and <a href="/glossary">pointed</a>.</p>"#), None);
+    assert_eq!(classify_fragment(r#"<p>Vote for the story on <a href="https://bit.ly/wormvote">this page</a>.</p>"#), Some(BoilerplateKind::Donation));
+    assert_eq!(classify_fragment(r#"<p>“I’d never donate to the Protectorate.”</p>"#), None);
+    assert_eq!(classify_fragment(r#"<p>“Vote for me,” Glenn said, “and I’ll make the <a href="/wards">Wards</a> look good.”</p>"#), None);
+    assert_eq!(classify_fragment(r#"<p><img src="header.png"><a href="/next">Next Chapter</a></p>"#), None);
+}
+
+#[test]
+fn test_boilerplate_removal() {
+    let mut example = Html::parse_fragment(r#"<p>Story.</p><p><a href="/prev">Last Chapter</a> <a href="/next">Next Chapter</a></p><hr><p>Author’s note: Thanks for reading.</p><p>See you Saturday.</p><p><a href="https://www.patreon.com/wildbow">Patreon</a></p><div class="sharedaddy"><p>Share this:</p></div>"#);
+    apply_offline(Box::new(Boilerplate::new(AuthorNotes::Drop)), &mut example, &[]);
+
+    assert_eq!(
+        Html::parse_fragment("<p>Story.</p><hr>"),
+        Html::parse_fragment(&example.root_element().inner_html()),
+    );
+}
+
+#[test]
+fn test_leading_author_note() {
+    let mut example = Html::parse_fragment(r#"<p>Content warning: violence.</p><p>Story.</p><p>More story.</p>"#);
+    apply_offline(Box::new(Boilerplate::new(AuthorNotes::Drop)), &mut example, &[]);
+
+    assert_eq!(
+        Html::parse_fragment("<p>Story.</p><p>More story.</p>"),
+        Html::parse_fragment(&example.root_element().inner_html()),
+    );
+
+    let mut example = Html::parse_fragment(r#"<p>A/N: Short chapter today.</p><p>Sorry.</p><hr><p>Story.</p>"#);
+    apply_offline(Box::new(Boilerplate::new(AuthorNotes::Drop)), &mut example, &[]);
+
+    assert_eq!(
+        Html::parse_fragment("<hr><p>Story.</p>"),
+        Html::parse_fragment(&example.root_element().inner_html()),
+    );
+
+    let mut example = Html::parse_fragment(r#"<p>Content warning: violence.</p><p>First scene.</p><hr><p>Second scene.</p>"#);
+    apply_offline(Box::new(Boilerplate::new(AuthorNotes::Drop)), &mut example, &[]);
+
+    assert_eq!(
+        Html::parse_fragment("<p>First scene.</p><hr><p>Second scene.</p>"),
+        Html::parse_fragment(&example.root_element().inner_html()),
+    );
+
+    let mut example = Html::parse_fragment(r#"<p>A/N: Short chapter today.</p><p>One.</p><p>Two.</p><p>Three.</p><p>Four.</p><hr><p>Five.</p>"#);
+    apply_offline(Box::new(Boilerplate::new(AuthorNotes::Drop)), &mut example, &[]);
+
+    assert_eq!(
+        Html::parse_fragment("<p>One.</p><p>Two.</p><p>Three.</p><p>Four.</p><hr><p>Five.</p>"),
+        Html::parse_fragment(&example.root_element().inner_html()),
+    );
+}
+
+#[test]
+fn test_author_note_aside() {
+    let mut example = Html::parse_fragment(r#"<p>Story.</p><hr><p>A/N: Thanks for reading.</p><p>See you Saturday.</p><p><a href="/next">Next Chapter</a></p>"#);
+    apply_offline(Box::new(Boilerplate::new(AuthorNotes::Aside)), &mut example, &[]);
+
+    assert_eq!(
+        Html::parse_fragment(r#"<p>Story.</p><hr><aside class="author-note"><p>A/N: Thanks for reading.</p><p>See you Saturday.</p></aside>"#),
+        Html::parse_fragment(&example.root_element().inner_html()),
+    );
+}
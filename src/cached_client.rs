@@ -1,11 +1,15 @@
 extern crate easy_error;
 extern crate reqwest;
 
-use easy_error::{Error, ResultExt};
+use easy_error::{err_msg, Error, ResultExt};
 use reqwest::blocking::{Client, Response};
+use reqwest::header::CONTENT_TYPE;
 use reqwest::Url;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs::create_dir_all;
 use std::path::PathBuf;
+use std::time::SystemTime;
 
 /// Facilitates response type selection in `CachedClient`
 /// by converting the cached and fetched responses to requested type.
@@ -44,6 +48,43 @@ impl DataType for Vec<u8> {
     }
 }
 
+/// Binary contents together with the type the server claimed for them.
+/// The `Content-Type` header is not cached so it will only be available for freshly fetched resources.
+#[derive(Clone, Debug)]
+pub struct TypedBytes {
+    pub bytes: Vec<u8>,
+    pub content_type: Option<String>,
+}
+
+impl AsRef<[u8]> for TypedBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl DataType for TypedBytes {
+    fn from_response(response: Response) -> Result<Self, Error> {
+        // Unlike pages, error pages are of no use in place of binary files.
+        let response = response.error_for_status().context("Server returned an error")?;
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        Ok(Self {
+            bytes: Vec::from(response.bytes().context("Cannot extract data.")?),
+            content_type,
+        })
+    }
+
+    fn from_bytes(contents: &[u8]) -> Result<Self, Error> {
+        Ok(Self {
+            bytes: Vec::from(contents),
+            content_type: None,
+        })
+    }
+}
+
 /// Wraps the response from `CachedClient` to allow the consumer
 /// to find out whether the resource was fetched or obtained from cache.
 #[derive(Debug)]
@@ -72,21 +113,92 @@ impl<T: DataType> Resource<T> {
 pub struct CachedClient {
     client: Client,
     cache_dir: Option<PathBuf>,
+    /// Addresses the fetched URLs were redirected to.
+    redirects: RefCell<HashMap<Url, Url>>,
+    /// Only serve the cache, which is removed with the client. Used by tests.
+    offline: bool,
 }
 
 impl CachedClient {
     pub fn new(cache_dir: Option<PathBuf>) -> Result<Self, Error> {
         if let Some(ref cache_path) = cache_dir {
-            create_dir_all(cache_path).context(format!("Could not create cache directory {cache_path:?}"))?;
+            create_dir_all(cache_path.join("redirects")).context(format!("Could not create cache directory {cache_path:?}"))?;
         }
 
         Ok(Self {
             client: Client::new(),
-            cache_dir: cache_dir,
+            cache_dir,
+            redirects: RefCell::new(HashMap::new()),
+            offline: false,
         })
     }
 
+    /// Creates a client that serves given resources from a temporary cache and fails to fetch anything else.
+    #[cfg(test)]
+    pub(crate) fn offline(cached: &[(&str, &[u8])]) -> Self {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+        let cache_dir = std::env::temp_dir().join(format!(
+            "rust-wildbow-scraper-test-{}-{}",
+            std::process::id(),
+            CLIENTS.fetch_add(1, Ordering::Relaxed),
+        ));
+        let mut client = Self::new(Some(cache_dir.clone())).unwrap();
+        client.offline = true;
+        for (url, contents) in cached {
+            std::fs::write(cache_dir.join(url.replace("/", "%2F")), contents).unwrap();
+        }
+        client
+    }
+
+    fn cache_file(&self, url: &Url) -> Option<PathBuf> {
+        self.cache_dir.as_ref().map(|cache_path| cache_path.join(url.to_string().replace("/", "%2F")))
+    }
+
+    /// File storing the address given URL was redirected to, so that it is known for cached pages as well.
+    fn redirect_file(&self, url: &Url) -> Option<PathBuf> {
+        self.cache_dir.as_ref().map(|cache_path| cache_path.join("redirects").join(url.to_string().replace("/", "%2F")))
+    }
+
+    fn remember_redirect(&self, url: &Url, final_url: &Url) -> Result<(), Error> {
+        if final_url == url {
+            self.redirects.borrow_mut().remove(url);
+            if let Some(redirect_file) = self.redirect_file(url) {
+                // The page might have been redirected when it was cached before.
+                let _ = std::fs::remove_file(redirect_file);
+            }
+            return Ok(());
+        }
+
+        self.redirects.borrow_mut().insert(url.clone(), final_url.clone());
+        if let Some(redirect_file) = self.redirect_file(url) {
+            std::fs::write(redirect_file, final_url.as_str()).context(format!("Could not cache redirect of {url}"))?;
+        }
+        Ok(())
+    }
+
+    /// The address the server redirected given URL to when it was fetched, or the URL itself.
+    /// Relative links on the page need to be resolved against it.
+    pub fn redirected_url(&self, url: &Url) -> Url {
+        if let Some(final_url) = self.redirects.borrow().get(url) {
+            return final_url.clone();
+        }
+        self.redirect_file(url)
+            .and_then(|redirect_file| std::fs::read_to_string(redirect_file).ok())
+            .and_then(|final_url| Url::parse(&final_url).ok())
+            .unwrap_or_else(|| url.clone())
+    }
+
+    /// When the cached copy of given URL was downloaded, if there is one.
+    pub fn fetched_at(&self, url: &Url) -> Option<SystemTime> {
+        std::fs::metadata(self.cache_file(url)?).and_then(|metadata| metadata.modified()).ok()
+    }
+
     pub fn fetch_uncached(&self, url: &Url) -> Result<Response, Error> {
+        if self.offline {
+            return Err(err_msg(format!("Could not retrieve page {url}: offline")));
+        }
         self.client
             .get(url.clone())
             .send()
@@ -96,25 +208,59 @@ impl CachedClient {
     /// Provides the contents of given URL in the format specified by the type parameter `T`:
     ///  - For `String`, the contents will be decoded using UTF-8 encoding.
     ///  - For `Vec<u8>`, the conents will be returned as they are.
+    ///  - For `TypedBytes`, the contents will be accompanied by their `Content-Type`, when known.
+    ///
     /// When the client has a cache directory available, it will attempt to look for the URL in there.
     pub fn fetch<T: DataType>(&self, url: &Url, skip_cache: bool) -> Result<Resource<T>, Error> {
-        Ok(match self.cache_dir {
+        Ok(match self.cache_file(url) {
             // Cache directory exists.
-            Some(ref cache_path) => {
-                let cached_file = cache_path.join(url.to_string().replace("/", "%2F"));
+            Some(cached_file) => {
                 if !skip_cache && cached_file.exists() {
                     let cached_contents =
                         std::fs::read(&cached_file).context(format!("Unable to load {cached_file:?} from cache"))?;
                     Resource::Cached(DataType::from_bytes(&cached_contents)?)
                 } else {
                     let page = self.fetch_uncached(url)?;
+                    self.remember_redirect(url, page.url())?;
                     let contents: T = DataType::from_response(page).context("Unable to retrieve data")?;
                     std::fs::write(cached_file, contents.clone()).context(format!("Could not cache {url}"))?;
                     Resource::Fetched(contents)
                 }
             }
             // No cache directory, fetch directly.
-            None => Resource::Fetched(DataType::from_response(self.fetch_uncached(url)?)?),
+            None => {
+                let page = self.fetch_uncached(url)?;
+                self.remember_redirect(url, page.url())?;
+                Resource::Fetched(DataType::from_response(page)?)
+            }
         })
     }
 }
+
+impl Drop for CachedClient {
+    fn drop(&mut self) {
+        if let (true, Some(cache_dir)) = (self.offline, &self.cache_dir) {
+            let _ = std::fs::remove_dir_all(cache_dir);
+        }
+    }
+}
+
+#[test]
+fn test_redirected_url() {
+    let cache_dir = std::env::temp_dir().join(format!("cached-client-{}", std::process::id()));
+    let client = CachedClient::new(Some(cache_dir.clone())).unwrap();
+    let url = Url::parse("https://parahumans.wordpress.com/?p=7").unwrap();
+    let final_url = Url::parse("https://parahumans.wordpress.com/2011/06/11/1-1/").unwrap();
+
+    assert_eq!(client.redirected_url(&url), url);
+
+    client.remember_redirect(&url, &final_url).unwrap();
+    assert_eq!(client.redirected_url(&url), final_url);
+    // Later runs only see the cache.
+    assert_eq!(CachedClient::new(Some(cache_dir.clone())).unwrap().redirected_url(&url), final_url);
+
+    client.remember_redirect(&url, &url).unwrap();
+    assert_eq!(CachedClient::new(Some(cache_dir.clone())).unwrap().redirected_url(&url), url);
+
+    std::fs::remove_dir_all(cache_dir).unwrap();
+}
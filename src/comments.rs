@@ -0,0 +1,299 @@
+use cached_client::CachedClient;
+use easy_error::{err_msg, Error};
+use html5ever::tree_builder::NodeOrText;
+use dom_manipulation::{DomOperation, MutableDom};
+use reqwest::Url;
+use scraper::{ElementRef, Html, Selector};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use xml_utils::XmlSerializable;
+
+/// Where to put the reader comments scraped from chapter pages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommentsMode {
+    /// Add a comments page per chapter at the end of the book, linked from the chapter.
+    Pages,
+    /// Produce a separate EPUB containing only the comments.
+    Companion,
+}
+
+impl FromStr for CommentsMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pages" => Ok(Self::Pages),
+            "companion" => Ok(Self::Companion),
+            _ => Err(err_msg(format!("Unknown comments mode {s}, expected one of: pages, companion"))),
+        }
+    }
+}
+
+/// A reader comment together with the replies to it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Comment {
+    pub author: String,
+    pub date: String,
+    /// XHTML of the comment text.
+    pub content: String,
+    pub replies: Vec<Comment>,
+}
+
+lazy_static! {
+    static ref COMMENT_LIST_SELECTOR: Selector = Selector::parse("ol.commentlist, ol.comment-list").unwrap();
+    static ref COMMENT_SELECTOR: Selector = Selector::parse("li.comment").unwrap();
+    static ref REPLIES_SELECTOR: Selector = Selector::parse("ul.children, ol.children").unwrap();
+    static ref AUTHOR_SELECTOR: Selector = Selector::parse(".comment-author .fn").unwrap();
+    static ref TIME_SELECTOR: Selector = Selector::parse("time[datetime]").unwrap();
+    static ref DATE_SELECTOR: Selector = Selector::parse(".comment-metadata a, .commentmetadata a").unwrap();
+    static ref CONTENT_SELECTOR: Selector = Selector::parse(".comment-content").unwrap();
+    /// Parts of the comment body that are not the text of the comment (older themes do not wrap it).
+    static ref CHROME_SELECTOR: Selector = Selector::parse(".comment-author, .comment-meta, .commentmetadata, .reply, footer").unwrap();
+    static ref IMAGE_SELECTOR: Selector = Selector::parse("img").unwrap();
+    static ref COMMENT_PAGE_LINK_SELECTOR: Selector = Selector::parse(r#"a[href*="comment-page-"]"#).unwrap();
+    static ref CURRENT_PAGE_SELECTOR: Selector = Selector::parse(".page-numbers.current").unwrap();
+    static ref PREVIOUS_PAGE_SELECTOR: Selector = Selector::parse(".nav-previous a").unwrap();
+    static ref NEXT_PAGE_SELECTOR: Selector = Selector::parse(".nav-next a").unwrap();
+}
+
+/// Direct child elements of given element.
+fn child_elements<'a>(elem: &ElementRef<'a>) -> impl Iterator<Item = ElementRef<'a>> {
+    elem.children().filter_map(ElementRef::wrap)
+}
+
+/// Extracts the comment threads from a WordPress chapter page.
+pub fn extract_comments(doc: &Html) -> Vec<Comment> {
+    doc.select(&COMMENT_LIST_SELECTOR)
+        .flat_map(|list| child_elements(&list).filter(|li| COMMENT_SELECTOR.matches(li)))
+        .map(extract_comment)
+        .collect()
+}
+
+/// Number of the comment page at given address, like `…/1-1/comment-page-2/#comments`.
+fn comment_page_number(url: &Url) -> Option<u32> {
+    url.path_segments()?.filter_map(|segment| segment.strip_prefix("comment-page-")).find_map(|number| number.parse().ok())
+}
+
+/// Pages of comments linked from a page, by their number.
+fn comment_page_links(doc: &Html, page_url: &Url) -> BTreeMap<u32, Url> {
+    doc.select(&COMMENT_PAGE_LINK_SELECTOR)
+        .filter_map(|link| page_url.join(link.value().attr("href")?).ok())
+        .filter_map(|mut url| {
+            url.set_fragment(None);
+            Some((comment_page_number(&url)?, url))
+        })
+        .collect()
+}
+
+/// Number of the comment page shown on a chapter page, which has an address without it.
+fn shown_comment_page(doc: &Html, page_url: &Url) -> Option<u32> {
+    let linked = |selector: &Selector| {
+        let href = doc.select(selector).next()?.value().attr("href")?;
+        comment_page_number(&page_url.join(href).ok()?)
+    };
+    doc.select(&CURRENT_PAGE_SELECTOR)
+        .next()
+        .and_then(|current| current.text().collect::<String>().trim().parse().ok())
+        .or_else(|| linked(&PREVIOUS_PAGE_SELECTOR).map(|previous| previous + 1))
+        .or_else(|| linked(&NEXT_PAGE_SELECTOR).map(|next| next - 1))
+}
+
+/// Extracts the comment threads of a chapter, following WordPress comment pagination
+/// so that long threads are not cut off after the first page.
+/// Pages that cannot be downloaded are skipped with a warning.
+pub fn download_comments(client: &CachedClient, doc: &Html, page_url: &Url) -> Vec<Comment> {
+    let mut queue = comment_page_links(doc, page_url);
+    let number = comment_page_number(page_url).or_else(|| shown_comment_page(doc, page_url)).unwrap_or(1);
+    queue.remove(&number);
+    let mut pages = BTreeMap::new();
+    pages.insert(number, extract_comments(doc));
+
+    while let Some((&number, _)) = queue.iter().next() {
+        let url = queue.remove(&number).unwrap();
+        let page = match client.fetch::<String>(&url, false) {
+            Ok(page) => page,
+            Err(error) => {
+                println!("Warning: Comments on {page_url} are incomplete, could not download {url}: {error}");
+                pages.insert(number, Vec::new());
+                continue;
+            }
+        };
+        let doc = Html::parse_document(page.contents());
+        for (linked, url) in comment_page_links(&doc, &url) {
+            if !pages.contains_key(&linked) && linked != number {
+                queue.entry(linked).or_insert(url);
+            }
+        }
+        pages.insert(number, extract_comments(&doc));
+    }
+
+    pages.into_values().flatten().collect()
+}
+
+fn extract_comment(li: ElementRef) -> Comment {
+    // The comment itself is wrapped in a <div> or <article>, replies follow in a nested list.
+    let body = child_elements(&li).find(|child| !REPLIES_SELECTOR.matches(child)).unwrap_or(li);
+
+    let author = body
+        .select(&AUTHOR_SELECTOR)
+        .next()
+        .map(|author| author.text().collect::<String>().trim().to_string())
+        .unwrap_or_else(|| "Anonymous".to_string());
+
+    let date = body
+        .select(&TIME_SELECTOR)
+        .next()
+        .and_then(|time| time.value().attr("datetime"))
+        .map(str::to_string)
+        .or_else(|| body.select(&DATE_SELECTOR).next().map(|date| date.text().collect::<String>().trim().to_string()))
+        .unwrap_or_default();
+
+    let content = match body.select(&CONTENT_SELECTOR).next() {
+        Some(content) => content.inner_xml(),
+        None => child_elements(&body)
+            .filter(|child| !CHROME_SELECTOR.matches(child))
+            .map(|child| child.xml())
+            .collect(),
+    };
+
+    let replies = child_elements(&li)
+        .filter(|child| REPLIES_SELECTOR.matches(child))
+        .flat_map(|list| child_elements(&list).filter(|reply| COMMENT_SELECTOR.matches(reply)))
+        .map(extract_comment)
+        .collect();
+
+    Comment {
+        author,
+        date,
+        content: clean_up_content(&content),
+        replies,
+    }
+}
+
+/// Replaces images (mostly smilies hosted elsewhere) by their alternative text
+/// so that the book does not reference remote resources.
+fn clean_up_content(content: &str) -> String {
+    let mut doc = Html::parse_fragment(content);
+    let ops = doc
+        .select(&IMAGE_SELECTOR)
+        .map(|img| DomOperation::ReplaceElement {
+            node_id: img.id(),
+            replacement: NodeOrText::AppendText(img.value().attr("alt").unwrap_or("").into()),
+        })
+        .collect();
+    doc.perform_operations(ops);
+    doc.root_element().inner_xml()
+}
+
+/// Number of comments in the threads, including replies.
+pub fn count_comments(comments: &[Comment]) -> usize {
+    comments.iter().map(|comment| 1 + count_comments(&comment.replies)).sum()
+}
+
+/// Renders the comment threads as nested XHTML lists.
+pub fn comments_xhtml(comments: &[Comment]) -> String {
+    let mut xhtml = String::from("<ol class=\"comments\">\n");
+    for comment in comments {
+        xhtml.push_str(&format!(
+            "<li class=\"comment\"><p class=\"comment-meta\"><span class=\"comment-author\">{}</span> <span class=\"comment-date\">{}</span></p>\n<div class=\"comment-content\">{}</div>\n",
+            html_escape::encode_text(&comment.author),
+            html_escape::encode_text(&comment.date),
+            comment.content,
+        ));
+        if !comment.replies.is_empty() {
+            xhtml.push_str(&comments_xhtml(&comment.replies));
+        }
+        xhtml.push_str("</li>\n");
+    }
+    xhtml.push_str("</ol>\n");
+    xhtml
+}
+
+#[test]
+fn test_extract_comments() {
+    // Markup of themes used by https://parahumans.wordpress.com/ and https://www.parahumans.net/ respectively.
+    let doc = Html::parse_document(r##"<body>
+        <ol class="commentlist">
+            <li class="comment even thread-even depth-1" id="li-comment-1">
+                <div id="comment-1">
+                    <div class="comment-author vcard"><img alt="" src="avatar.png"><cite class="fn"><a href="https://example.com">Alice</a></cite> <span class="says">says:</span></div>
+                    <div class="comment-meta commentmetadata"><a href="#comment-1">June 11, 2011 at 6:00 pm</a></div>
+                    <p>First! <img src="smile.png" alt=":)" class="wp-smiley"></p>
+                    <div class="reply"><a href="?replytocom=1">Reply</a></div>
+                </div>
+                <ul class="children">
+                    <li class="comment odd alt depth-2" id="li-comment-2">
+                        <div id="comment-2">
+                            <div class="comment-author vcard"><cite class="fn">Bob</cite></div>
+                            <div class="comment-meta commentmetadata"><a href="#comment-2">June 12, 2011 at 1:00 am</a></div>
+                            <p>Not quite.</p>
+                        </div>
+                    </li>
+                </ul>
+            </li>
+            <li class="pingback" id="li-comment-3"><div>Pingback</div></li>
+        </ol>
+        <ol class="comment-list">
+            <li id="comment-4" class="comment depth-1">
+                <article id="div-comment-4" class="comment-body">
+                    <footer class="comment-meta">
+                        <div class="comment-author vcard"><b class="fn">Carol</b></div>
+                        <div class="comment-metadata"><a href="#comment-4"><time datetime="2019-03-23T10:00:00+00:00">March 23, 2019</time></a></div>
+                    </footer>
+                    <div class="comment-content"><p>Typo: <em>teh</em></p></div>
+                </article>
+            </li>
+        </ol>
+    </body>"##);
+
+    let comments = extract_comments(&doc);
+
+    assert_eq!(
+        comments,
+        vec![
+            Comment {
+                author: "Alice".to_string(),
+                date: "June 11, 2011 at 6:00 pm".to_string(),
+                content: "<p>First! :)</p>".to_string(),
+                replies: vec![Comment {
+                    author: "Bob".to_string(),
+                    date: "June 12, 2011 at 1:00 am".to_string(),
+                    content: "<p>Not quite.</p>".to_string(),
+                    replies: vec![],
+                }],
+            },
+            Comment {
+                author: "Carol".to_string(),
+                date: "2019-03-23T10:00:00+00:00".to_string(),
+                content: "<p>Typo: <em>teh</em></p>".to_string(),
+                replies: vec![],
+            },
+        ],
+    );
+    assert_eq!(count_comments(&comments), 3);
+}
+
+#[test]
+fn test_download_comments() {
+    let comment = |author: &str| format!(r#"<li class="comment"><div><div class="comment-author"><cite class="fn">{author}</cite></div><p>Hi.</p></div></li>"#);
+    let page = |comments: &str, navigation: &str| {
+        format!(r#"<html><body><ol class="commentlist">{comments}</ol><div class="navigation">{navigation}</div></body></html>"#)
+    };
+
+    // The chapter shows the newest page, older ones are linked from it.
+    let chapter = Html::parse_document(&page(
+        &comment("Carol"),
+        r##"<div class="nav-previous"><a href="https://example.com/1-1/comment-page-2/#comments">Older Comments</a></div>"##,
+    ));
+    let older_pages = [
+        ("https://example.com/1-1/comment-page-1/", page(&comment("Alice"), r##"<div class="nav-next"><a href="https://example.com/1-1/comment-page-2/#comments">Newer Comments</a></div>"##)),
+        ("https://example.com/1-1/comment-page-2/", page(&comment("Bob"), r##"<div class="nav-previous"><a href="https://example.com/1-1/comment-page-1/#comments">Older Comments</a></div><div class="nav-next"><a href="https://example.com/1-1/comment-page-3/#comments">Newer Comments</a></div>"##)),
+    ];
+    let cached: Vec<(&str, &[u8])> = older_pages.iter().map(|(url, contents)| (*url, contents.as_bytes())).collect();
+    let client = CachedClient::offline(&cached);
+
+    let comments = download_comments(&client, &chapter, &Url::parse("https://example.com/1-1/").unwrap());
+
+    let authors: Vec<&str> = comments.iter().map(|comment| comment.author.as_str()).collect();
+    assert_eq!(authors, ["Alice", "Bob", "Carol"]);
+}
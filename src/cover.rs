@@ -0,0 +1,312 @@
+use easy_error::{err_msg, Error, ResultExt};
+use image::ImageFormat;
+use media_type::MediaType;
+use reqwest::Url;
+use resvg::usvg::fontdb;
+use resvg::{tiny_skia, usvg};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Size of generated covers, in the 2:3 ratio common for e-book covers.
+const WIDTH: u32 = 1200;
+const HEIGHT: u32 = 1800;
+
+/// Widest a line of text may get, leaving a margin on both sides.
+const TEXT_WIDTH: f32 = WIDTH as f32 * 0.8;
+
+/// Rough width of an average character relative to the font size, used for wrapping the title.
+const CHARACTER_WIDTH: f32 = 0.7;
+
+/// Format of the covers we generate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CoverFormat {
+    /// Rasterized using the system fonts, readable by every reader.
+    #[default]
+    Png,
+    /// Vector image with the text rendered by the reader itself.
+    Svg,
+}
+
+impl FromStr for CoverFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "png" => Ok(Self::Png),
+            "svg" => Ok(Self::Svg),
+            _ => Err(err_msg(format!("Unknown cover format {s}, expected one of: png, svg"))),
+        }
+    }
+}
+
+/// Covers supplied by the user, each given either for a specific book as `key=path`,
+/// or as a path to an image used for all books or to a directory containing images named after the book keys.
+#[derive(Debug, Default)]
+pub struct CoverFiles {
+    books: HashMap<String, PathBuf>,
+    directories: Vec<PathBuf>,
+    all_books: Option<PathBuf>,
+}
+
+impl CoverFiles {
+    /// Parses the `--cover-file` arguments, checking that the named files are usable covers.
+    pub fn new(specs: &[String]) -> Result<Self, Error> {
+        let mut cover_files = Self::default();
+        for spec in specs {
+            match spec.split_once('=') {
+                Some((key, path)) if !Path::new(spec).exists() => {
+                    let path = PathBuf::from(path);
+                    load_cover_file(&path)?;
+                    cover_files.books.insert(key.to_string(), path);
+                }
+                _ => {
+                    let path = PathBuf::from(spec);
+                    if path.is_dir() {
+                        cover_files.directories.push(path);
+                    } else {
+                        load_cover_file(&path)?;
+                        if let Some(previous) = cover_files.all_books.replace(path) {
+                            return Err(err_msg(format!("Cover {previous:?} given for all books already, use key=path to choose covers for specific books")));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(cover_files)
+    }
+
+    /// Keys of the books covers were given for explicitly.
+    pub fn book_keys(&self) -> impl Iterator<Item = &str> {
+        self.books.keys().map(String::as_str)
+    }
+
+    /// Finds the cover supplied for the book with given key.
+    pub fn find(&self, key: &str) -> Option<PathBuf> {
+        self.books
+            .get(key)
+            .cloned()
+            .or_else(|| {
+                self.directories.iter().find_map(|dir| {
+                    ["jpg", "jpeg", "png", "gif", "webp", "svg"]
+                        .iter()
+                        .map(|extension| dir.join(format!("{key}.{extension}")))
+                        .find(|path| path.is_file())
+                })
+            })
+            .or_else(|| self.all_books.clone())
+    }
+}
+
+/// Reads a cover image from a file, making sure it is in a supported format and not damaged.
+pub fn load_cover_file(path: &Path) -> Result<(Vec<u8>, MediaType), Error> {
+    let data = std::fs::read(path).context(format!("Could not read cover file {path:?}"))?;
+    let absolute = std::fs::canonicalize(path).context(format!("Could not resolve cover file {path:?}"))?;
+    let url = Url::from_file_path(&absolute).map_err(|_| err_msg(format!("Invalid cover file path {path:?}")))?;
+    let media_type = MediaType::detect(&data, None, &url)?;
+
+    let format = match media_type {
+        MediaType::Jpeg => Some(ImageFormat::Jpeg),
+        MediaType::Png => Some(ImageFormat::Png),
+        MediaType::Gif => Some(ImageFormat::Gif),
+        MediaType::Webp => Some(ImageFormat::WebP),
+        MediaType::Svg => None,
+    };
+    match format {
+        Some(format) => {
+            image::load_from_memory_with_format(&data, format).context(format!("Cover file {path:?} is not a valid image"))?;
+        }
+        None => {
+            usvg::Tree::from_data(&data, &usvg::Options::default()).context(format!("Cover file {path:?} is not a valid SVG image"))?;
+        }
+    }
+
+    Ok((data, media_type))
+}
+
+/// Text printed on a generated cover.
+pub struct CoverText<'a> {
+    pub title: &'a str,
+    pub author: &'a str,
+    /// Name of the series the book belongs to.
+    pub series: Option<&'a str>,
+    /// Position of the book in the series, e.g. `2`, or the range of volumes in an omnibus, e.g. `1–2`.
+    pub volume: Option<&'a str>,
+}
+
+/// Splits the text into lines of at most `max_chars` characters, breaking between words where possible.
+fn wrap(text: &str, max_chars: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if line.chars().count() + 1 + word.chars().count() <= max_chars => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_string()),
+        }
+    }
+    lines
+}
+
+/// Lays out `lines` as centred SVG text, with the first baseline at `y`.
+fn text_element(lines: &[String], y: f32, font_size: f32, attrs: &str) -> String {
+    let mut svg = format!("<text x=\"{}\" y=\"{y}\" font-size=\"{font_size}\" text-anchor=\"middle\" {attrs}>", WIDTH / 2);
+    for (i, line) in lines.iter().enumerate() {
+        let dy = if i == 0 { 0.0 } else { font_size * 1.15 };
+        svg.push_str(&format!("<tspan x=\"{}\" dy=\"{dy}\">{}</tspan>", WIDTH / 2, html_escape::encode_text(line)));
+    }
+    svg.push_str("</text>");
+    svg
+}
+
+/// Creates a simple typographic cover: title in the middle with series above it and author at the bottom.
+pub fn cover_svg(text: &CoverText) -> String {
+    // Shrink the title until it fits in three lines and long words do not overflow.
+    let mut title_size = 180.0;
+    let mut title_lines;
+    loop {
+        let max_chars = (TEXT_WIDTH / (title_size * CHARACTER_WIDTH)) as usize;
+        title_lines = wrap(text.title, max_chars);
+        let fits = title_lines.len() <= 3 && title_lines.iter().all(|line| line.chars().count() <= max_chars);
+        if fits || title_size <= 60.0 {
+            break;
+        }
+        title_size -= 20.0;
+    }
+    let title_height = title_size * 1.15 * (title_lines.len() - 1) as f32;
+    let title_y = HEIGHT as f32 * 0.45 - title_height / 2.0;
+
+    let mut svg = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\" version=\"1.1\" width=\"{WIDTH}\" height=\"{HEIGHT}\" viewBox=\"0 0 {WIDTH} {HEIGHT}\">\n"
+    );
+    svg.push_str(&format!("<rect width=\"{WIDTH}\" height=\"{HEIGHT}\" fill=\"#1d2330\"/>\n"));
+    svg.push_str(&format!(
+        "<rect x=\"60\" y=\"60\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"#c9a65b\" stroke-width=\"6\"/>\n",
+        WIDTH - 120,
+        HEIGHT - 120,
+    ));
+
+    let mut subtitle = Vec::new();
+    if let Some(series) = text.series {
+        subtitle.push(series.to_string());
+    }
+    if let Some(volume) = text.volume {
+        subtitle.push(format!("{} {volume}", if volume.contains('–') { "Volumes" } else { "Volume" }));
+    }
+    if !subtitle.is_empty() {
+        svg.push_str(&text_element(&[subtitle.join(" · ").to_uppercase()], title_y - title_size * 1.2, 56.0, "font-family=\"serif\" letter-spacing=\"6\" fill=\"#c9a65b\""));
+        svg.push('\n');
+    }
+
+    svg.push_str(&text_element(&title_lines, title_y, title_size, "font-family=\"serif\" font-weight=\"bold\" fill=\"#f4efe4\""));
+    svg.push('\n');
+    svg.push_str(&format!(
+        "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"#c9a65b\" stroke-width=\"4\"/>\n",
+        WIDTH / 2 - 150,
+        title_y + title_height + title_size * 0.6,
+        WIDTH / 2 + 150,
+        title_y + title_height + title_size * 0.6,
+    ));
+    svg.push_str(&text_element(&[text.author.to_string()], HEIGHT as f32 * 0.85, 80.0, "font-family=\"serif\" fill=\"#f4efe4\""));
+    svg.push_str("\n</svg>\n");
+
+    svg
+}
+
+/// Rasterizes an SVG image using the fonts installed on the system.
+pub fn render_png(svg: &str) -> Result<Vec<u8>, Error> {
+    let mut options = usvg::Options::default();
+    let fontdb = options.fontdb_mut();
+    fontdb.load_system_fonts();
+    if fontdb.is_empty() {
+        return Err(err_msg("No system fonts available for rendering the text"));
+    }
+    let serif_query = fontdb::Query {
+        families: &[fontdb::Family::Serif],
+        ..Default::default()
+    };
+    if fontdb.query(&serif_query).is_none() {
+        // The default serif family (Times New Roman) is often not installed, use any serif font, or any font at all.
+        let families: Vec<&str> = fontdb.faces().flat_map(|face| face.families.iter().map(|(family, _)| family.as_str())).collect();
+        let family = families
+            .iter()
+            .find(|family| family.contains("Serif") && !family.contains("Sans"))
+            .unwrap_or(&families[0])
+            .to_string();
+        fontdb.set_serif_family(family);
+    }
+
+    let tree = usvg::Tree::from_str(svg, &options).context("Could not parse cover SVG")?;
+    let size = tree.size().to_int_size();
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height()).ok_or(err_msg("Could not allocate cover image"))?;
+    resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+    pixmap.encode_png().context("Could not encode cover image")
+}
+
+/// Generates a cover in requested format. When PNG cannot be rendered, SVG is used instead.
+pub fn generate_cover(text: &CoverText, format: CoverFormat) -> (Vec<u8>, MediaType) {
+    let svg = cover_svg(text);
+    if format == CoverFormat::Png {
+        match render_png(&svg) {
+            Ok(png) => return (png, MediaType::Png),
+            Err(error) => println!("Warning: Using SVG cover since it could not be rendered: {error}"),
+        }
+    }
+    (svg.into_bytes(), MediaType::Svg)
+}
+
+#[test]
+fn test_cover_svg() {
+    let svg = cover_svg(&CoverText {
+        title: "The Very Long Title of a Book & Its Sequel",
+        author: "Wildbow",
+        series: Some("Parahumans"),
+        volume: Some("2"),
+    });
+
+    assert_eq!(MediaType::from_magic_bytes(svg.as_bytes()), Some(MediaType::Svg));
+    assert!(svg.contains("PARAHUMANS · VOLUME 2"));
+    assert!(svg.contains("Book &amp;"));
+    assert!(usvg::Tree::from_str(&svg, &usvg::Options::default()).is_ok());
+    assert_eq!(wrap("The Very Long Title", 9), vec!["The Very", "Long", "Title"]);
+
+    let omnibus = cover_svg(&CoverText {
+        title: "Parahumans",
+        author: "Wildbow",
+        series: None,
+        volume: Some("1–2"),
+    });
+    assert!(omnibus.contains(">VOLUMES 1–2<"));
+}
+
+#[test]
+fn test_cover_files() {
+    let dir = std::env::temp_dir().join(format!("cover-files-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("covers")).unwrap();
+    let svg = cover_svg(&CoverText {
+        title: "Worm",
+        author: "Wildbow",
+        series: None,
+        volume: None,
+    });
+    std::fs::write(dir.join("covers/worm.svg"), &svg).unwrap();
+    std::fs::write(dir.join("ward.png"), &svg).unwrap();
+    std::fs::write(dir.join("broken.png"), b"\x89PNG\r\n\x1A\nbroken").unwrap();
+    std::fs::write(dir.join("cover.bmp"), b"BM\x36\0\0\0").unwrap();
+    let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+
+    let cover_files = CoverFiles::new(&[format!("pact={}", path("ward.png")), path("covers")]).unwrap();
+    assert_eq!(cover_files.find("worm"), Some(dir.join("covers/worm.svg")));
+    assert_eq!(cover_files.find("pact"), Some(dir.join("ward.png")));
+    assert_eq!(cover_files.find("twig"), None);
+    // Detected from the contents rather than the extension.
+    assert_eq!(load_cover_file(&dir.join("ward.png")).unwrap().1, MediaType::Svg);
+
+    assert!(CoverFiles::new(&[path("broken.png")]).is_err());
+    assert!(CoverFiles::new(&[path("cover.bmp")]).is_err());
+    assert!(CoverFiles::new(&[path("missing.jpg")]).is_err());
+    assert!(CoverFiles::new(&[path("ward.png"), path("covers/worm.svg")]).is_err());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -1,12 +1,13 @@
-use html5ever::tree_builder::{NodeOrText, TreeSink};
-use markup5ever::QualName;
+use html5ever::tree_builder::{ElementFlags, NodeOrText, TreeSink};
+use markup5ever::{Attribute, QualName};
 #[cfg(test)]
 use scraper::Selector;
+use scraper::node::Element;
 use scraper::{Html, Node};
 #[cfg(test)]
-use xml_utils::html_attr_name;
+use xml_utils::{html_attr_name, html_elem_name};
 
-pub(crate) enum DomOperation<Handle> {
+pub enum DomOperation<Handle> {
     /// Will remove the attribute `attr_name` of element `node_id`.
     RemoveAttribute {
         node_id: Handle,
@@ -28,6 +29,11 @@ pub(crate) enum DomOperation<Handle> {
         parent_id: Handle,
         child_id: Handle,
     },
+    /// Will add `text` after the last child of element with `parent_id`.
+    AppendText {
+        parent_id: Handle,
+        text: String,
+    },
     /// Will remove element with `node_id` and put replacement in its place in the tree.
     ReplaceElement {
         node_id: Handle,
@@ -38,9 +44,27 @@ pub(crate) enum DomOperation<Handle> {
     DissolveElement {
         node_id: Handle,
     },
+    /// Will remove element with `node_id`, including all its children, from the tree.
+    RemoveElement {
+        node_id: Handle,
+    },
+    /// Will create a new element called `name` with attributes `attrs`
+    /// in place of the first of `node_ids` and move all the `node_ids` elements inside it.
+    WrapElements {
+        node_ids: Vec<Handle>,
+        name: QualName,
+        attrs: Vec<Attribute>,
+    },
+    /// Will turn element with `node_id` into an element called `name` with attributes `attrs`,
+    /// keeping its children.
+    RenameElement {
+        node_id: Handle,
+        name: QualName,
+        attrs: Vec<Attribute>,
+    },
 }
 
-pub(crate) trait MutableDom: TreeSink {
+pub trait MutableDom: TreeSink {
     /// Performs given operation to mutate the tree.
     fn perform_operation(&mut self, op: DomOperation<Self::Handle>);
 
@@ -89,6 +113,9 @@ impl MutableDom for Html {
                     node.append_id(child_id);
                 }
             }
+            DomOperation::AppendText { parent_id, text } => {
+                self.append(&parent_id, NodeOrText::AppendText(text.into()));
+            }
             DomOperation::ReplaceElement { node_id, replacement } => {
                 self.append_before_sibling(&node_id, replacement);
                 self.remove_from_parent(&node_id);
@@ -107,6 +134,25 @@ impl MutableDom for Html {
 
                 self.remove_from_parent(&node_id);
             }
+            DomOperation::RemoveElement { node_id } => {
+                self.remove_from_parent(&node_id);
+            }
+            DomOperation::WrapElements { node_ids, name, attrs } => {
+                if let Some(first_id) = node_ids.first() {
+                    let wrapper_id = self.create_element(name, attrs, ElementFlags::default());
+                    self.append_before_sibling(first_id, NodeOrText::AppendNode(wrapper_id));
+                    for id in node_ids {
+                        self.append(&wrapper_id, NodeOrText::AppendNode(id));
+                    }
+                }
+            }
+            DomOperation::RenameElement { node_id, name, attrs } => {
+                if let Some(mut node) = self.tree.get_mut(node_id) {
+                    if let Node::Element(ref mut elem) = node.value() {
+                        *elem = Element::new(name, attrs);
+                    }
+                }
+            }
         }
     }
 }
@@ -192,6 +238,22 @@ fn test_append_child() {
     );
 }
 
+#[test]
+fn test_append_text() {
+    let mut doc = Html::parse_fragment("<em>Emphasis</em> <strong>Bold</strong> <del>Wrong</del>");
+    let strong = doc.select(&Selector::parse("strong").unwrap()).next().expect("Strong tag not found.");
+
+    doc.perform_operation(DomOperation::AppendText {
+        parent_id: strong.id(),
+        text: String::from(" text"),
+    });
+
+    assert_eq!(
+        Html::parse_fragment("<em>Emphasis</em> <strong>Bold text</strong> <del>Wrong</del>"),
+        Html::parse_fragment(&doc.root_element().inner_html()),
+    );
+}
+
 #[test]
 fn test_replace_element() {
     let mut doc = Html::parse_fragment("<em>Emphasis</em> <strong>Bold</strong> <del>Wrong</del>");
@@ -222,3 +284,55 @@ fn test_dissolve_element() {
         Html::parse_fragment(&doc.root_element().inner_html()),
     );
 }
+
+#[test]
+fn test_remove_element() {
+    let mut doc = Html::parse_fragment("<div><p class='nop'><em>Foo</em></p><p class='figure'><em>Empha<strong title='secret'>sis</strong></em> <strong>Bold</strong></p> <a href='#'>After</a></div>");
+    let figure = doc.select(&Selector::parse(".figure").unwrap()).next().expect("Figure not found.");
+
+    doc.perform_operation(DomOperation::RemoveElement {
+        node_id: figure.id(),
+    });
+
+    assert_eq!(
+        Html::parse_fragment("<div><p class='nop'><em>Foo</em></p> <a href='#'>After</a></div>"),
+        Html::parse_fragment(&doc.root_element().inner_html()),
+    );
+}
+
+#[test]
+fn test_wrap_elements() {
+    let mut doc = Html::parse_fragment("<div><p class='nop'><em>Foo</em></p><p class='note'>Note</p> <p class='note'>Other <strong>note</strong></p><hr></div>");
+    let notes = doc.select(&Selector::parse(".note").unwrap()).map(|note| note.id()).collect();
+
+    doc.perform_operation(DomOperation::WrapElements {
+        node_ids: notes,
+        name: html_elem_name("aside"),
+        attrs: vec![Attribute {
+            name: html_attr_name("class"),
+            value: "notes".into(),
+        }],
+    });
+
+    assert_eq!(
+        Html::parse_fragment("<div><p class='nop'><em>Foo</em></p><aside class='notes'><p class='note'>Note</p><p class='note'>Other <strong>note</strong></p></aside> <hr></div>"),
+        Html::parse_fragment(&doc.root_element().inner_html()),
+    );
+}
+
+#[test]
+fn test_rename_element() {
+    let mut doc = Html::parse_fragment("<div class='wp-caption' style='width: 310px'><img src='a.png'><p class='wp-caption-text'>Caption</p></div>");
+    let div = doc.select(&Selector::parse("div").unwrap()).next().expect("Div not found.");
+
+    doc.perform_operation(DomOperation::RenameElement {
+        node_id: div.id(),
+        name: html_elem_name("figure"),
+        attrs: vec![],
+    });
+
+    assert_eq!(
+        Html::parse_fragment("<figure><img src='a.png'><p class='wp-caption-text'>Caption</p></figure>"),
+        Html::parse_fragment(&doc.root_element().inner_html()),
+    );
+}
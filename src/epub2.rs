@@ -0,0 +1,88 @@
+use dom_manipulation::{DomOperation, MutableDom};
+use markup5ever::Attribute;
+use scraper::{Html, Selector};
+use xml_utils::{html_attr_name, html_elem_name, XmlSerializable};
+
+/// Doctype of XHTML 1.1, which EPUB 2 content documents are based on.
+pub const XHTML11_DOCTYPE: &str = "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.1//EN\" \"http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd\">";
+
+/// Elements introduced in HTML5, which EPUB 2 readers do not know, and the XHTML 1.1 elements replacing them.
+const HTML5_ELEMENTS: &[(&str, &str)] = &[
+    ("article", "div"),
+    ("aside", "div"),
+    ("figcaption", "div"),
+    ("figure", "div"),
+    ("footer", "div"),
+    ("header", "div"),
+    ("main", "div"),
+    ("nav", "div"),
+    ("section", "div"),
+    ("mark", "span"),
+    ("time", "span"),
+];
+
+lazy_static! {
+    static ref HTML5_SELECTOR: Selector = Selector::parse(&HTML5_ELEMENTS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")).unwrap();
+}
+
+/// Replaces HTML5 elements in a chapter body with their XHTML 1.1 counterparts for EPUB 2.
+/// The original element name is prepended to the classes so that the stylesheet can still tell them apart.
+pub fn downgrade_markup(body_text: &str) -> String {
+    if !HTML5_ELEMENTS.iter().any(|(name, _)| body_text.contains(&format!("<{name}"))) {
+        // Avoid needlessly re-serializing the chapter.
+        return body_text.to_string();
+    }
+
+    let mut doc = Html::parse_fragment(body_text);
+    let mut ops = Vec::new();
+    for elem in doc.select(&HTML5_SELECTOR) {
+        let name = elem.value().name();
+        let replacement = HTML5_ELEMENTS.iter().find(|(html5_name, _)| *html5_name == name).map(|(_, replacement)| *replacement).unwrap();
+        let class = match elem.value().attr("class") {
+            Some(class) => format!("{name} {class}"),
+            None => name.to_string(),
+        };
+
+        let mut attrs: Vec<Attribute> = elem
+            .value()
+            .attrs()
+            .filter(|(attr_name, _)| *attr_name != "class" && *attr_name != "datetime")
+            .map(|(attr_name, value)| Attribute {
+                name: html_attr_name(attr_name),
+                value: value.into(),
+            })
+            .collect();
+        attrs.push(Attribute {
+            name: html_attr_name("class"),
+            value: class.into(),
+        });
+
+        ops.push(DomOperation::RenameElement {
+            node_id: elem.id(),
+            name: html_elem_name(replacement),
+            attrs,
+        });
+    }
+
+    doc.perform_operations(ops);
+    doc.root_element().inner_xml()
+}
+
+#[test]
+fn test_downgrade_markup() {
+    let unchanged = "<p>No <em>HTML5</em> here.</p>";
+    assert_eq!(downgrade_markup(unchanged), unchanged);
+
+    let downgraded = downgrade_markup(concat!(
+        r#"<p>Story.</p><figure><img src="images/map.png" alt="Map"><figcaption>The city</figcaption></figure>"#,
+        r#"<aside class="author-note"><p>Posted <time datetime="2011-06-11">Saturday</time>.</p></aside>"#,
+    ));
+    // Serialized as XML, so re-serialize as HTML to compare without parse errors.
+    assert_eq!(
+        Html::parse_fragment(&Html::parse_fragment(&downgraded).root_element().inner_html()),
+        Html::parse_fragment(concat!(
+            r#"<p>Story.</p><div class="figure"><img src="images/map.png" alt="Map"><div class="figcaption">The city</div></div>"#,
+            r#"<div class="aside author-note"><p>Posted <span class="time">Saturday</span>.</p></div>"#,
+        )),
+    );
+}
@@ -0,0 +1,107 @@
+use easy_error::{err_msg, Error, ResultExt};
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+const MIMETYPE: &str = "application/epub+zip";
+const CONTAINER_PATH: &str = "META-INF/container.xml";
+
+/// Extracts an EPUB into a directory, for inspection and editing by hand.
+pub fn unpack_epub(epub: &[u8], directory: &Path) -> Result<(), Error> {
+    let mut archive = ZipArchive::new(Cursor::new(epub)).context("Could not read EPUB")?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).context("Could not read EPUB")?;
+        if file.is_dir() {
+            continue;
+        }
+        let name = file.enclosed_name().ok_or_else(|| err_msg(format!("Invalid file name {} in EPUB", file.name())))?;
+        let path = directory.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).context(format!("Could not create directory {}", parent.display()))?;
+        }
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).context(format!("Could not read {} from EPUB", file.name()))?;
+        std::fs::write(&path, data).context(format!("Could not write file {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Lists the files below the directory, as paths relative to `root` sorted by name.
+fn directory_files(root: &Path, directory: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let entries = std::fs::read_dir(directory).context(format!("Could not read directory {}", directory.display()))?;
+    let mut paths = entries.map(|entry| entry.map(|entry| entry.path())).collect::<Result<Vec<_>, _>>()
+        .context(format!("Could not read directory {}", directory.display()))?;
+    paths.sort();
+    for path in paths {
+        if path.is_dir() {
+            directory_files(root, &path, files)?;
+        } else {
+            files.push(path.strip_prefix(root).unwrap().to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/// Packs a directory written by `unpack_epub` back into an EPUB.
+/// The `mimetype` file goes first and uncompressed, as readers expect, followed by the container and the other files.
+pub fn pack_epub(directory: &Path) -> Result<Vec<u8>, Error> {
+    if !directory.join(CONTAINER_PATH).is_file() {
+        return Err(err_msg(format!("{} is not an unpacked EPUB, it has no {CONTAINER_PATH}", directory.display())));
+    }
+    let mut files = Vec::new();
+    directory_files(directory, directory, &mut files)?;
+    let mut names: Vec<String> = files.iter()
+        .map(|file| file.components().map(|component| component.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/"))
+        .filter(|name| name != "mimetype")
+        .collect();
+    names.sort_by_key(|name| name != CONTAINER_PATH);
+
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    writer.start_file("mimetype", FileOptions::default().compression_method(CompressionMethod::Stored))
+        .context("Could not write mimetype")?;
+    writer.write_all(MIMETYPE.as_bytes()).context("Could not write mimetype")?;
+    for name in names {
+        let data = std::fs::read(directory.join(&name)).context(format!("Could not read file {name}"))?;
+        writer.start_file(name.as_str(), FileOptions::default()).context(format!("Could not add {name} to EPUB"))?;
+        writer.write_all(&data).context(format!("Could not add {name} to EPUB"))?;
+    }
+    let epub = writer.finish().context("Could not write EPUB")?;
+    Ok(epub.into_inner())
+}
+
+#[test]
+fn test_unpack_and_pack_epub() {
+    use epub_builder::{EpubBuilder, EpubContent, ZipLibrary};
+
+    let mut builder = EpubBuilder::new(ZipLibrary::new().unwrap()).unwrap();
+    builder.metadata("title", "Worm").unwrap();
+    builder.add_resource("images/map.png", "png".as_bytes(), "image/png").unwrap();
+    builder.add_content(EpubContent::new("chapter_1.xhtml", "<html/>".as_bytes())).unwrap();
+    let mut epub = Vec::new();
+    builder.generate(&mut epub).unwrap();
+
+    let dir = std::env::temp_dir().join(format!("epub-directory-{}", std::process::id()));
+    unpack_epub(&epub, &dir).unwrap();
+    assert_eq!(std::fs::read_to_string(dir.join("OEBPS/chapter_1.xhtml")).unwrap(), "<html/>");
+    assert!(dir.join("OEBPS/content.opf").is_file());
+    std::fs::write(dir.join("OEBPS/chapter_1.xhtml"), "<html><body/></html>").unwrap();
+
+    let packed = pack_epub(&dir).unwrap();
+    let mut archive = ZipArchive::new(Cursor::new(packed)).unwrap();
+    let names: Vec<String> = (0..archive.len()).map(|i| archive.by_index(i).unwrap().name().to_string()).collect();
+    let original = ZipArchive::new(Cursor::new(epub)).unwrap();
+    let mut original_names: Vec<String> = original.file_names().map(String::from).collect();
+    let mut sorted_names = names.clone();
+    sorted_names.sort();
+    original_names.sort();
+    assert_eq!(sorted_names, original_names);
+    assert_eq!(names[..2], ["mimetype", CONTAINER_PATH]);
+    assert_eq!(archive.by_index(0).unwrap().compression(), CompressionMethod::Stored);
+    let mut chapter = String::new();
+    archive.by_name("OEBPS/chapter_1.xhtml").unwrap().read_to_string(&mut chapter).unwrap();
+    assert_eq!(chapter, "<html><body/></html>");
+
+    assert!(pack_epub(&dir.join("OEBPS")).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
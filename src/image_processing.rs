@@ -0,0 +1,212 @@
+use easy_error::{Error, ResultExt};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageFormat};
+use media_type::MediaType;
+use std::collections::HashSet;
+use std::io::Cursor;
+
+/// JPEG quality used when an image has to be re-encoded and no quality was requested.
+const DEFAULT_JPEG_QUALITY: u8 = 85;
+
+/// Number of distinct colours above which an image is considered a photo rather than a drawing.
+const PHOTO_COLOR_THRESHOLD: usize = 4096;
+
+/// How to adjust the images before embedding them in the book.
+/// With the default options, images are embedded exactly as downloaded.
+#[derive(Clone, Debug, Default)]
+pub struct ImageOptions {
+    /// Images wider than this are scaled down, keeping the aspect ratio.
+    pub max_width: Option<u32>,
+    /// Images taller than this are scaled down, keeping the aspect ratio.
+    pub max_height: Option<u32>,
+    /// Re-compress JPEG images with this quality (1–100).
+    pub jpeg_quality: Option<u8>,
+    /// Store opaque PNG images that look like photos as JPEG.
+    pub png_to_jpeg: bool,
+    /// Convert images to shades of grey, which is all e-ink screens can show anyway.
+    pub grayscale: bool,
+    /// Convert WebP images, which many readers cannot display, to JPEG (or PNG when they are transparent).
+    pub transcode_webp: bool,
+}
+
+impl ImageOptions {
+    fn max_dimensions(&self) -> Option<(u32, u32)> {
+        if self.max_width.is_none() && self.max_height.is_none() {
+            None
+        } else {
+            Some((self.max_width.unwrap_or(u32::MAX), self.max_height.unwrap_or(u32::MAX)))
+        }
+    }
+
+    /// Whether an image of given type might be changed at all.
+    /// SVG images are vectors, and GIFs are left alone since they are small and possibly animated.
+    fn affects(&self, media_type: MediaType) -> bool {
+        let resizes_or_recolors = self.max_dimensions().is_some() || self.grayscale;
+        match media_type {
+            MediaType::Jpeg => resizes_or_recolors || self.jpeg_quality.is_some(),
+            MediaType::Png => resizes_or_recolors || self.png_to_jpeg,
+            MediaType::Webp => resizes_or_recolors || self.transcode_webp,
+            MediaType::Gif | MediaType::Svg => false,
+        }
+    }
+
+    /// Applies the options to given image, returning the new contents and their type.
+    /// Returns `None` for images that should be kept as they are, to avoid recompression losses.
+    pub fn process(&self, data: &[u8], media_type: MediaType) -> Result<Option<(Vec<u8>, MediaType)>, Error> {
+        if !self.affects(media_type) {
+            return Ok(None);
+        }
+
+        let format = match media_type {
+            MediaType::Jpeg => ImageFormat::Jpeg,
+            MediaType::Png => ImageFormat::Png,
+            MediaType::Webp => ImageFormat::WebP,
+            MediaType::Gif | MediaType::Svg => unreachable!("not affected by image options"),
+        };
+        let mut img = image::load_from_memory_with_format(data, format).context("Could not decode image")?;
+        let mut changed = false;
+
+        // Decide before scaling and converting to grayscale, which reduce the number of colours.
+        let output_type = match media_type {
+            MediaType::Png if self.png_to_jpeg && looks_like_photo(&img) => MediaType::Jpeg,
+            MediaType::Webp if self.transcode_webp => {
+                if img.color().has_alpha() {
+                    MediaType::Png
+                } else {
+                    MediaType::Jpeg
+                }
+            }
+            _ => media_type,
+        };
+
+        if let Some((max_width, max_height)) = self.max_dimensions() {
+            let (width, height) = img.dimensions();
+            if width > max_width || height > max_height {
+                img = img.resize(max_width.min(width), max_height.min(height), FilterType::Lanczos3);
+                changed = true;
+            }
+        }
+
+        if self.grayscale && img.color().has_color() {
+            img = img.grayscale();
+            changed = true;
+        }
+
+        if output_type == media_type && !changed && media_type != MediaType::Jpeg {
+            return Ok(None);
+        }
+
+        let encoded = encode(&img, output_type, self.jpeg_quality.unwrap_or(DEFAULT_JPEG_QUALITY))?;
+
+        if output_type == media_type && !changed && encoded.len() >= data.len() {
+            // Re-compressing did not help, the original was already small enough.
+            return Ok(None);
+        }
+
+        Ok(Some((encoded, output_type)))
+    }
+}
+
+/// Whether the image is opaque and has too many colours to be a drawing or a diagram,
+/// which compress better as PNG.
+fn looks_like_photo(img: &DynamicImage) -> bool {
+    if img.color().has_alpha() && img.pixels().any(|(_, _, pixel)| pixel[3] < u8::MAX) {
+        return false;
+    }
+
+    let mut colors = HashSet::new();
+    for (_, _, pixel) in img.pixels() {
+        colors.insert([pixel[0], pixel[1], pixel[2]]);
+        if colors.len() > PHOTO_COLOR_THRESHOLD {
+            return true;
+        }
+    }
+    false
+}
+
+fn encode(img: &DynamicImage, media_type: MediaType, jpeg_quality: u8) -> Result<Vec<u8>, Error> {
+    let mut encoded = Vec::new();
+    match media_type {
+        MediaType::Jpeg => {
+            // JPEG has no transparency. Concrete buffers are needed
+            // since `DynamicImage` would be encoded according to its generic RGBA view.
+            let mut encoder = JpegEncoder::new_with_quality(&mut encoded, jpeg_quality.clamp(1, 100));
+            if img.color().has_color() {
+                encoder.encode_image(&img.to_rgb8())
+            } else {
+                encoder.encode_image(&img.to_luma8())
+            }
+            .context("Could not encode JPEG image")?;
+        }
+        MediaType::Png => {
+            img.write_to(&mut Cursor::new(&mut encoded), ImageFormat::Png)
+                .context("Could not encode PNG image")?;
+        }
+        MediaType::Webp => {
+            // Resized or grayscale WebP images are kept as WebP unless transcoding was requested.
+            img.write_to(&mut Cursor::new(&mut encoded), ImageFormat::WebP)
+                .context("Could not encode WebP image")?;
+        }
+        MediaType::Gif | MediaType::Svg => unreachable!("GIF and SVG images are not re-encoded"),
+    }
+    Ok(encoded)
+}
+
+#[cfg(test)]
+fn test_image(width: u32, height: u32) -> DynamicImage {
+    DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+        image::Rgb([(x * 7 % 256) as u8, (y * 13 % 256) as u8, ((x * y) % 256) as u8])
+    }))
+}
+
+#[test]
+fn test_process_image() {
+    let mut png = Vec::new();
+    test_image(400, 200).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+
+    assert!(ImageOptions::default().process(&png, MediaType::Png).unwrap().is_none());
+
+    let options = ImageOptions {
+        max_width: Some(100),
+        png_to_jpeg: true,
+        grayscale: true,
+        ..Default::default()
+    };
+    let (processed, media_type) = options.process(&png, MediaType::Png).unwrap().unwrap();
+    assert_eq!(media_type, MediaType::Jpeg);
+    assert_eq!(MediaType::from_magic_bytes(&processed), Some(MediaType::Jpeg));
+    let img = image::load_from_memory(&processed).unwrap();
+    assert_eq!(img.dimensions(), (100, 50));
+    let sof = processed.windows(2).position(|marker| marker == b"\xFF\xC0").unwrap();
+    assert_eq!(processed[sof + 9], 1, "JPEG should have a single colour component");
+}
+
+#[test]
+fn test_transcode_webp() {
+    let mut webp = Vec::new();
+    test_image(64, 64).write_to(&mut Cursor::new(&mut webp), ImageFormat::WebP).unwrap();
+
+    let options = ImageOptions {
+        transcode_webp: true,
+        ..Default::default()
+    };
+    let (processed, media_type) = options.process(&webp, MediaType::Webp).unwrap().unwrap();
+    assert_eq!(media_type, MediaType::Jpeg);
+    assert_eq!(MediaType::from_magic_bytes(&processed), Some(MediaType::Jpeg));
+
+    let options = ImageOptions {
+        max_width: Some(32),
+        grayscale: true,
+        ..Default::default()
+    };
+    let mut webp = Vec::new();
+    test_image(64, 64).write_to(&mut Cursor::new(&mut webp), ImageFormat::WebP).unwrap();
+    let (processed, media_type) = options.process(&webp, MediaType::Webp).unwrap().unwrap();
+    assert_eq!(media_type, MediaType::Webp);
+    assert_eq!(MediaType::from_magic_bytes(&processed), Some(MediaType::Webp));
+    let img = image::load_from_memory(&processed).unwrap();
+    assert_eq!(img.dimensions(), (32, 32));
+    // WebP has no grayscale mode, it is decoded as RGB.
+    assert!(img.to_rgb8().pixels().all(|pixel| pixel[0] == pixel[1] && pixel[1] == pixel[2]));
+}
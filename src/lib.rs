@@ -0,0 +1,41 @@
+//! Scrapes wildbow's web serials and compiles them into ebooks.
+//!
+//! The `rust-wildbow-scraper` binary is built on these modules. Library users can clean up chapter content
+//! with their own transforms by implementing `transforms::Transform` and registering it in a `TransformRegistry`.
+
+extern crate base64;
+extern crate chrono;
+extern crate ego_tree;
+extern crate epub_builder;
+#[macro_use]
+extern crate html5ever;
+extern crate html_escape;
+extern crate image;
+extern crate markup5ever;
+extern crate percent_encoding;
+extern crate resvg;
+extern crate reqwest;
+extern crate scraper;
+extern crate serde;
+extern crate serde_json;
+extern crate easy_error;
+#[macro_use]
+extern crate lazy_static;
+extern crate uuid;
+extern crate xml5ever;
+extern crate zip;
+
+pub mod boilerplate;
+pub mod cached_client;
+pub mod comments;
+pub mod cover;
+pub mod links;
+pub mod dom_manipulation;
+pub mod epub2;
+pub mod epub_directory;
+pub mod image_processing;
+pub mod media_type;
+pub mod metadata;
+pub mod transforms;
+pub mod writers;
+pub mod xml_utils;
@@ -0,0 +1,270 @@
+use dom_manipulation::{DomOperation, MutableDom};
+use easy_error::{err_msg, Error};
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use scraper::{Html, Selector};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use xml_utils::{html_attr_name, XmlSerializable};
+
+/// What to do with links leading outside of the book.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExternalLinks {
+    /// Leave them as they are.
+    #[default]
+    Keep,
+    /// Replace them with their text.
+    Strip,
+    /// Point them to numbered notes at the end of the chapter listing the URLs,
+    /// for readers without a browser.
+    Endnotes,
+}
+
+impl FromStr for ExternalLinks {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "strip" => Ok(Self::Strip),
+            "endnotes" => Ok(Self::Endnotes),
+            _ => Err(err_msg(format!("Unknown external links policy {s}, expected one of: keep, strip, endnotes"))),
+        }
+    }
+}
+
+lazy_static! {
+    static ref HREF_SELECTOR: Selector = Selector::parse("a[href]").unwrap();
+}
+
+/// Maps URLs of scraped chapters to the files containing them inside the book.
+#[derive(Default)]
+pub struct ChapterMap {
+    files: HashMap<String, String>,
+    /// Files already chosen for chapters.
+    taken: HashSet<String>,
+}
+
+impl ChapterMap {
+    /// Key identifying a page regardless of scheme, `www.` prefix, query, fragment and trailing slash.
+    fn key(url: &Url) -> Option<String> {
+        let host = url.host_str()?.to_lowercase();
+        let host = host.strip_prefix("www.").unwrap_or(&host);
+        Some(format!("{host}{}", url.path().trim_end_matches('/')))
+    }
+
+    /// Records that the page at `url` is stored in `file`.
+    pub fn insert(&mut self, url: &Url, file: &str) {
+        if let Some(key) = Self::key(url) {
+            if let Entry::Vacant(entry) = self.files.entry(key) {
+                entry.insert(file.to_string());
+                self.taken.insert(file.to_string());
+            }
+        }
+    }
+
+    /// Chooses the file to store the chapter at `url` in. The name is derived from the last segment of the address,
+    /// rather than the position of the chapter, so that it stays the same when the book is rebuilt with new chapters
+    /// and reading positions stored by readers keep pointing to the right place.
+    pub fn file_name(&self, url: &Url) -> String {
+        let segment = url.path_segments().and_then(|mut segments| segments.rfind(|segment| !segment.is_empty())).unwrap_or("");
+        let mut slug = String::new();
+        for c in percent_decode_str(segment).decode_utf8_lossy().to_lowercase().chars() {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                slug.push(c);
+            } else if !slug.is_empty() && !slug.ends_with('-') {
+                slug.push('-');
+            }
+        }
+        let slug = slug.trim_end_matches('-');
+        let slug = if slug.is_empty() { "chapter" } else { slug };
+
+        let mut file = format!("chapter_{slug}.xhtml");
+        let mut n = 2;
+        while self.taken.contains(&file) {
+            file = format!("chapter_{slug}-{n}.xhtml");
+            n += 1;
+        }
+        file
+    }
+
+    /// Finds the file of the chapter given URL points to.
+    pub fn get(&self, url: &Url) -> Option<&str> {
+        Self::key(url).and_then(|key| self.files.get(&key)).map(String::as_str)
+    }
+
+    /// Points links to scraped chapters inside given chapter body to their files inside the book,
+    /// and handles the remaining links according to the `external_links` policy.
+    /// Relative links are resolved against `base_url`, the original address of the chapter.
+    pub fn rewrite_links(&self, body_text: &str, base_url: &Url, external_links: ExternalLinks) -> String {
+        self.rewrite(body_text, |href| base_url.join(href).ok(), external_links)
+    }
+
+    /// Like `rewrite_links`, for pages combining parts of several chapters whose links were already made absolute.
+    /// Relative links are left alone since they point inside the book.
+    pub fn rewrite_absolute_links(&self, body_text: &str, external_links: ExternalLinks) -> String {
+        self.rewrite(body_text, |href| Url::parse(href).ok(), external_links)
+    }
+
+    fn rewrite<F: Fn(&str) -> Option<Url>>(&self, body_text: &str, resolve: F, external_links: ExternalLinks) -> String {
+        let mut doc = Html::parse_fragment(body_text);
+        let mut ops = Vec::new();
+        let mut endnotes = Vec::new();
+
+        for link in doc.select(&HREF_SELECTOR) {
+            let href = link.value().attr("href").unwrap();
+            if href.starts_with('#') {
+                continue;
+            }
+            let url = match resolve(href) {
+                Some(url) => url,
+                None => continue,
+            };
+
+            if let Some(file) = self.get(&url) {
+                ops.push(DomOperation::SetAttribute {
+                    node_id: link.id(),
+                    attr_name: html_attr_name("href"),
+                    value: file.to_string(),
+                });
+                continue;
+            }
+
+            match external_links {
+                ExternalLinks::Keep => {
+                    if href != url.as_str() {
+                        // Relative links would not work inside the book.
+                        ops.push(DomOperation::SetAttribute {
+                            node_id: link.id(),
+                            attr_name: html_attr_name("href"),
+                            value: url.to_string(),
+                        });
+                    }
+                }
+                ExternalLinks::Strip => {
+                    ops.push(DomOperation::DissolveElement { node_id: link.id() });
+                }
+                ExternalLinks::Endnotes => {
+                    endnotes.push(url);
+                    let number = endnotes.len();
+                    ops.push(DomOperation::SetAttribute {
+                        node_id: link.id(),
+                        attr_name: html_attr_name("href"),
+                        value: format!("#endnote-{number}"),
+                    });
+                    ops.push(DomOperation::SetAttribute {
+                        node_id: link.id(),
+                        attr_name: html_attr_name("id"),
+                        value: format!("endnote-ref-{number}"),
+                    });
+                    ops.push(DomOperation::SetAttribute {
+                        node_id: link.id(),
+                        attr_name: html_attr_name("class"),
+                        value: "endnote-ref".to_string(),
+                    });
+                    ops.push(DomOperation::AppendText {
+                        parent_id: link.id(),
+                        text: format!(" [{number}]"),
+                    });
+                }
+            }
+        }
+
+        if ops.is_empty() {
+            // Avoid needlessly re-serializing the chapter.
+            return body_text.to_string();
+        }
+
+        doc.perform_operations(ops);
+        let mut body_text = doc.root_element().inner_xml();
+
+        if !endnotes.is_empty() {
+            body_text.push_str("<section class=\"endnotes\">\n<h2>Links</h2>\n<ol>\n");
+            for (i, url) in endnotes.iter().enumerate() {
+                body_text.push_str(&format!(
+                    "<li id=\"endnote-{number}\"><a href=\"#endnote-ref-{number}\">↩</a> {}</li>\n",
+                    html_escape::encode_text(url.as_str()),
+                    number = i + 1,
+                ));
+            }
+            body_text.push_str("</ol>\n</section>\n");
+        }
+
+        body_text
+    }
+}
+
+#[test]
+fn test_rewrite_links() {
+    let mut chapters = ChapterMap::default();
+    chapters.insert(&Url::parse("https://www.parahumans.net/2017/10/21/glowworm-p-1/").unwrap(), "chapter_1.xhtml");
+    chapters.insert(&Url::parse("https://www.parahumans.net/2017/11/11/daybreak-1-1/").unwrap(), "chapter_2.xhtml");
+
+    let base_url = Url::parse("https://www.parahumans.net/2017/11/14/daybreak-1-2/").unwrap();
+    let body_text = r#"<p>As seen <a href="http://parahumans.net/2017/10/21/glowworm-p-1">before</a> and <a href="/2017/11/11/daybreak-1-1/#comments">earlier</a>, <a href="https://parahumans.wordpress.com/">Worm</a>.</p>"#;
+
+    assert_eq!(
+        chapters.rewrite_links(body_text, &base_url, ExternalLinks::Keep),
+        r#"<p>As seen <a href="chapter_1.xhtml">before</a> and <a href="chapter_2.xhtml">earlier</a>, <a href="https://parahumans.wordpress.com/">Worm</a>.</p>"#,
+    );
+
+    let unlinked = "<p>No <em>links</em> here.</p>";
+    assert_eq!(chapters.rewrite_links(unlinked, &base_url, ExternalLinks::Keep), unlinked);
+}
+
+#[test]
+fn test_file_name() {
+    let mut chapters = ChapterMap::default();
+    let url = Url::parse("https://www.parahumans.net/2017/11/11/daybreak-1-1/").unwrap();
+    assert_eq!(chapters.file_name(&url), "chapter_daybreak-1-1.xhtml");
+    chapters.insert(&url, "chapter_daybreak-1-1.xhtml");
+
+    let other = Url::parse("https://www.parahumans.net/2018/01/01/daybreak-1-1/").unwrap();
+    assert_eq!(chapters.file_name(&other), "chapter_daybreak-1-1-2.xhtml");
+    let unicode = Url::parse("https://www.parahumans.net/2020/05/02/last-20-%e2%80%93-end/").unwrap();
+    assert_eq!(chapters.file_name(&unicode), "chapter_last-20-end.xhtml");
+    assert_eq!(chapters.file_name(&Url::parse("https://example.com/").unwrap()), "chapter_chapter.xhtml");
+}
+
+#[test]
+fn test_external_links() {
+    let chapters = ChapterMap::default();
+    let base_url = Url::parse("https://www.parahumans.net/2017/11/14/daybreak-1-2/").unwrap();
+    let body_text = r#"<p>Read <a href="https://parahumans.wordpress.com/">Worm</a> and <a href="/about/">this</a>.</p>"#;
+
+    assert_eq!(
+        chapters.rewrite_links(body_text, &base_url, ExternalLinks::Keep),
+        r#"<p>Read <a href="https://parahumans.wordpress.com/">Worm</a> and <a href="https://www.parahumans.net/about/">this</a>.</p>"#,
+    );
+    assert_eq!(
+        chapters.rewrite_links(body_text, &base_url, ExternalLinks::Strip),
+        "<p>Read Worm and this.</p>",
+    );
+    // Attribute order is not deterministic, compare the parsed trees.
+    assert_eq!(
+        Html::parse_fragment(&chapters.rewrite_links(body_text, &base_url, ExternalLinks::Endnotes)),
+        Html::parse_fragment(concat!(
+            r##"<p>Read <a href="#endnote-1" id="endnote-ref-1" class="endnote-ref">Worm [1]</a> and <a href="#endnote-2" id="endnote-ref-2" class="endnote-ref">this [2]</a>.</p>"##,
+            "<section class=\"endnotes\">\n<h2>Links</h2>\n<ol>\n",
+            "<li id=\"endnote-1\"><a href=\"#endnote-ref-1\">↩</a> https://parahumans.wordpress.com/</li>\n",
+            "<li id=\"endnote-2\"><a href=\"#endnote-ref-2\">↩</a> https://www.parahumans.net/about/</li>\n",
+            "</ol>\n</section>\n",
+        )),
+    );
+}
+
+#[test]
+fn test_rewrite_absolute_links() {
+    let mut chapters = ChapterMap::default();
+    chapters.insert(&Url::parse("https://www.parahumans.net/2017/11/11/daybreak-1-1/").unwrap(), "chapter_daybreak-1-1.xhtml");
+    let body_text = concat!(
+        r#"<h2><a href="chapter_daybreak-1-1.xhtml">Daybreak 1.1</a></h2>"#,
+        r#"<p>See <a href="https://www.parahumans.net/2017/11/11/daybreak-1-1/">here</a> and <a href="https://parahumans.wordpress.com/">Worm</a>.</p>"#,
+    );
+
+    assert_eq!(
+        chapters.rewrite_absolute_links(body_text, ExternalLinks::Strip),
+        r#"<h2><a href="chapter_daybreak-1-1.xhtml">Daybreak 1.1</a></h2><p>See <a href="chapter_daybreak-1-1.xhtml">here</a> and Worm.</p>"#,
+    );
+}
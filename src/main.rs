@@ -1,38 +1,42 @@
 extern crate structopt;
+extern crate chrono;
 extern crate directories;
-extern crate ego_tree;
 extern crate epub_builder;
-#[macro_use]
-extern crate html5ever;
 extern crate html_escape;
-extern crate markup5ever;
 extern crate reqwest;
+extern crate rust_wildbow_scraper;
 extern crate scraper;
 extern crate easy_error;
 #[macro_use]
 extern crate lazy_static;
-extern crate xml5ever;
-
-mod cached_client;
-mod dom_manipulation;
-mod xml_utils;
 
-use cached_client::CachedClient;
-use dom_manipulation::{DomOperation, MutableDom};
-use html5ever::tree_builder::{NodeOrText, TreeSink};
+use rust_wildbow_scraper::boilerplate::AuthorNotes;
+use rust_wildbow_scraper::cached_client::{CachedClient, TypedBytes};
+use chrono::{DateTime, Utc};
+use rust_wildbow_scraper::comments::{comments_xhtml, count_comments, download_comments, Comment, CommentsMode};
+use rust_wildbow_scraper::cover::{generate_cover, load_cover_file, CoverFiles, CoverFormat, CoverText};
+use rust_wildbow_scraper::epub_directory;
 use structopt::StructOpt;
 use directories::ProjectDirs;
-use epub_builder::{EpubBuilder, EpubContent, EpubVersion, ReferenceType, ZipLibrary};
+use epub_builder::EpubVersion;
 use reqwest::Url;
 use scraper::{ElementRef, Html, Selector};
-use std::fs::File;
 use std::io;
 use std::iter::FromIterator;
 use std::collections::HashMap;
 use std::io::Write;
 use std::path::Path;
 use easy_error::{ResultExt, Error, err_msg};
-use xml_utils::{FilterableTree, html_attr_name, html_elem_name, XmlSerializable};
+use rust_wildbow_scraper::image_processing::ImageOptions;
+use rust_wildbow_scraper::links::{ChapterMap, ExternalLinks};
+use rust_wildbow_scraper::media_type::MediaType;
+use rust_wildbow_scraper::metadata::{book_identifier, epub_date, BookMetadata};
+use rust_wildbow_scraper::transforms::{
+    embed_image, restore_placeholders, FailedImages, ImageError, ImageManager, MissingImage, Pipeline, TransformContext, TransformOptions,
+    TransformRegistry, CONTENT_ELEMENT_SELECTOR, DEFAULT_TRANSFORMS,
+};
+use rust_wildbow_scraper::writers::{new_writer, BookWriter, OutputFormat, Page, PageKind, TextFiles, TextOptions, WriterOptions};
+use rust_wildbow_scraper::xml_utils::{FilterableTree, html_elem_name, XmlSerializable};
 use std::path::PathBuf;
 
 lazy_static! {
@@ -45,9 +49,16 @@ struct Book {
     title: &'static str,
     start: &'static str,
     desc: &'static str,
+    /// Publication date, in RFC 2822 format.
     date: &'static str,
     cover: Option<&'static str>,
+    /// Name of the series the book belongs to and its position in it.
+    series: Option<(&'static str, &'static str)>,
+    /// Genres the book is filed under.
+    subjects: &'static [&'static str],
     final_chapter_title: Option<&'static str>,
+    /// Names of transforms from `TransformRegistry` applied to each chapter, in order.
+    transforms: &'static [&'static str],
 }
 
 /// scrapes books written by Wildbow like Worm, Ward, Twig ETC and converts it to EPUB format.
@@ -89,11 +100,104 @@ struct Args {
 	/// Get covers? Default is to prompt for each book
 	#[structopt(short, long)]
 	covers: Option<bool>,
+    /// Use a generated cover with the title instead of the fan-art one
+    #[structopt(long)]
+    generate_covers: bool,
+    /// Format of generated covers, used for books without a fan-art cover or when it cannot be downloaded
+    #[structopt(long, default_value = "png", possible_values = &["png", "svg"])]
+    cover_format: CoverFormat,
+    /// Use own cover image: `key=path` for a single book, path to an image for all requested books,
+    /// or a directory with images named after the book keys (e.g. `worm.jpg`). Can be given multiple times
+    #[structopt(long, number_of_values = 1)]
+    cover_file: Vec<String>,
+    /// Output format: EPUB, an unpacked EPUB directory for hand editing, a single HTML file readable in any browser,
+    /// a directory of Markdown files, plain text, FB2, MOBI for Kindle, LaTeX source for print, or JSON (Lines) for tooling
+    #[structopt(long, default_value = "epub", possible_values = &["epub", "epub-dir", "html", "markdown", "text", "fb2", "mobi", "latex", "json", "jsonl"])]
+    format: OutputFormat,
+    /// EPUB version to produce: 3, or 2 for older readers
+    #[structopt(long, default_value = "3", possible_values = &["2", "3"], parse(try_from_str = parse_epub_version))]
+    epub_version: EpubVersion,
+    /// Wrap plain text at this many characters, 0 keeps each paragraph on a single line
+    #[structopt(long, default_value = "0")]
+    text_width: usize,
+    /// Write plain text as a single file, or as a directory with a file per chapter
+    #[structopt(long, default_value = "single", possible_values = &["single", "chapters"])]
+    text_files: TextFiles,
+    /// Line marking scene breaks in plain text
+    #[structopt(long, default_value = "* * *")]
+    text_scene_break: String,
+    /// Header starting each chapter in plain text, with {title}, {arc} and {index} replaced; empty for none
+    #[structopt(long, default_value = "{title}")]
+    text_header: String,
+    /// Comma-separated list of content transforms to apply instead of the book’s default pipeline
+    #[structopt(long, use_delimiter = true)]
+    transforms: Option<Vec<String>>,
+    /// List available content transforms and exit
+    #[structopt(long)]
+    list_transforms: bool,
+    /// Comma-separated list of books to combine into a single omnibus, e.g. `worm,glow,ward`
+    #[structopt(long, use_delimiter = true)]
+    omnibus: Option<Vec<String>>,
+    /// Pack a directory written with `--format epub-dir` back into an EPUB and exit
+    #[structopt(long)]
+    pack: Option<PathBuf>,
+    /// What to do with author’s notes: drop them, keep them in a separately styled aside, or collect them in an appendix
+    #[structopt(long, default_value = "drop", possible_values = &["drop", "aside", "appendix"])]
+    author_notes: AuthorNotes,
+    /// Archive reader comments, either as pages at the end of the book or as a separate companion EPUB
+    #[structopt(long, possible_values = &["pages", "companion"])]
+    comments: Option<CommentsMode>,
+    /// What to do with links to outside sites: keep them, strip them to plain text, or list them as endnotes
+    #[structopt(long, default_value = "keep", possible_values = &["keep", "strip", "endnotes"])]
+    external_links: ExternalLinks,
+    /// Scale down images wider than this many pixels
+    #[structopt(long)]
+    max_image_width: Option<u32>,
+    /// Scale down images taller than this many pixels
+    #[structopt(long)]
+    max_image_height: Option<u32>,
+    /// Re-compress JPEG images with given quality (1-100)
+    #[structopt(long)]
+    jpeg_quality: Option<u8>,
+    /// Store photos downloaded as PNG as JPEG instead
+    #[structopt(long)]
+    png_to_jpeg: bool,
+    /// Convert images to grayscale, e.g. for e-ink readers
+    #[structopt(long)]
+    grayscale_images: bool,
+    /// Convert WebP images to JPEG or PNG for readers that do not support them
+    #[structopt(long)]
+    transcode_webp: bool,
+    /// What to do when an image cannot be downloaded: abort, put a placeholder in its place, or retry after the rest of the book is downloaded
+    #[structopt(long, default_value = "abort", possible_values = &["abort", "skip", "retry"])]
+    failed_images: FailedImages,
+}
+
+/// Settings affecting how each of the requested books is scraped and put together.
+struct BookOptions {
+    /// Whether to include covers, `None` prompts for each book.
+    covers: Option<bool>,
+    /// Whether to use generated covers even for books with a fan-art cover.
+    generate_covers: bool,
+    cover_format: CoverFormat,
+    /// Covers supplied by the user, used instead of both fan-art and generated ones.
+    cover_files: CoverFiles,
+    /// Transforms to use instead of each book’s default pipeline.
+    transforms: Option<Vec<String>>,
+    transform_options: TransformOptions,
+    comments: Option<CommentsMode>,
+    external_links: ExternalLinks,
+    format: OutputFormat,
+    writer_options: WriterOptions,
 }
 
 struct DownloadedBook {
-    title: &'static str,
-    builder: EpubBuilder<ZipLibrary>,
+    title: String,
+    writer: Box<dyn BookWriter>,
+    /// Separate book with reader comments, when requested.
+    comments_writer: Option<Box<dyn BookWriter>>,
+    /// Images that could not be embedded in the book.
+    missing_images: Vec<MissingImage>,
 }
 
 fn main() -> Result<(), Error> {
@@ -101,7 +205,7 @@ fn main() -> Result<(), Error> {
 }
 
 fn get_info(key: &str) -> Option<Book> {
-    return Some(match key {
+    Some(match key {
         "worm" => Book {
             title: "Worm",
             start: "https://parahumans.wordpress.com/2011/06/11/1-1/",
@@ -109,7 +213,10 @@ fn get_info(key: &str) -> Option<Book> {
                 "An introverted teenage girl with an unconventional superpower, Taylor goes out in costume to find escape from a deeply unhappy and frustrated civilian life. Her first attempt at taking down a supervillain sees her mistaken for one, thrusting her into the midst of the local ‘cape’ scene’s politics, unwritten rules, and ambiguous morals. As she risks life and limb, Taylor faces the dilemma of having to do the wrong things for the right reasons.",
             date: "Tue, 19 Nov 2013 00:00:00 +0100",
             cover: Some("https://i.imgur.com/g0fLbQ1.jpg"),
+            series: Some(("Parahumans", "1")),
+            subjects: &["Superheroes", "Science fiction", "Web serial"],
             final_chapter_title: Some("Interlude: End"),
+            transforms: DEFAULT_TRANSFORMS,
         },
         "pact" => Book {
             title: "Pact",
@@ -118,7 +225,10 @@ fn get_info(key: &str) -> Option<Book> {
                 "Blake Thorburn was driven away from home and family by a vicious fight over inheritance, returning only for a deathbed visit with the grandmother who set it in motion. Blake soon finds himself next in line to inherit the property, a trove of dark supernatural knowledge, and the many enemies his grandmother left behind her in the small town of Jacob’s Bell.",
             date: "Sat, 07 Mar 2015 00:00:00 +0100",
             cover: Some("https://preview.redd.it/9scpenoq5v671.png?width=1410&format=png&auto=webp&s=c17e05b90d886ed1858aed33fbeeee37ed35a711"),
+            series: None,
+            subjects: &["Urban fantasy", "Horror", "Web serial"],
             final_chapter_title: Some("Epilogue"),
+            transforms: DEFAULT_TRANSFORMS,
         },
         "twig" => Book {
             title: "Twig",
@@ -127,7 +237,10 @@ fn get_info(key: &str) -> Option<Book> {
                 "The year is 1921, and a little over a century has passed since a great mind unraveled the underpinnings of life itself.  Every week, it seems, the papers announce great advances, solving the riddle of immortality, successfully reviving the dead, the cloning of living beings, or blending of two animals into one.  For those on the ground, every week brings new mutterings of work taken by ‘stitched’ men of patchwork flesh that do not need to sleep, or more fearful glances as they have to step off the sidewalks to make room for great laboratory-grown beasts.  Often felt but rarely voiced is the notion that events are already spiraling out of the control of the academies that teach these things. It is only this generation, they say, that the youth and children are able to take the mad changes in stride, accepting it all as a part of day to day life.  Of those children, a small group of strange youths from the Lambsbridge Orphanage stand out, taking a more direct hand in events.",
             date: "Tue, 17 Oct 2017 00:00:00 +0200",
             cover: Some("https://i.imgur.com/3KeIJyz.jpg"),
+            series: None,
+            subjects: &["Science fiction", "Biopunk", "Web serial"],
             final_chapter_title: Some("Forest for the Trees – e.4"),
+            transforms: DEFAULT_TRANSFORMS,
         },
         "glow" => Book {
             title: "Glow-worm",
@@ -136,7 +249,10 @@ fn get_info(key: &str) -> Option<Book> {
                 "The bridge between Worm and Ward, Glow-worm introduces readers to the characters of Ward, and the consequences of Gold Morning",
             date: "Sat, 11 Nov 2017 00:00:00 +0100",
             cover: None,
+            series: Some(("Parahumans", "1.5")),
+            subjects: &["Superheroes", "Science fiction", "Web serial"],
             final_chapter_title: Some("P.9"),
+            transforms: DEFAULT_TRANSFORMS,
         },
         "ward" => Book {
             title: "Ward",
@@ -145,7 +261,10 @@ fn get_info(key: &str) -> Option<Book> {
                 "The unwritten rules that govern the fights and outright wars between ‘capes’ have been amended: everyone gets their second chance.  It’s an uneasy thing to come to terms with when notorious supervillains and even monsters are playing at being hero.  The world ended two years ago, and as humanity straddles the old world and the new, there aren’t records, witnesses, or facilities to answer the villains’ past actions in the present.  One of many compromises, uneasy truces and deceptions that are starting to splinter as humanity rebuilds. None feel the injustice of this new status quo or the lack of established footing more than the past residents of the parahuman asylums.  The facilities hosted parahumans and their victims, but the facilities are ruined or gone; one of many fragile ex-patients is left to find a place in a fractured world.  She’s perhaps the person least suited to have anything to do with this tenuous peace or to stand alongside these false heroes.  She’s put in a position to make the decision: will she compromise to help forge what they call, with dark sentiment, a second golden age?  Or will she stand tall as a gilded dark age dawns?",
             date: "Sat, 11 Nov 2017 00:00:00 +0100",
             cover: Some("https://i.redd.it/2c4czdyhnqv41.jpg"),
+            series: Some(("Parahumans", "2")),
+            subjects: &["Superheroes", "Science fiction", "Web serial"],
             final_chapter_title: Some("Last – 20.end"),
+            transforms: DEFAULT_TRANSFORMS,
         },
         "pale" => Book {
             title: "Pale",
@@ -153,7 +272,10 @@ fn get_info(key: &str) -> Option<Book> {
             desc: "There are ways of being inducted into the practices, those esoteric traditions that predate computers, cell phones, the engines industry, and even paper and bronze.  Make the right deals, learn the right words to say or symbols to write down, and you can make the wind listen to you, exchange your skin for that of a serpent, or call forth the sorts of monsters that appear in horror movies.",
             date: "Tue, 05 May 2020 00:00:00 +0100",
             cover: Some("https://i.redd.it/xnp5vvxvnr471.png"),
+            series: None,
+            subjects: &["Urban fantasy", "Mystery", "Web serial"],
             final_chapter_title: Some("Loose Ends – E.6"),
+            transforms: DEFAULT_TRANSFORMS,
         },
         "claw" => Book {
             title: "Claw",
@@ -161,7 +283,10 @@ fn get_info(key: &str) -> Option<Book> {
             desc: "Joshua Munce, Sheila Hardy, Dan Whitely, Max Highland, Tonya Keifer, Marvin Su… this pair has many names, but those names aren’t their own; they’re names to sell.  In a rigged and crumbling system, the only way to get ahead is to circumvent the rules, but that comes with its own risks.  Police, investigations, prison.  There are other ways, more insulated, which are to play assist to help those people.  Helping them to disappear, cleaning up messes, escrow services for the handling of good, payment, or guests.  Always keeping it professional, keeping things insulated, with layers of distance.  When others panic, with too many variables to consider in the heat of the moment, they can do the thinking.  Who would suspect this mom and dad with two kids?",
             date: "Tue, 09 Mar 2024 00:00:00 +0100",
             cover: None,
+            series: None,
+            subjects: &["Crime", "Thriller", "Web serial"],
             final_chapter_title: Some("Bear – 6.6"),
+            transforms: DEFAULT_TRANSFORMS,
         },
         "seek" => Book {
             title: "Seek",
@@ -169,15 +294,18 @@ fn get_info(key: &str) -> Option<Book> {
             desc: "Despite our best efforts, few survived faster than light travel.  None survived the trip back.  So we took a different approach altogether.  We started bringing the universe to us. There’s no point.  What hasn’t changed in the last four hundred years won’t change in our lifetimes. There’s no point.  We’ve solved it.  Everything humanity needs, it has.  We’ve reached the finish line. There’s no point.  Turn off the lights, close your eyes, and cover your ears, nightmares come manifest.Three storylines from three individuals, worlds and eras apart.",
             date: "Fri, 18 Oct 2024 00:00:00 +0100",
             cover: None,
+            series: None,
+            subjects: &["Science fiction", "Web serial"],
             final_chapter_title: None,
+            transforms: DEFAULT_TRANSFORMS,
         },
         _ => return None,
-    });
+    })
 }
 
 fn prompt_cover(title: &str, url: &str) -> Result<bool, Error> {
     print!(
-        "Would you like to include a cover for {}? Cover URL is {}. If it cannot be downloaded, program will not exit gracefully.(y/n)",
+        "Would you like to include a cover for {}? Cover URL is {}. If it cannot be downloaded, a generated cover will be used instead. (y/n)",
         title,
         url
     );
@@ -189,142 +317,382 @@ fn prompt_cover(title: &str, url: &str) -> Result<bool, Error> {
     Ok(buf.trim() == "y" || buf.trim() == "yes")
 }
 
-#[derive(Debug)]
-enum MediaType {
-    Jpeg,
-    Png,
-    Svg,
-}
+fn interpret_args() -> Result<(), Error> {
+    let args = Args::from_args(); // parse command line arguments, print help messages, and make sure all the arguments are valid. This feature is provided by structopt
 
-impl MediaType {
-    pub fn content_type(&self) -> &'static str {
-        match self {
-            Self::Jpeg => "image/jpeg",
-            Self::Png => "image/png",
-            Self::Svg => "image/svg+xml",
+    let registry = TransformRegistry::new();
+    if args.list_transforms {
+        for name in registry.names() {
+            println!("{name}");
         }
+        return Ok(());
     }
-
-    pub fn extension(&self) -> &'static str {
-        match self {
-            Self::Jpeg => "jpg",
-            Self::Png => "png",
-            Self::Svg => "svg",
-        }
+    if let Some(ref directory) = args.pack {
+        return pack_directory(directory, args.output.clone());
     }
 
-    pub fn from_url(url: &Url) -> Result<Self, Error> {
-        let extension = url.path().split('.').last().ok_or(err_msg(format!("Cannot obtain without suffix specified: {url}")))?;
-
-        if extension == "png" {
-            Ok(Self::Png)
-        } else if extension == "svg" {
-            Ok(Self::Svg)
-        } else if extension == "jpg" || extension == "jpeg" {
-            Ok(Self::Jpeg)
-        } else {
-            Err(err_msg(format!("Given URL probably is not one of the supported media types: {url}")))
-        }
-    }
-}
-
-fn interpret_args() -> Result<(), Error> {
-    let args = Args::from_args(); // parse command line arguments, print help messages, and make sure all the arguments are valid. This feature is provided by structopt
-
     let proj_dirs = ProjectDirs::from("net", "Demenses",  "rust-wildbow-scraper");
     let cache_dir = proj_dirs.as_ref().map(|dirs| dirs.cache_dir());
     if let Some(cache_path) = cache_dir {
         println!("Using cache directory: {:?}", cache_path);
     }
 
+    let cover_files = CoverFiles::new(&args.cover_file)?;
+    if let Some(key) = cover_files.book_keys().find(|key| *key != "omnibus" && get_info(key).is_none()) {
+        return Err(err_msg(format!("Cover file given for unknown book {key}")));
+    }
+    if let Some(key) = args.omnibus.iter().flatten().find(|key| get_info(key).is_none()) {
+        return Err(err_msg(format!("Unknown book {key} in omnibus")));
+    }
+
+    let options = BookOptions {
+        covers: args.covers,
+        generate_covers: args.generate_covers,
+        cover_format: args.cover_format,
+        cover_files,
+        transforms: args.transforms.clone(),
+        transform_options: TransformOptions {
+            author_notes: args.author_notes,
+            images: ImageOptions {
+                max_width: args.max_image_width,
+                max_height: args.max_image_height,
+                jpeg_quality: args.jpeg_quality,
+                png_to_jpeg: args.png_to_jpeg,
+                grayscale: args.grayscale_images,
+                // WebP is not among the image types EPUB 2 readers have to support.
+                transcode_webp: args.transcode_webp
+                    || (args.epub_version == EpubVersion::V20 && matches!(args.format, OutputFormat::Epub | OutputFormat::EpubDirectory)),
+            },
+            failed_images: args.failed_images,
+        },
+        comments: args.comments,
+        external_links: args.external_links,
+        format: args.format,
+        writer_options: WriterOptions {
+            epub_version: args.epub_version,
+            text: TextOptions {
+                width: args.text_width,
+                files: args.text_files,
+                scene_break: args.text_scene_break.clone(),
+                header: args.text_header.clone(),
+            },
+        },
+    };
+
+    let mut missing_images = Vec::new();
+    let mut results = Vec::new();
+
+    let mut finish_book = |book: Result<DownloadedBook, Error>| {
+        results.push(book.and_then(|mut book| {
+            let title = book.title.clone();
+            missing_images.extend(book.missing_images.drain(..).map(|image| (title.clone(), image)));
+            process_book(book, args.output.clone())
+        }));
+    };
     // an anonymous function which adds the book with name name to books if requested is true
-    let add_book = |name, requested| {
+    let mut add_book = |name, requested| {
         if requested {
-            process_book(download_book(cache_dir, name, args.covers)?, args.output.clone())?;
+            finish_book(download_book(cache_dir, name, &registry, &options));
         }
-        let result: Result<(), Error> = Ok(());
-        result
     };
-    add_book("worm", args.worm || args.all)?;
-    add_book("ward", args.ward || args.all)?;
-    add_book("pact", args.pact || args.all)?;
-    add_book("pale", args.pale || args.all)?;
-    add_book("claw", args.claw || args.all)?;
-    add_book("glow", args.glow_worm || args.all)?;
-    add_book("twig", args.twig || args.all)?;
-    add_book("seek", args.seek || args.all)
+    add_book("worm", args.worm || args.all);
+    add_book("ward", args.ward || args.all);
+    add_book("pact", args.pact || args.all);
+    add_book("pale", args.pale || args.all);
+    add_book("claw", args.claw || args.all);
+    add_book("glow", args.glow_worm || args.all);
+    add_book("twig", args.twig || args.all);
+    add_book("seek", args.seek || args.all);
+    if let Some(ref names) = args.omnibus {
+        finish_book(download_omnibus(cache_dir, names, &registry, &options));
+    }
+
+    if !missing_images.is_empty() {
+        println!("{} image(s) could not be embedded:", missing_images.len());
+        for (title, image) in missing_images {
+            match image.page_url {
+                Some(page_url) => println!("  {title}: {} (on {page_url}): {}", image.url, image.reason),
+                None => println!("  {title}: cover {}: {}", image.url, image.reason),
+            }
+        }
+    }
+
+    // The first error is returned after the summary, so that it still lists the images of the other books.
+    results.into_iter().collect()
 }
 
 fn download_book<P: AsRef<Path>>(
     cache_dir: Option<P>,
     name: &str,
-    download_cover_default: Option<bool>
+    registry: &TransformRegistry,
+    options: &BookOptions,
 ) -> Result<DownloadedBook, Error> {
     let book = get_info(name).ok_or(err_msg(format!("Unknown book {name}")))?;
+    let pipeline = book_pipeline(&book, registry, options)?;
+    let page_url = Url::parse(book.start).context(format!("Could not create url from '{}'", book.start))?;
+    let metadata = book_metadata(name, &book)?;
+    let mut writer = new_writer(options.format, metadata.clone(), &options.writer_options)?;
 
-    let mut builder = EpubBuilder::new(ZipLibrary::new().context("Could not create ZipLibrary")?).context("Could not create EpubBuilder")?;
+    let book_cache_dir = cache_dir.map(|dir| dir.as_ref().join(name));
+    let client = CachedClient::new(book_cache_dir)?;
+    let mut missing_images = Vec::new();
+    let mut contents = BookContents::default();
 
-    let stylesheet = "
-        .indent-one {
-            margin-left: 2em;
-        }
-        .indent-two {
-            margin-left: 4em;
-        }
-        .aligncenter, .center {
-            text-align: center;
+    add_cover(writer.as_mut(), &client, name, &book, options, &mut missing_images)?;
+
+    download_pages(&book, Some(page_url), writer.as_mut(), client, &pipeline, options, &mut missing_images, &mut contents, 1)?;
+    let chapter_comments = add_back_matter(writer.as_mut(), contents, options)?;
+
+    Ok(DownloadedBook {
+        title: book.title.to_string(),
+        writer,
+        comments_writer: new_comments_writer(name, &metadata, &chapter_comments, options)?,
+        missing_images,
+    })
+}
+
+/// Downloads the books with given keys into a single omnibus, with an entry for each book in the table of contents
+/// and the chapters of the book below it.
+fn download_omnibus<P: AsRef<Path>>(
+    cache_dir: Option<P>,
+    names: &[String],
+    registry: &TransformRegistry,
+    options: &BookOptions,
+) -> Result<DownloadedBook, Error> {
+    let mut books = Vec::new();
+    let mut parts = Vec::new();
+    for name in names {
+        let book = get_info(name).ok_or(err_msg(format!("Unknown book {name}")))?;
+        parts.push(book_metadata(name, &book)?);
+        books.push((name, book));
+    }
+    let metadata = BookMetadata::omnibus(&parts);
+    let mut writer = new_writer(options.format, metadata.clone(), &options.writer_options)?;
+
+    if options.covers != Some(false) {
+        let (data, filetype) = match options.cover_files.find("omnibus") {
+            Some(path) => {
+                println!("Using cover from {path:?}");
+                load_cover_file(&path)?
+            }
+            None => {
+                println!("Generating cover for {}", metadata.title);
+                let volumes = omnibus_volumes(books.iter().map(|(_, book)| book));
+                let text = CoverText { title: &metadata.title, author: "Wildbow", series: None, volume: volumes.as_deref() };
+                generate_cover(&text, options.cover_format)
+            }
+        };
+        add_processed_cover(writer.as_mut(), data, filetype, options)?;
+    }
+
+    let mut missing_images = Vec::new();
+    // Shared by the books so that images used in several of them are only stored once
+    // and links between the books point inside the omnibus.
+    let mut contents = BookContents::default();
+    for (name, book) in books {
+        let pipeline = book_pipeline(&book, registry, options)?;
+        let page_url = Url::parse(book.start).context(format!("Could not create url from '{}'", book.start))?;
+        let client = CachedClient::new(cache_dir.as_ref().map(|dir| dir.as_ref().join(name)))?;
+
+        writer.add_page(Page {
+            file: format!("{name}.xhtml"),
+            title: book.title.to_string(),
+            body_text: format!("<p>{}</p>\n", html_escape::encode_text(book.desc)),
+            kind: PageKind::Chapter,
+            level: 1,
+            arc: None,
+            url: None,
+            fetched_at: None,
+        })?;
+        // Retrying failed images only restores them in the chapters of the current book.
+        let mut book_missing_images = Vec::new();
+        download_pages(&book, Some(page_url), writer.as_mut(), client, &pipeline, options, &mut book_missing_images, &mut contents, 2)?;
+        missing_images.extend(book_missing_images);
+    }
+    let chapter_comments = add_back_matter(writer.as_mut(), contents, options)?;
+
+    Ok(DownloadedBook {
+        comments_writer: new_comments_writer("omnibus", &metadata, &chapter_comments, options)?,
+        title: metadata.title,
+        writer,
+        missing_images,
+    })
+}
+
+/// Range of volumes of a series the omnibus is made of, e.g. `1–2`, for its cover.
+/// There is none for books from different series, or not belonging to any.
+fn omnibus_volumes<'a>(books: impl Iterator<Item = &'a Book>) -> Option<String> {
+    let mut series = None;
+    let mut volumes = Vec::new();
+    for book in books {
+        let (name, index) = book.series?;
+        if series.replace(name).is_some_and(|previous| previous != name) {
+            return None;
         }
-        .right {
-            text-align: right;
+        volumes.push((index.parse::<f64>().ok()?, index));
+    }
+    volumes.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    let (_, first) = volumes.first()?;
+    let (_, last) = volumes.last()?;
+    Some(if first == last { first.to_string() } else { format!("{first}–{last}") })
+}
+
+#[test]
+fn test_omnibus_volumes() {
+    let books = |names: &[&str]| names.iter().map(|name| get_info(name).unwrap()).collect::<Vec<_>>();
+    assert_eq!(omnibus_volumes(books(&["ward", "worm", "glow"]).iter()).as_deref(), Some("1–2"));
+    assert_eq!(omnibus_volumes(books(&["worm"]).iter()).as_deref(), Some("1"));
+    assert_eq!(omnibus_volumes(books(&["worm", "pact"]).iter()), None);
+}
+
+fn book_pipeline(book: &Book, registry: &TransformRegistry, options: &BookOptions) -> Result<Pipeline, Error> {
+    match options.transforms {
+        Some(ref names) => Pipeline::from_names(registry, names, &options.transform_options),
+        None => Pipeline::from_names(registry, book.transforms, &options.transform_options),
+    }
+}
+
+fn book_metadata(name: &str, book: &Book) -> Result<BookMetadata, Error> {
+    let page_url = Url::parse(book.start).context(format!("Could not create url from '{}'", book.start))?;
+    Ok(BookMetadata {
+        title: book.title.to_string(),
+        author: "Wildbow".to_string(),
+        author_file_as: Some("McCrae, John".to_string()),
+        description: book.desc.to_string(),
+        language: "en-US".to_string(),
+        identifier: book_identifier(name),
+        date: epub_date(book.date)?,
+        series: book.series.map(|(series, index)| (series.to_string(), index.to_string())),
+        subjects: book.subjects.iter().map(|subject| subject.to_string()).collect(),
+        // The serials are self-published under the pen name.
+        publisher: Some("Wildbow".to_string()),
+        source: Some(page_url.origin().ascii_serialization() + "/"),
+    })
+}
+
+/// Creates the separate book with reader comments, when requested.
+fn new_comments_writer(
+    name: &str,
+    metadata: &BookMetadata,
+    chapter_comments: &[ChapterComments],
+    options: &BookOptions,
+) -> Result<Option<Box<dyn BookWriter>>, Error> {
+    if options.comments != Some(CommentsMode::Companion) {
+        return Ok(None);
+    }
+    let comments_metadata = BookMetadata {
+        title: format!("{} – Comments", metadata.title),
+        description: format!("Reader comments on {}", metadata.title),
+        identifier: book_identifier(&format!("{name}-comments")),
+        ..metadata.clone()
+    };
+    let mut comments_writer = new_writer(options.format, comments_metadata, &options.writer_options)?;
+    add_comment_pages(comments_writer.as_mut(), chapter_comments, None, options.external_links)?;
+    Ok(Some(comments_writer))
+}
+
+/// Adds the cover supplied by the user or the fan-art cover of the book to it, or a generated one
+/// when the book has none, it cannot be used, or generated covers were requested.
+fn add_cover(
+    writer: &mut dyn BookWriter,
+    client: &CachedClient,
+    name: &str,
+    book: &Book,
+    options: &BookOptions,
+    missing_images: &mut Vec<MissingImage>,
+) -> Result<(), Error> {
+    if options.covers == Some(false) {
+        println!("Not using cover.");
+        return Ok(());
+    }
+
+    let downloaded = if let Some(path) = options.cover_files.find(name) {
+        println!("Using cover from {path:?}");
+        Some(load_cover_file(&path)?)
+    } else {
+        match book.cover {
+            Some(cover) if !options.generate_covers => {
+                let download_cover = match options.covers {
+                    Some(download) => download,
+                    None => prompt_cover(book.title, cover)?
+                };
+                if !download_cover {
+                    println!("Not using cover.");
+                    return Ok(());
+                }
+                download_cover_image(client, cover, missing_images)?
+            }
+            _ => None,
         }
-        .size-full {
-            width: 100%;
-            height: auto;
-            object-fit: contain;
+    };
+
+    let (data, filetype) = downloaded.unwrap_or_else(|| {
+        println!("Generating cover for {}", book.title);
+        generate_cover(
+            &CoverText {
+                title: book.title,
+                author: "Wildbow",
+                series: book.series.map(|(series, _)| series),
+                volume: book.series.map(|(_, index)| index),
+            },
+            options.cover_format,
+        )
+    });
+    add_processed_cover(writer, data, filetype, options)
+}
+
+/// Adds the cover after running it through the same image processing as the images in the chapters.
+fn add_processed_cover(writer: &mut dyn BookWriter, data: Vec<u8>, filetype: MediaType, options: &BookOptions) -> Result<(), Error> {
+    let (data, filetype) = match options.transform_options.images.process(&data, filetype) {
+        Ok(Some(processed)) => processed,
+        Ok(None) => (data, filetype),
+        Err(error) => {
+            println!("Warning: Using unprocessed cover: {error}");
+            (data, filetype)
         }
-    ";
-
-    builder
-    .epub_version(EpubVersion::V30)
-    .stylesheet(stylesheet.as_bytes()).context("Could not set stylesheet")?
-    .metadata("author", "John McCrae").context("Could not set author metadata")?
-    .metadata("title", book.title).context("Could not set title metadata")?
-    .metadata("lang", "en-US").context("Could not set language metadata")?
-    .metadata("description", book.desc).context("Could not set description metadata")?;
-    // date metadata not yet supported
-    //.metadata(book.date)?
+    };
+    writer.add_cover(&data, filetype)
+}
 
-    let book_cache_dir = cache_dir.map(|dir| dir.as_ref().join(name));
-    let client = CachedClient::new(book_cache_dir)?;
+/// Downloads the cover from given URL, returning `None` when it cannot be used.
+fn download_cover_image(client: &CachedClient, cover: &str, missing_images: &mut Vec<MissingImage>) -> Result<Option<(Vec<u8>, MediaType)>, Error> {
+    let cover_url = Url::parse(cover).context(format!("Could not construct url from '{}'", cover))?;
+    let res = match client.fetch::<TypedBytes>(&cover_url, false).context(format!("Could not retrieve data from url '{}", cover_url)) {
+        Ok(res) => res,
+        Err(error) => {
+            println!("Warning: Not using downloaded cover: {error}");
+            missing_images.push(MissingImage {
+                url: cover_url,
+                page_url: None,
+                reason: error.to_string(),
+                placeholder_id: None,
+                alt: String::new(),
+            });
+            return Ok(None);
+        }
+    };
+    if res.is_cached() {
+        println!("Using cover from cache for {cover}");
+    } else {
+        println!("Downloaded cover from {cover}");
+    }
 
-    if let Some(cover) = book.cover {
-        let download_cover = match download_cover_default {
-            Some(download) => download,
-            None => prompt_cover(book.title, cover)?
-        };
-        if download_cover {
-            let cover_url = Url::parse(&cover).context(format!("Could not construct url from '{}'", cover))?;
-            let res = client.fetch::<Vec<u8>>(&cover_url, false).context(format!("Could not retrieve data from url '{}", cover_url))?;
-            if res.is_cached() {
-                println!("Using cover from cache for {cover}");
-            } else {
-                println!("Downloaded cover from {cover}");
-            }
-            let data = res.contents();
-            let filetype = MediaType::from_url(&cover_url)?;
-            builder.add_cover_image(format!("cover.{}", filetype.extension()), &**data, filetype.content_type())
-                   .context("Could not add cover image")?;
-        } else {
-            println!("Not using cover.");
+    let data = res.contents();
+    match MediaType::detect(&data.bytes, data.content_type.as_deref(), &cover_url) {
+        Ok(filetype) => Ok(Some((data.bytes.clone(), filetype))),
+        Err(error) => {
+            println!("Warning: Not using downloaded cover: {error}");
+            Ok(None)
         }
     }
-    let page_url = Url::parse(&book.start).context(format!("Could not create url from '{}'", book.start))?;
-    download_pages(&book, Some(page_url), &mut builder, client)?;
+}
 
-    Ok(DownloadedBook {
-        title: book.title,
-        builder: builder,
-    })
+fn parse_epub_version(version: &str) -> Result<EpubVersion, Error> {
+    match version {
+        "2" => Ok(EpubVersion::V20),
+        "3" => Ok(EpubVersion::V30),
+        _ => Err(err_msg(format!("Unknown EPUB version {version}, expected one of: 2, 3"))),
+    }
 }
 
 fn style_classes(input: ElementRef) -> String {
@@ -332,12 +700,11 @@ fn style_classes(input: ElementRef) -> String {
 
     let mut properties = if let Some(style) = attrs.remove("style") {
         let parsed: Vec<(&str, &str)> = style.split(";")
-            .map(|property|
+            .filter_map(|property|
                 property
                     .split_once(":")
                     .map(|(name, value)| (name.trim(), value.trim()))
             )
-            .filter_map(|property| property)
             .collect();
         HashMap::from_iter(parsed)
     } else {
@@ -436,185 +803,235 @@ fn test_style_classes() {
 
 lazy_static! {
     static ref META_REFRESH_SELECTOR: Selector = Selector::parse(r#"meta[http-equiv="refresh"]"#).unwrap();
-    static ref CONTENT_ELEMENT_SELECTOR: Selector = Selector::parse("div.entry-content p, div.entry-content h1").unwrap();
-    static ref LINK_SELECTOR: Selector = Selector::parse("a").unwrap();
+    static ref ENTRY_CONTENT_SELECTOR: Selector = Selector::parse("div.entry-content").unwrap();
+    static ref AUTHOR_NOTE_SELECTOR: Selector = Selector::parse("aside.author-note").unwrap();
+    static ref SERIALIZED_BLOCK_SELECTOR: Selector = Selector::parse("p, h1, aside.author-note, figure").unwrap();
     static ref NEXT_LINK_SELECTOR: Selector = Selector::parse(r#"a[rel="next"]"#).unwrap();
     static ref TITLE_SELECTOR: Selector = Selector::parse("title").unwrap();
     static ref IMAGE_SELECTOR: Selector = Selector::parse("img").unwrap();
-    static ref CLOUDFLARE_EMAIL_SELECTOR: Selector = Selector::parse("a.__cf_email__[data-cfemail]").unwrap();
-    static ref USELESS_SPAN_SELECTOR: Selector = Selector::parse(r#"span:not([class]):not([style]), span.short_text[id="result_box"], span.short_text[id="result_box"] span.hps"#).unwrap();
-}
-
-/// Cloudflare mangles anything even vaguely resembling an email into a string that's decoded by
-/// javascript on the client. For example, 'Point_Me_@_The_Sky' turns into:
-///   '<a href="/cdn-cgi/l/email-protection" class="__cf_email__" data-cfemail="...">[email&nbsp;protected]</a>_The_Sky'
-fn fix_cloudflare_links(doc: &mut Html) {
-    let cloudflare_links = doc.select(&CLOUDFLARE_EMAIL_SELECTOR).map(|elem| {
-        let data = elem.value().attr("data-cfemail").unwrap();
-        let bytes = hex::decode(data).expect("mangled email data is not a hex string");
-        assert!(bytes.len() >= 4, "mangled email data not long enough");
-        let key = bytes[0];
-        let decoded = bytes[1..]
-            .iter()
-            .map(|byte| byte ^ key)
-            .collect::<Vec<u8>>();
-
-        DomOperation::ReplaceElement {
-            node_id: elem.id(),
-            replacement: NodeOrText::AppendText(std::str::from_utf8(&decoded).expect("decoded email isn't a UTF-8 string").into()),
-        }
-    }).collect();
-
-    doc.perform_operations(cloudflare_links);
 }
 
-/// Creates a copy of given image element with cleaned up attributes.
-fn clean_up_image(img: &ElementRef, new_src: String) -> Vec<DomOperation<<Html as TreeSink>::Handle>> {
-    let img_id = img.id();
+/// Converts the content elements of a cleaned up chapter body into XHTML paragraphs.
+/// Author’s notes set aside by the boilerplate transform are either kept in place,
+/// or, when `extract_author_notes` is true, returned separately.
+fn serialize_content(content: &Html, extract_author_notes: bool) -> (String, Vec<String>) {
+    let mut body_text = String::new();
+    let mut author_notes = Vec::new();
 
-    vec![
-        // Since we will not download all the differently size versions,
-        // let’s ignore srcset & other related attributes.
-        // The other sizes are auto-generated by WordPress anyway.
-        DomOperation::RemoveAttribute {
-            node_id: img_id,
-            attr_name: html_attr_name("srcset"),
-        },
-        DomOperation::RemoveAttribute {
-            node_id: img_id,
-            attr_name: html_attr_name("sizes"),
-        },
-        DomOperation::RemoveAttribute {
-            node_id: img_id,
-            attr_name: html_attr_name("loading"),
-        },
-        // Replace src.
-        DomOperation::SetAttribute {
-            node_id: img_id,
-            attr_name: html_attr_name("src"),
-            value: new_src,
-        },
-    ]
+    for elem in content.select(&SERIALIZED_BLOCK_SELECTOR) {
+        if elem.value().name() == "aside" {
+            let note_text = serialize_paragraphs(elem.select(&CONTENT_ELEMENT_SELECTOR));
+            if extract_author_notes {
+                author_notes.push(note_text);
+            } else {
+                body_text.push_str("<aside class=\"author-note\">\n");
+                body_text.push_str(&note_text);
+                body_text.push_str("</aside>\n");
+            }
+        } else if elem.ancestors().filter_map(ElementRef::wrap).any(|ancestor| AUTHOR_NOTE_SELECTOR.matches(&ancestor) || ancestor.value().name() == "figure") {
+            // Serialized together with their container.
+        } else if elem.value().name() == "figure" {
+            body_text.push_str(&("<figure>".to_string() + &elem.inner_xml() + "</figure>\n"));
+        } else {
+            body_text.push_str(&serialize_paragraphs(std::iter::once(elem)));
+        }
+    }
+
+    (body_text, author_notes)
 }
 
-type ImageManager = HashMap<Vec<u8>, (String, &'static str)>;
+fn serialize_paragraphs<'a, I: Iterator<Item = ElementRef<'a>>>(elems: I) -> String {
+    let content_elems = elems
+        .filter(|elem| {
+            // Remove paragraphs which only have text inside links and there is no image.
+            // Those are probably “Next/Previous Chapter” links.
+            // If there is an image, it is probably due to the link’s paragraph being
+            // accidentally smooshed into the paragraph with protagonist image (used e.g. by Pale).
+            // That was already tackled during image handling.
+            elem.select(&IMAGE_SELECTOR).next().is_some()
+            || ! elem.text_filter(|elem| elem.name != html_elem_name("a")).collect::<String>().trim().is_empty()
+        });
 
-/// Removes unnecessary attributes on images, changes their sources to point inside the EPUB,
-/// and if the paragraph containing the image also contains junk, remove it.
-fn clean_up_images(
-    doc: &mut Html,
-    client: &CachedClient,
-    images: &mut ImageManager,
-    page_url: &Url,
-) -> Result<(), Error> {
-    let root = doc.root_element();
-    let img_elems = root.select(&IMAGE_SELECTOR);
-
-    let has_single_image = img_elems.clone().count() == 1;
-    let should_replace_paragraph_contents = has_single_image && if let Some(link) = root.select(&LINK_SELECTOR).next() {
-        // Some Pale chapters (e.g. 18.10) have image paragraphs contaminated with “Next/Previous Chapter” links.
-        // If we detect that happened and there is no text in the paragraph outside the link, let’s keep only the image.
-        root.text().collect::<String>().trim() == link.text().collect::<String>().trim()
-    } else {
-        false
-    };
+    let mut body_text = String::new();
 
-    let mut ops = Vec::new();
+    for elem in content_elems {
+        body_text.push_str(&("<p".to_string() + &style_classes(elem) + ">" + &elem.inner_xml() + "</p>\n"));
+    }
 
-    for img in img_elems {
-        if let Some(src) = img.value().attr("src") {
-            let mut image_url = page_url.join(src).context("Could not resolve image URL")?;
-            // Remove `w` and `h` parameters from query string to download full-size image.
-            image_url.set_query(None);
+    body_text
+}
 
-            let res = client.fetch::<Vec<u8>>(&image_url, false)?;
-            if res.is_cached() {
-                println!("Found image in cache for {image_url}");
-            } else {
-                println!("Downloaded image from {image_url}");
-            }
+#[test]
+fn test_serialize_content() {
+    let content = Html::parse_fragment(r#"<p style="text-align:center;">■</p><p><a href="prev">Last Chapter</a> <a href="next">Next Chapter</a></p><div class="sharedaddy"><h3>Share this:</h3></div><h1>Heading</h1>"#);
 
-            let contents = res.contents().to_vec();
+    assert_eq!(
+        serialize_content(&content, false),
+        ("<p class=\"center\">■</p>\n<p>Heading</p>\n".to_string(), vec![]),
+    );
 
-            // Use prefix otherwise epub_builder will produce invalid ids (starting with a number).
-            let url_path = format!("images{}", image_url.path());
-            let path = if let Some((path, _content_type)) = images.get(&contents) {
-                // Pale re-uploads the same header image multiple times.
-                // Let’s use the first image if one with the same contents already exists
-                // to avoid bloating the EPUB.
-                println!("Will re-use {path} instead of {image_url} since it is the same.");
+    let content = Html::parse_fragment(r#"<p>Story.</p><aside class="author-note"><p>Thanks for reading.</p><p><a href="next">Next Chapter</a></p></aside>"#);
 
-                path.clone()
-            } else {
-                let content_type = MediaType::from_url(&image_url)?.content_type();
-                images.insert(contents, (url_path.clone(), content_type));
-
-                url_path
-            };
-
-            let mut new_img_changes = clean_up_image(&img, path);
-            ops.append(&mut new_img_changes);
-            if should_replace_paragraph_contents {
-                // Remove everything and then put just the image back.
-                ops.push(
-                    DomOperation::RemoveChildren {
-                        node_id: root.id(),
-                    }
-                );
-                ops.push(
-                    DomOperation::AppendChild {
-                        parent_id: root.id(),
-                        child_id: img.id(),
-                    }
-                );
-                break;
-            }
-        }
-    }
+    assert_eq!(
+        serialize_content(&content, false),
+        ("<p>Story.</p>\n<aside class=\"author-note\">\n<p>Thanks for reading.</p>\n</aside>\n".to_string(), vec![]),
+    );
+
+    assert_eq!(
+        serialize_content(&content, true),
+        ("<p>Story.</p>\n".to_string(), vec!["<p>Thanks for reading.</p>\n".to_string()]),
+    );
 
-    doc.perform_operations(ops);
+    let content = Html::parse_fragment(r#"<p>Story.</p><figure><img src="images/map.png"><figcaption><p>The city</p></figcaption></figure>"#);
 
-    Ok(())
+    assert_eq!(
+        serialize_content(&content, false),
+        ("<p>Story.</p>\n<figure><img src=\"images/map.png\"/><figcaption><p>The city</p></figcaption></figure>\n".to_string(), vec![]),
+    );
 }
 
-/// Removes spans that are not used for styling the content
-/// such as various junk inserted by WordPress’s editor.
-fn remove_useless_spans(doc: &mut Html) {
-    let useless_spans = doc.select(&USELESS_SPAN_SELECTOR);
+/// Chapter content and links extracted from a single page.
+struct DownloadedPage {
+    body_text: String,
+    title: String,
+    /// Address of the page after following redirects.
+    url: Url,
+    /// When the page was downloaded, possibly in an earlier run when it comes from the cache.
+    fetched_at: DateTime<Utc>,
+    next_page: Option<Url>,
+    /// Author’s notes removed from the body, when they are to be collected in an appendix.
+    author_notes: Vec<String>,
+    /// Reader comments, when they are to be archived.
+    comments: Vec<Comment>,
+}
 
-    let mut ops = Vec::new();
+/// Chapter waiting to be added to the book once all the chapters are known.
+struct Chapter {
+    file: String,
+    title: String,
+    arc: Option<String>,
+    url: Url,
+    fetched_at: DateTime<Utc>,
+    body_text: String,
+    /// Author’s notes to be collected in the appendix.
+    author_notes: Vec<String>,
+}
 
-    for span in useless_spans {
-        ops.push(DomOperation::DissolveElement {
-            node_id: span.id(),
-        })
+/// Name of the arc from a chapter title like “Gestation 1.1” or “Daybreak – 1.1”.
+/// Titles without a chapter number, like interludes, have none and belong to the arc before them.
+fn arc_title(title: &str) -> Option<String> {
+    let (prefix, number) = title.trim().rsplit_once(' ')?;
+    let (part, _) = number.split_once('.')?;
+    if !(part.chars().all(|c| c.is_ascii_digit()) || part.chars().count() == 1) || prefix.contains("Interlude") {
+        return None;
+    }
+    let arc = prefix.trim_end_matches(|c: char| c.is_whitespace() || c == '–' || c == '-' || c == ':');
+    if arc.is_empty() {
+        None
+    } else {
+        Some(arc.to_string())
     }
-
-    doc.perform_operations(ops);
 }
 
 #[test]
-fn test_useless_span_removal() {
-    // Likely created by copying from Translator.
-    // https://pactwebserial.wordpress.com/2014/04/03/collateral-4-12/
-    let mut example = Html::parse_fragment(r#"<p>“<span id="result_box" class="short_text" lang="ga"><span class="hps">Comhroinn</span> </span><span id="result_box" class="short_text" lang="ga"><span class="hps">liom</span></span><em><span id="result_box" class="short_text" lang="ga">,</span></em>” the woman rasped.</p>"#);
-    remove_useless_spans(&mut example);
+fn test_arc_title() {
+    assert_eq!(arc_title("Gestation 1.1").as_deref(), Some("Gestation"));
+    assert_eq!(arc_title("Daybreak – 1.1").as_deref(), Some("Daybreak"));
+    assert_eq!(arc_title("Forest for the Trees – e.4").as_deref(), Some("Forest for the Trees"));
+    assert_eq!(arc_title("Last – 20.end").as_deref(), Some("Last"));
+    assert_eq!(arc_title("Interlude 1"), None);
+    assert_eq!(arc_title("Interlude 10.5 (Bonus)"), None);
+    assert_eq!(arc_title("Interlude 10.5"), None);
+    assert_eq!(arc_title("P.9"), None);
+}
 
-    assert_eq!(
-        Html::parse_fragment("<p>“Comhroinn liom<em>,</em>” the woman rasped.</p>"),
-        Html::parse_fragment(&example.root_element().inner_html()),
-    );
+/// Reader comments of a chapter included in the book.
+struct ChapterComments {
+    chapter_file: String,
+    title: String,
+    /// Address of the chapter, which relative links in the comments are resolved against.
+    url: Url,
+    comments: Vec<Comment>,
+}
+
+/// Name of the file with comments on the chapter stored in `chapter_file`.
+fn comments_file(chapter_file: &str) -> String {
+    chapter_file.replacen("chapter_", "comments_", 1)
+}
+
+/// Adds a page for each chapter’s comments, together with an overview page listing them.
+/// When the comments are included in the book itself, `chapter_map` is passed
+/// and they will link back to the chapters.
+fn add_comment_pages(
+    writer: &mut dyn BookWriter,
+    chapter_comments: &[ChapterComments],
+    chapter_map: Option<&ChapterMap>,
+    external_links: ExternalLinks,
+) -> Result<(), Error> {
+    let mut overview_text = String::from("<ul>\n");
+    for chapter in chapter_comments {
+        overview_text.push_str(&format!(
+            "<li><a href=\"{}\">{}</a> ({})</li>\n",
+            comments_file(&chapter.chapter_file),
+            html_escape::encode_text(&chapter.title),
+            count_comments(&chapter.comments),
+        ));
+    }
+    overview_text.push_str("</ul>\n");
+
+    writer.add_page(Page {
+        file: "comments.xhtml".to_string(),
+        title: "Comments".to_string(),
+        body_text: overview_text,
+        kind: PageKind::Notes,
+        level: 1,
+        arc: None,
+        url: None,
+        fetched_at: None,
+    })?;
+
+    for chapter in chapter_comments {
+        let mut body_text = String::new();
+        if chapter_map.is_some() {
+            body_text.push_str(&format!("<p><a href=\"{}\">Back to the chapter</a></p>\n", chapter.chapter_file));
+        }
+        let no_chapters = ChapterMap::default();
+        body_text.push_str(&chapter_map.unwrap_or(&no_chapters).rewrite_links(
+            &comments_xhtml(&chapter.comments),
+            &chapter.url,
+            external_links,
+        ));
+
+        writer.add_page(Page {
+            file: comments_file(&chapter.chapter_file),
+            title: format!("Comments: {}", chapter.title),
+            body_text,
+            kind: PageKind::Notes,
+            level: 2,
+            arc: None,
+            url: None,
+            fetched_at: None,
+        })?;
+    }
+
+    Ok(())
 }
 
 fn download_page(
     client: &CachedClient,
     images: &mut ImageManager,
+    missing_images: &mut Vec<MissingImage>,
+    pipeline: &Pipeline,
+    options: &BookOptions,
     page_url: &Url,
     skip_cache: bool,
-) -> Result<(String, String, Option<Url>), Error> {
+) -> Result<DownloadedPage, Error> {
     let res = client.fetch::<String>(page_url, skip_cache)?;
     let is_cached = res.is_cached();
+    let fetched_at = client.fetched_at(page_url).map(DateTime::from).unwrap_or_else(Utc::now);
     let page = res.contents();
+    // Links on the page are relative to where the server redirected us.
+    let url = client.redirected_url(page_url);
 
     let doc = Html::parse_document(page.as_ref());
 
@@ -633,8 +1050,8 @@ fn download_page(
     if let Some(redirect_url) = redirect {
         let mut redirect_chars = redirect_url.chars();
         redirect_chars.nth(3); // skip over 'url='
-        let page_url = page_url.join(redirect_chars.as_str()).context(format!("Could not resolve url '{}'", redirect_chars.as_str()))?;
-        return download_page(client, images, &page_url, skip_cache);
+        let page_url = url.join(redirect_chars.as_str()).context(format!("Could not resolve url '{}'", redirect_chars.as_str()))?;
+        return download_page(client, images, missing_images, pipeline, options, &page_url, skip_cache);
     }
 
     let next_page = doc.select(&NEXT_LINK_SELECTOR).next();
@@ -658,37 +1075,24 @@ fn download_page(
     } else {
         println!("Downloaded {title} from {page_url}");
     }
-    let content_elems = doc
-        .select(&CONTENT_ELEMENT_SELECTOR)
-        .filter(|elem| {
-            // Remove paragraphs which only have text inside links and there is no image.
-            // Those are probably “Next/Previous Chapter” links.
-            // If there is an image, it is probably due to the link’s paragraph being
-            // accidentally smooshed into the paragraph with protagonist image (used e.g. by Pale).
-            // We will tackle that during image handling.
-            elem.select(&IMAGE_SELECTOR).next().is_some()
-            || ! elem.text_filter(|elem| elem.name != html_elem_name("a")).collect::<String>().trim().is_empty()
-        });
-
-    let mut body_text = String::new();
-
-    for elem in content_elems {
-        let mut doc = Html::parse_fragment(&elem.inner_xml());
-        fix_cloudflare_links(&mut doc);
-        clean_up_images(
-            &mut doc,
-            client,
-            images,
-            page_url,
-        )?;
-        remove_useless_spans(&mut doc);
-        let elem_text = doc.root_element().inner_xml();
-
-        body_text.push_str(&("<p".to_string() + &style_classes(elem) + ">" + &elem_text + "</p>\n"));
-    }
+    // Process the whole chapter body as a single tree so that transforms can look across paragraphs
+    // and we do not have to parse each paragraph separately.
+    let entry_content = doc
+        .select(&ENTRY_CONTENT_SELECTOR)
+        .map(|elem| elem.inner_xml())
+        .collect::<String>();
+    let mut content = Html::parse_fragment(&entry_content);
+    let mut context = TransformContext {
+        client,
+        images,
+        missing_images,
+        page_url: &url,
+    };
+    pipeline.apply(&mut content, &mut context)?;
+    let (body_text, extracted_author_notes) = serialize_content(&content, options.transform_options.author_notes == AuthorNotes::Appendix);
 
     let next_page_url = if let Some(a_element) = next_page {
-        Some(page_url.join(a_element.value().attr("href").ok_or(err_msg("<a> link with name 'next' does not have href attribute"))?).context("Could not resolve url")?)
+        Some(url.join(a_element.value().attr("href").ok_or(err_msg("<a> link with name 'next' does not have href attribute"))?).context("Could not resolve url")?)
     } else {
         None
     };
@@ -698,26 +1102,56 @@ fn download_page(
     if next_page_url.is_none() && is_cached {
         // If this was a last chapter and it was cached, let’s try to refetch it
         // in case there is a new chapter link available.
-        return download_page(client, images, page_url, true);
+        return download_page(client, images, missing_images, pipeline, options, page_url, true);
     }
 
-    Ok((body_text, title, next_page_url))
+    let comments = if options.comments.is_some() { download_comments(client, &doc, &url) } else { Vec::new() };
+
+    Ok(DownloadedPage {
+        body_text,
+        title,
+        url,
+        fetched_at,
+        next_page: next_page_url,
+        author_notes: extracted_author_notes,
+        comments,
+    })
+}
+
+/// What is collected while downloading the chapters but only added to the book after all of them.
+/// An omnibus shares it between its books.
+#[derive(Default)]
+struct BookContents {
+    images: ImageManager,
+    chapter_map: ChapterMap,
+    /// Author’s notes collected for the appendix, with links pointing inside the book or made absolute.
+    appendix_text: String,
+    chapter_comments: Vec<ChapterComments>,
 }
 
+/// Downloads the chapters of the book and adds them to the writer at given level of the table of contents.
+#[allow(clippy::too_many_arguments)]
 fn download_pages(
     book: &Book,
     mut link: Option<Url>,
-    builder: &mut EpubBuilder<ZipLibrary>,
+    writer: &mut dyn BookWriter,
     client: CachedClient,
+    pipeline: &Pipeline,
+    options: &BookOptions,
+    missing_images: &mut Vec<MissingImage>,
+    contents: &mut BookContents,
+    level: usize,
 ) -> Result<(), Error> {
-
-    let mut chapter_number = 1;
-    let mut images: ImageManager = HashMap::new();
+    let mut chapters = Vec::new();
+    let mut arc = None;
 
     while let Some(page_url) = link {
-        let (body_text, title, next_page) = download_page(
+        let DownloadedPage { mut body_text, title, url, fetched_at, next_page, author_notes, comments } = download_page(
             &client,
-            &mut images,
+            &mut contents.images,
+            missing_images,
+            pipeline,
+            options,
             &page_url,
             false,
         )?;
@@ -730,39 +1164,174 @@ fn download_pages(
             continue;
         }
 
-        let escaped_title = html_escape::encode_text(&title);
-        let cont = "<?xml version='1.0' encoding='utf-8' ?><html xmlns='http://www.w3.org/1999/xhtml'><head><title>".to_string() + &escaped_title + "</title><meta http-equiv='Content-Type' content ='text/html; charset=utf-8' />\n<link rel='stylesheet' type='text/css' href='stylesheet.css' />\n</head><body><h1>" + &escaped_title + "</h1>\n" + &body_text + "</body></html>";
+        let chapter_file = contents.chapter_map.file_name(&url);
+        // Remember both the requested and redirected address so that links using either can be rewritten.
+        contents.chapter_map.insert(&page_url, &chapter_file);
+        contents.chapter_map.insert(&url, &chapter_file);
+
+        if !comments.is_empty() {
+            if options.comments == Some(CommentsMode::Pages) {
+                body_text.push_str(&format!(
+                    "<p class=\"right\"><a href=\"{}\">Comments ({})</a></p>\n",
+                    comments_file(&chapter_file),
+                    count_comments(&comments),
+                ));
+            }
+            contents.chapter_comments.push(ChapterComments {
+                chapter_file: chapter_file.clone(),
+                title: title.clone(),
+                url: url.clone(),
+                comments,
+            });
+        }
 
-        // Title here should not need to be escaped but unfortunately,
-        // without this the nav.xhtml will contain unescaped &.
-        // And we cannot just escape it ourseves or it will be double escaped in toc.ncx.
-        // Let’s replace it with small ampersand Unicode character.
-        // https://github.com/lise-henry/epub-builder/pull/41
-        let title_clean = title.replace("&", "﹠");
-        builder.add_content(EpubContent::new(format!("chapter_{}.xhtml", chapter_number), cont.as_bytes()).title(&title_clean).reftype(ReferenceType::Text))
-               .context("Could not add chapter")?;
+        let is_final_chapter = Some(title.as_str()) == book.final_chapter_title;
+
+        if let Some(chapter_arc) = arc_title(&title) {
+            arc = Some(chapter_arc);
+        }
+        chapters.push(Chapter {
+            file: chapter_file,
+            title,
+            arc: arc.clone(),
+            url,
+            fetched_at,
+            body_text,
+            author_notes,
+        });
 
-        if Some(title) == book.final_chapter_title.map(|title| title.to_string()) {
+        if is_final_chapter {
             // Stop after the final chapter to avoid including e.g. retrospectives.
             break;
         }
+    }
+
+    if options.transform_options.failed_images == FailedImages::Retry {
+        retry_missing_images(&client, &mut contents.images, &options.transform_options.images, missing_images, &mut chapters);
+    }
+
+    // Chapters are only added once we know all their addresses,
+    // so that links to later chapters can point inside the book as well.
+    for chapter in chapters {
+        if !chapter.author_notes.is_empty() {
+            contents.appendix_text.push_str(&format!(
+                "<section class=\"author-note\">\n<h2><a href=\"{}\">{}</a></h2>\n",
+                chapter.file,
+                html_escape::encode_text(&chapter.title),
+            ));
+            // The external links policy is applied to the whole appendix, so that endnotes are numbered across it.
+            contents.appendix_text.push_str(&contents.chapter_map.rewrite_links(&chapter.author_notes.concat(), &chapter.url, ExternalLinks::Keep));
+            contents.appendix_text.push_str("</section>\n");
+        }
+
+        writer.add_page(Page {
+            body_text: contents.chapter_map.rewrite_links(&chapter.body_text, &chapter.url, options.external_links),
+            file: chapter.file,
+            title: chapter.title,
+            kind: PageKind::Chapter,
+            level,
+            arc: chapter.arc,
+            url: Some(chapter.url),
+            fetched_at: Some(chapter.fetched_at),
+        })?;
+    }
 
-        chapter_number += 1
+    Ok(())
+}
+
+/// Adds the pages collected for the end of the book and the images, returning the comments for the companion book.
+fn add_back_matter(writer: &mut dyn BookWriter, contents: BookContents, options: &BookOptions) -> Result<Vec<ChapterComments>, Error> {
+    let BookContents { images, chapter_map, appendix_text, chapter_comments } = contents;
+
+    if !appendix_text.is_empty() {
+        writer.add_page(Page {
+            file: "author_notes.xhtml".to_string(),
+            title: "Author’s Notes".to_string(),
+            body_text: chapter_map.rewrite_absolute_links(&appendix_text, options.external_links),
+            kind: PageKind::Notes,
+            level: 1,
+            arc: None,
+            url: None,
+            fetched_at: None,
+        })?;
+    }
+
+    if options.comments == Some(CommentsMode::Pages) && !chapter_comments.is_empty() {
+        add_comment_pages(writer, &chapter_comments, Some(&chapter_map), options.external_links)?;
     }
 
     for (image, (name, content_type)) in images {
-        builder.add_resource(&name, &*image, content_type).context(format!("Could not add image {name}"))?;
+        writer.add_resource(&name, &image, content_type)?;
+    }
+
+    Ok(chapter_comments)
+}
+
+/// Tries to download the images replaced by placeholders again,
+/// putting the ones that succeed back into the chapters.
+fn retry_missing_images(
+    client: &CachedClient,
+    images: &mut ImageManager,
+    image_options: &ImageOptions,
+    missing_images: &mut Vec<MissingImage>,
+    chapters: &mut [Chapter],
+) {
+    let mut restored = HashMap::new();
+
+    missing_images.retain_mut(|image| {
+        let Some(ref placeholder_id) = image.placeholder_id else {
+            return true;
+        };
+        println!("Retrying image {}", image.url);
+        match embed_image(client, images, image_options, &image.url) {
+            Ok(path) => {
+                restored.insert(placeholder_id.clone(), (path, image.alt.clone()));
+                false
+            }
+            Err(ImageError::Download(error)) | Err(ImageError::Unsupported(error)) => {
+                println!("Warning: Image {} still cannot be embedded: {error}", image.url);
+                image.reason = error.to_string();
+                true
+            }
+        }
+    });
+
+    if !restored.is_empty() {
+        for chapter in chapters {
+            chapter.body_text = restore_placeholders(&chapter.body_text, &restored);
+        }
     }
+}
 
+/// Packs an unpacked EPUB directory into an EPUB named after it.
+fn pack_directory(directory: &Path, output_folder: Option<PathBuf>) -> Result<(), Error> {
+    let name = directory.file_name().ok_or_else(|| err_msg(format!("Cannot name EPUB after {}", directory.display())))?;
+    let epub_name = format!("{}.epub", name.to_string_lossy());
+    let epub = epub_directory::pack_epub(directory)?;
+    println!("Packing {} into {epub_name}", directory.display());
+    std::fs::write(output_folder.unwrap_or_default().join(&epub_name), epub).context(format!("Could not write {epub_name}"))?;
     Ok(())
 }
 
-fn process_book(mut book: DownloadedBook, output_folder: Option<PathBuf>) -> Result<(), Error> {
+fn process_book(book: DownloadedBook, output_folder: Option<PathBuf>) -> Result<(), Error> {
     println!("Done downloading {}", book.title);
     let filename = book.title.to_lowercase();
-    println!("Converting to epub now at {}.epub", filename);
-    let mut zipfile = File::create(output_folder.unwrap_or_default().join(filename+ ".epub")).context("Could not open file")?;
-    book.builder.generate(&mut zipfile).context("Could not generate ebook")?;
+    let output_folder = output_folder.unwrap_or_default();
+    // Formats written as a directory have no extension.
+    let output_name = |name: &str, extension: Option<&str>| match extension {
+        Some(extension) => format!("{name}.{extension}"),
+        None => name.to_string(),
+    };
+    let extension = book.writer.extension();
+    let format = extension.unwrap_or("a directory");
+    let book_name = output_name(&filename, extension);
+    println!("Converting to {format} now at {book_name}");
+    book.writer.finish(&output_folder.join(book_name))?;
+    if let Some(comments_writer) = book.comments_writer {
+        let comments_name = output_name(&format!("{filename}-comments"), extension);
+        println!("Converting comments to {format} now at {comments_name}");
+        comments_writer.finish(&output_folder.join(comments_name))?;
+    }
     println!("Done downloading {}", book.title);
     Ok(())
 }
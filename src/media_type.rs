@@ -0,0 +1,178 @@
+use easy_error::{err_msg, Error};
+use reqwest::Url;
+
+/// Image formats that can be embedded in the book.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaType {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+    Svg,
+}
+
+/// Signatures of image formats we recognize but cannot embed.
+const UNSUPPORTED_SIGNATURES: &[(&[u8], &str)] = &[
+    (b"BM", "BMP"),
+    (b"II*\0", "TIFF"),
+    (b"MM\0*", "TIFF"),
+    (b"\0\0\x01\0", "ICO"),
+];
+
+impl MediaType {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Gif => "image/gif",
+            Self::Webp => "image/webp",
+            Self::Svg => "image/svg+xml",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::Gif => "gif",
+            Self::Webp => "webp",
+            Self::Svg => "svg",
+        }
+    }
+
+    fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_lowercase().as_str() {
+            "jpg" | "jpeg" => Some(Self::Jpeg),
+            "png" => Some(Self::Png),
+            "gif" => Some(Self::Gif),
+            "webp" => Some(Self::Webp),
+            "svg" => Some(Self::Svg),
+            _ => None,
+        }
+    }
+
+    /// Recognizes the format by the signature at the start of the file.
+    pub fn from_magic_bytes(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"\xFF\xD8\xFF") {
+            Some(Self::Jpeg)
+        } else if data.starts_with(b"\x89PNG\r\n\x1A\n") {
+            Some(Self::Png)
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some(Self::Gif)
+        } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+            Some(Self::Webp)
+        } else if has_svg_root(&String::from_utf8_lossy(&data[..data.len().min(4096)])) {
+            Some(Self::Svg)
+        } else {
+            None
+        }
+    }
+
+    /// Interprets the value of `Content-Type` HTTP header.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+        match mime.as_str() {
+            "image/jpeg" | "image/jpg" | "image/pjpeg" => Some(Self::Jpeg),
+            "image/png" => Some(Self::Png),
+            "image/gif" => Some(Self::Gif),
+            "image/webp" => Some(Self::Webp),
+            "image/svg+xml" => Some(Self::Svg),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format from the URL suffix or, for image proxies, the `format` query parameter.
+    pub fn from_url(url: &Url) -> Option<Self> {
+        let file_name = url.path().rsplit('/').next().unwrap_or("");
+        file_name
+            .rsplit_once('.')
+            .and_then(|(_, extension)| Self::from_extension(extension))
+            .or_else(|| {
+                url.query_pairs()
+                    .find(|(key, _)| key == "format")
+                    .and_then(|(_, format)| Self::from_extension(&format))
+            })
+    }
+
+    /// Determines the format of downloaded image, trusting its contents first,
+    /// then the `Content-Type` the server sent, and the URL only as a last resort.
+    pub fn detect(data: &[u8], content_type: Option<&str>, url: &Url) -> Result<Self, Error> {
+        if let Some(media_type) = Self::from_magic_bytes(data) {
+            return Ok(media_type);
+        }
+
+        if let Some((_, format)) = UNSUPPORTED_SIGNATURES.iter().find(|(signature, _)| data.starts_with(signature)) {
+            return Err(err_msg(format!("{format} images are not supported: {url}")));
+        }
+
+        content_type
+            .and_then(Self::from_content_type)
+            .or_else(|| Self::from_url(url))
+            .ok_or_else(|| match content_type {
+                Some(content_type) => err_msg(format!("Unsupported media type {content_type}: {url}")),
+                None => err_msg(format!("Could not determine the media type of {url}")),
+            })
+    }
+}
+
+/// SVG is text, so let’s check that the root element is `svg`, skipping the XML declaration,
+/// doctype, comments and processing instructions before it.
+/// Merely containing `<svg` is not enough, HTML pages can have inline SVG.
+fn has_svg_root(text: &str) -> bool {
+    let mut rest = text.trim_start_matches('\u{FEFF}');
+    loop {
+        rest = rest.trim_start();
+        let end = if rest.starts_with("<?") {
+            rest.find("?>").map(|end| end + 2)
+        } else if rest.starts_with("<!--") {
+            rest.find("-->").map(|end| end + 3)
+        } else if rest.starts_with("<!DOCTYPE") || rest.starts_with("<!doctype") {
+            // The internal subset can contain `>`.
+            match (rest.find('['), rest.find('>')) {
+                (Some(subset), Some(end)) if subset < end => rest.find("]>").map(|end| end + 2),
+                (_, end) => end.map(|end| end + 1),
+            }
+        } else {
+            let name = rest.strip_prefix("<svg").unwrap_or("");
+            return name.starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/');
+        };
+        match end {
+            Some(end) => rest = &rest[end..],
+            None => return false,
+        }
+    }
+}
+
+#[test]
+fn test_detect_media_type() {
+    let url = Url::parse("https://example.com/image").unwrap();
+
+    assert_eq!(MediaType::detect(b"\xFF\xD8\xFF\xE0\0\x10JFIF", None, &url).unwrap(), MediaType::Jpeg);
+    assert_eq!(MediaType::detect(b"GIF89a\x01\0\x01\0", Some("image/png"), &url).unwrap(), MediaType::Gif);
+    assert_eq!(MediaType::detect(b"RIFF\x24\0\0\0WEBPVP8 ", None, &url).unwrap(), MediaType::Webp);
+    assert_eq!(
+        MediaType::detect(b"<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\"/>", None, &url).unwrap(),
+        MediaType::Svg,
+    );
+    assert_eq!(
+        MediaType::from_magic_bytes(concat!(
+            "\u{FEFF}<?xml version=\"1.0\" standalone=\"no\"?>\n<!-- Created with Inkscape -->\n",
+            "<!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\" \"http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd\" [\n<!ENTITY ns \"http://www.w3.org/2000/svg\">\n]>\n",
+            "<svg\n  xmlns=\"http://www.w3.org/2000/svg\">",
+        ).as_bytes()),
+        Some(MediaType::Svg),
+    );
+    // Error pages with inline SVG, e.g. icons, are not images.
+    assert_eq!(MediaType::from_magic_bytes(b"<!DOCTYPE html>\n<html><body><svg viewBox=\"0 0 1 1\"></svg></body></html>"), None);
+    assert_eq!(MediaType::from_magic_bytes(b"<html><svg></svg></html>"), None);
+    assert_eq!(MediaType::from_magic_bytes(b"<svgx/>"), None);
+    assert_eq!(MediaType::detect(b"????", Some("image/png; charset=binary"), &url).unwrap(), MediaType::Png);
+    assert!(MediaType::detect(b"BM\x36\0\0\0", Some("image/bmp"), &url).is_err());
+    assert!(MediaType::detect(b"????", None, &url).is_err());
+
+    let reddit_preview = Url::parse("https://preview.redd.it/9scpenoq5v671.png?width=1410&format=png&auto=webp").unwrap();
+    assert_eq!(MediaType::from_url(&reddit_preview), Some(MediaType::Png));
+    let proxy = Url::parse("https://example.com/image?format=webp").unwrap();
+    assert_eq!(MediaType::from_url(&proxy), Some(MediaType::Webp));
+    assert_eq!(MediaType::from_url(&Url::parse("https://example.com/v1.2/image").unwrap()), None);
+}
@@ -0,0 +1,334 @@
+use chrono::DateTime;
+use easy_error::{err_msg, Error, ResultExt};
+use std::io::{Cursor, Read, Write};
+use uuid::Uuid;
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+/// Path of the package document inside EPUBs generated by `epub_builder`.
+const OPF_PATH: &str = "OEBPS/content.opf";
+
+/// Path of the EPUB 2 table of contents inside EPUBs generated by `epub_builder`.
+const NCX_PATH: &str = "OEBPS/toc.ncx";
+
+/// Metadata of a book, written by each of the output formats.
+/// For EPUB, the fields from `identifier` on are written by `apply_to_epub`, since `epub_builder` cannot write them itself:
+/// it always uses a random identifier and the build time as the date.
+#[derive(Clone, Debug)]
+pub struct BookMetadata {
+    pub title: String,
+    /// Name the author is credited under, as on the cover.
+    pub author: String,
+    /// How the author is filed, as `Last, First`, when it is not their credited name, e.g. for a pen name.
+    pub author_file_as: Option<String>,
+    pub description: String,
+    /// Language tag, like `en-US`.
+    pub language: String,
+    /// Unique identifier, staying the same across rebuilds so readers recognise updates as the same book.
+    pub identifier: String,
+    /// Publication date in `YYYY-MM-DD` format.
+    pub date: String,
+    /// Name of the series the book belongs to and its position in it.
+    pub series: Option<(String, String)>,
+    pub subjects: Vec<String>,
+    pub publisher: Option<String>,
+    /// Address the book was scraped from.
+    pub source: Option<String>,
+}
+
+/// Namespace of the name-based identifiers of the books.
+const IDENTIFIER_NAMESPACE: Uuid = Uuid::from_bytes([
+    0x7f, 0x2b, 0x5e, 0x91, 0x3c, 0x0d, 0x4a, 0x6e, 0x9b, 0x18, 0xd4, 0x52, 0xa7, 0xe0, 0x3f, 0xc6,
+]);
+
+/// Identifier of the book with given key.
+/// It is derived from the key alone, so every build of the book gets the same one
+/// and readers keep the reading position and annotations when the book is updated.
+pub fn book_identifier(key: &str) -> String {
+    format!("urn:uuid:{}", Uuid::new_v5(&IDENTIFIER_NAMESPACE, key.as_bytes()))
+}
+
+/// Converts an RFC 2822 date like `Tue, 19 Nov 2013 00:00:00 +0100` to the `YYYY-MM-DD` format used by EPUB.
+pub fn epub_date(date: &str) -> Result<String, Error> {
+    let date = DateTime::parse_from_rfc2822(date).context(format!("Invalid date {date}"))?;
+    Ok(date.format("%Y-%m-%d").to_string())
+}
+
+/// Replaces the contents of the first `open`…`close` element in `text`.
+fn replace_element(text: &str, open: &str, close: &str, contents: &str) -> Result<String, Error> {
+    match text.find(open).and_then(|start| text[start..].find(close).map(|end| (start + open.len(), start + end))) {
+        Some((start, end)) => Ok(format!("{}{}{}", &text[..start], contents, &text[end..])),
+        None => Err(err_msg(format!("Package document has no {open}{close} element"))),
+    }
+}
+
+impl BookMetadata {
+    /// Combines the metadata of the books put together in an omnibus, in reading order.
+    /// Books of a single series are named after it, others after all of the books.
+    pub fn omnibus(books: &[BookMetadata]) -> BookMetadata {
+        let first = &books[0];
+        let series = first.series.as_ref().map(|(series, _)| series).filter(|series| {
+            books.iter().all(|book| book.series.as_ref().map(|(name, _)| name) == Some(*series))
+        });
+        let title = match series {
+            Some(series) => series.clone(),
+            None => books.iter().map(|book| book.title.as_str()).collect::<Vec<_>>().join(" & "),
+        };
+        let mut subjects: Vec<String> = Vec::new();
+        for subject in books.iter().flat_map(|book| &book.subjects) {
+            if !subjects.contains(subject) {
+                subjects.push(subject.clone());
+            }
+        }
+        let identifiers: Vec<&str> = books.iter().map(|book| book.identifier.as_str()).collect();
+        BookMetadata {
+            title,
+            author: first.author.clone(),
+            author_file_as: first.author_file_as.clone(),
+            description: books.iter().map(|book| format!("{}: {}", book.title, book.description)).collect::<Vec<_>>().join("\n\n"),
+            language: first.language.clone(),
+            identifier: book_identifier(&format!("omnibus/{}", identifiers.join(","))),
+            // Published once its last book was.
+            date: books.iter().map(|book| book.date.clone()).max().unwrap(),
+            series: None,
+            subjects,
+            publisher: first.publisher.clone(),
+            source: first.source.clone().filter(|source| books.iter().all(|book| book.source.as_ref() == Some(source))),
+        }
+    }
+
+    /// Elements added at the end of the OPF metadata section.
+    /// Series are written both in the form Calibre understands and as an EPUB 3 collection.
+    fn extra_elements(&self, epub3: bool) -> String {
+        let mut elements = String::new();
+        if let Some(ref file_as) = self.author_file_as {
+            if epub3 {
+                elements.push_str(&format!("    <meta refines=\"#epub-creator-1\" property=\"file-as\">{}</meta>\n", html_escape::encode_text(file_as)));
+            }
+        }
+        if let Some(ref publisher) = self.publisher {
+            elements.push_str(&format!("    <dc:publisher>{}</dc:publisher>\n", html_escape::encode_text(publisher)));
+        }
+        if let Some(ref source) = self.source {
+            elements.push_str(&format!("    <dc:source>{}</dc:source>\n", html_escape::encode_text(source)));
+        }
+        for subject in &self.subjects {
+            elements.push_str(&format!("    <dc:subject>{}</dc:subject>\n", html_escape::encode_text(subject)));
+        }
+        if let Some((ref series, ref index)) = self.series {
+            let series = html_escape::encode_double_quoted_attribute(series);
+            let index = html_escape::encode_double_quoted_attribute(index);
+            elements.push_str(&format!("    <meta name=\"calibre:series\" content=\"{series}\"/>\n"));
+            elements.push_str(&format!("    <meta name=\"calibre:series_index\" content=\"{index}\"/>\n"));
+            if epub3 {
+                elements.push_str(&format!("    <meta property=\"belongs-to-collection\" id=\"series\">{series}</meta>\n"));
+                elements.push_str("    <meta refines=\"#series\" property=\"collection-type\">series</meta>\n");
+                elements.push_str(&format!("    <meta refines=\"#series\" property=\"group-position\">{index}</meta>\n"));
+            }
+        }
+        elements
+    }
+
+    /// Writes the metadata into the package document generated by `epub_builder`.
+    /// Fails when the document does not look like expected, e.g. after `epub_builder` changed its template.
+    pub fn apply_to_opf(&self, opf: &str) -> Result<String, Error> {
+        let epub3 = opf.contains("<package version=\"3.0\"");
+        let opf = replace_element(opf, "<dc:identifier id=\"epub-id-1\">", "</dc:identifier>", &html_escape::encode_text(&self.identifier))?;
+        let opf = replace_element(&opf, "<dc:date>", "</dc:date>", &self.date)?;
+        let opf = match self.author_file_as {
+            Some(ref file_as) if !epub3 => {
+                let creator = "<dc:creator opf:role=\"aut\">";
+                if !opf.contains(creator) {
+                    return Err(err_msg("Package document has no author"));
+                }
+                opf.replacen(creator, &format!("<dc:creator opf:role=\"aut\" opf:file-as=\"{}\">", html_escape::encode_double_quoted_attribute(file_as)), 1)
+            }
+            Some(_) if !opf.contains("<dc:creator id=\"epub-creator-1\">") => return Err(err_msg("Package document has no author")),
+            _ => opf,
+        };
+        if !opf.contains("  </metadata>") {
+            return Err(err_msg("Package document has no metadata section"));
+        }
+        Ok(opf.replacen("  </metadata>", &format!("{}  </metadata>", self.extra_elements(epub3)), 1))
+    }
+
+    /// Writes the identifier into the table of contents generated by `epub_builder`,
+    /// which has to match the one of the package document but is left out by its template.
+    pub fn apply_to_ncx(&self, ncx: &str) -> Result<String, Error> {
+        if ncx.contains("name=\"dtb:uid\"") {
+            return Err(err_msg("Table of contents already has an identifier"));
+        }
+        if !ncx.contains("  <head>\n") {
+            return Err(err_msg("Table of contents has no head section"));
+        }
+        let uid = format!("  <head>\n    <meta name=\"dtb:uid\" content=\"{}\" />\n", html_escape::encode_double_quoted_attribute(&self.identifier));
+        Ok(ncx.replacen("  <head>\n", &uid, 1))
+    }
+
+    /// Rewrites the package document and table of contents of a generated EPUB, copying all the other files as they are.
+    pub fn apply_to_epub(&self, epub: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut archive = ZipArchive::new(Cursor::new(epub)).context("Could not read generated EPUB")?;
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i).context("Could not read generated EPUB")?;
+            if file.name() == OPF_PATH {
+                let mut opf = String::new();
+                file.read_to_string(&mut opf).context("Could not read package document")?;
+                writer
+                    .start_file(OPF_PATH, FileOptions::default().compression_method(file.compression()))
+                    .context("Could not write package document")?;
+                writer.write_all(self.apply_to_opf(&opf)?.as_bytes()).context("Could not write package document")?;
+            } else if file.name() == NCX_PATH {
+                let mut ncx = String::new();
+                file.read_to_string(&mut ncx).context("Could not read table of contents")?;
+                writer
+                    .start_file(NCX_PATH, FileOptions::default().compression_method(file.compression()))
+                    .context("Could not write table of contents")?;
+                writer.write_all(self.apply_to_ncx(&ncx)?.as_bytes()).context("Could not write table of contents")?;
+            } else {
+                // Keeps the order and compression, the `mimetype` file has to come first uncompressed.
+                writer.raw_copy_file(file).context("Could not copy EPUB contents")?;
+            }
+        }
+        let epub = writer.finish().context("Could not write EPUB")?;
+        Ok(epub.into_inner())
+    }
+}
+
+#[test]
+fn test_book_identifier() {
+    // Identifiers must not change, or readers would treat rebuilt books as different ones.
+    assert_eq!(book_identifier("worm"), "urn:uuid:122e30e5-cc0a-5a2d-b508-895a109400c9");
+    assert_eq!(book_identifier("worm"), book_identifier("worm"));
+    assert_ne!(book_identifier("worm"), book_identifier("ward"));
+}
+
+#[test]
+fn test_omnibus() {
+    let book = |title: &str, date: &str, series: Option<&str>, subjects: &[&str], source: &str| BookMetadata {
+        title: title.to_string(),
+        author: "Wildbow".to_string(),
+        author_file_as: None,
+        description: format!("About {title}."),
+        language: "en-US".to_string(),
+        identifier: book_identifier(&title.to_lowercase()),
+        date: date.to_string(),
+        series: series.map(|series| (series.to_string(), "1".to_string())),
+        subjects: subjects.iter().map(|subject| subject.to_string()).collect(),
+        publisher: Some("Wildbow".to_string()),
+        source: Some(source.to_string()),
+    };
+    let worm = book("Worm", "2013-11-19", Some("Parahumans"), &["Superheroes", "Web serial"], "https://parahumans.wordpress.com/");
+    let ward = book("Ward", "2020-05-02", Some("Parahumans"), &["Superheroes", "Science fiction"], "https://www.parahumans.net/");
+    let pact = book("Pact", "2015-03-07", None, &["Urban fantasy"], "https://pactwebserial.wordpress.com/");
+
+    let omnibus = BookMetadata::omnibus(&[worm.clone(), ward.clone()]);
+    assert_eq!(omnibus.title, "Parahumans");
+    assert_eq!(omnibus.description, "Worm: About Worm.\n\nWard: About Ward.");
+    assert_eq!(omnibus.date, "2020-05-02");
+    assert_eq!(omnibus.series, None);
+    assert_eq!(omnibus.subjects, ["Superheroes", "Web serial", "Science fiction"]);
+    assert_eq!(omnibus.source, None);
+    assert_eq!(omnibus.identifier, BookMetadata::omnibus(&[worm.clone(), ward.clone()]).identifier);
+    assert_ne!(omnibus.identifier, BookMetadata::omnibus(&[ward, worm.clone()]).identifier);
+
+    let omnibus = BookMetadata::omnibus(&[worm.clone(), pact]);
+    assert_eq!(omnibus.title, "Worm & Pact");
+    assert_eq!(omnibus.date, "2015-03-07");
+
+    let omnibus = BookMetadata::omnibus(&[worm]);
+    assert_eq!(omnibus.source.as_deref(), Some("https://parahumans.wordpress.com/"));
+}
+
+#[test]
+fn test_apply_to_opf() {
+    let metadata = BookMetadata {
+        title: "Worm".to_string(),
+        author: "Wildbow".to_string(),
+        author_file_as: Some("McCrae, John".to_string()),
+        description: String::new(),
+        language: "en-US".to_string(),
+        identifier: "urn:uuid:cb7e3b0a-4d28-4c58-a2a6-9b1f0e8f9d4c".to_string(),
+        date: epub_date("Tue, 19 Nov 2013 00:00:00 +0100").unwrap(),
+        series: Some(("Parahumans".to_string(), "1".to_string())),
+        subjects: vec!["Superheroes".to_string()],
+        publisher: None,
+        source: Some("https://parahumans.wordpress.com/?a=1&b=2".to_string()),
+    };
+    let opf = concat!(
+        "<package version=\"3.0\" xmlns=\"http://www.idpf.org/2007/opf\" unique-identifier=\"epub-id-1\">\n",
+        "  <metadata>\n",
+        "    <dc:identifier id=\"epub-id-1\">urn:uuid:0a5b1c6e-0000-4000-8000-000000000000</dc:identifier>\n",
+        "    <dc:date>2024-01-01T12:00:00Z</dc:date>\n",
+        "    <dc:creator id=\"epub-creator-1\">Wildbow</dc:creator>\n",
+        "    <meta property=\"dcterms:modified\">2024-01-01T12:00:00Z</meta>\n",
+        "  </metadata>\n",
+        "</package>\n",
+    );
+
+    assert_eq!(
+        metadata.apply_to_opf(opf).unwrap(),
+        concat!(
+            "<package version=\"3.0\" xmlns=\"http://www.idpf.org/2007/opf\" unique-identifier=\"epub-id-1\">\n",
+            "  <metadata>\n",
+            "    <dc:identifier id=\"epub-id-1\">urn:uuid:cb7e3b0a-4d28-4c58-a2a6-9b1f0e8f9d4c</dc:identifier>\n",
+            "    <dc:date>2013-11-19</dc:date>\n",
+            "    <dc:creator id=\"epub-creator-1\">Wildbow</dc:creator>\n",
+            "    <meta property=\"dcterms:modified\">2024-01-01T12:00:00Z</meta>\n",
+            "    <meta refines=\"#epub-creator-1\" property=\"file-as\">McCrae, John</meta>\n",
+            "    <dc:source>https://parahumans.wordpress.com/?a=1&amp;b=2</dc:source>\n",
+            "    <dc:subject>Superheroes</dc:subject>\n",
+            "    <meta name=\"calibre:series\" content=\"Parahumans\"/>\n",
+            "    <meta name=\"calibre:series_index\" content=\"1\"/>\n",
+            "    <meta property=\"belongs-to-collection\" id=\"series\">Parahumans</meta>\n",
+            "    <meta refines=\"#series\" property=\"collection-type\">series</meta>\n",
+            "    <meta refines=\"#series\" property=\"group-position\">1</meta>\n",
+            "  </metadata>\n",
+            "</package>\n",
+        ),
+    );
+
+    let unexpected = opf.replace("dc:identifier id=\"epub-id-1\"", "dc:identifier id=\"pub-id\"");
+    assert!(metadata.apply_to_opf(&unexpected).is_err());
+
+    let epub2 = opf.replace("version=\"3.0\"", "version=\"2.0\"").replace("<dc:creator id=\"epub-creator-1\">", "<dc:creator opf:role=\"aut\">");
+    assert!(metadata.apply_to_opf(&epub2).unwrap().contains("<dc:creator opf:role=\"aut\" opf:file-as=\"McCrae, John\">Wildbow</dc:creator>\n"));
+}
+
+#[test]
+fn test_apply_to_epub() {
+    use epub_builder::{EpubBuilder, EpubContent, ZipLibrary};
+
+    let mut builder = EpubBuilder::new(ZipLibrary::new().unwrap()).unwrap();
+    builder.metadata("title", "Worm").unwrap();
+    builder.add_content(EpubContent::new("chapter_1.xhtml", "<html/>".as_bytes())).unwrap();
+    let mut epub = Vec::new();
+    builder.generate(&mut epub).unwrap();
+
+    let metadata = BookMetadata {
+        title: "Worm".to_string(),
+        author: "Wildbow".to_string(),
+        author_file_as: None,
+        description: String::new(),
+        language: "en-US".to_string(),
+        identifier: "urn:uuid:cb7e3b0a-4d28-4c58-a2a6-9b1f0e8f9d4c".to_string(),
+        date: "2013-11-19".to_string(),
+        series: None,
+        subjects: vec![],
+        publisher: Some("Wildbow".to_string()),
+        source: None,
+    };
+    let epub = metadata.apply_to_epub(&epub).unwrap();
+
+    let mut archive = ZipArchive::new(Cursor::new(epub)).unwrap();
+    let names: Vec<String> = archive.file_names().map(String::from).collect();
+    assert!(names.iter().any(|name| name == "OEBPS/chapter_1.xhtml"));
+    let mimetype = archive.by_index(0).unwrap();
+    assert_eq!((mimetype.name(), mimetype.compression()), ("mimetype", zip::CompressionMethod::Stored));
+    drop(mimetype);
+    let mut opf = String::new();
+    archive.by_name(OPF_PATH).unwrap().read_to_string(&mut opf).unwrap();
+    assert!(opf.contains(">urn:uuid:cb7e3b0a-4d28-4c58-a2a6-9b1f0e8f9d4c</dc:identifier>"));
+    assert!(opf.contains("<dc:date>2013-11-19</dc:date>"));
+    assert!(opf.contains("<dc:publisher>Wildbow</dc:publisher>"));
+}
@@ -0,0 +1,702 @@
+use boilerplate::{AuthorNotes, Boilerplate};
+use cached_client::{CachedClient, TypedBytes};
+use dom_manipulation::{DomOperation, MutableDom};
+use easy_error::{err_msg, Error, ResultExt};
+use ego_tree::NodeId;
+use html5ever::tree_builder::NodeOrText;
+use image_processing::ImageOptions;
+use markup5ever::Attribute;
+use media_type::MediaType;
+use reqwest::Url;
+use scraper::{ElementRef, Html, Selector};
+use std::collections::HashMap;
+use std::str::FromStr;
+use xml_utils::{html_attr_name, html_elem_name, XmlSerializable};
+
+/// Images embedded in the book, by contents, with the path they are stored at and their content type.
+pub type ImageManager = HashMap<Vec<u8>, (String, &'static str)>;
+
+/// Transforms applied to chapter content when the book does not specify its own pipeline.
+pub const DEFAULT_TRANSFORMS: &[&str] = &["cloudflare-emails", "boilerplate", "figures", "images", "useless-spans"];
+
+/// What to do when an image cannot be downloaded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FailedImages {
+    /// Stop scraping the book.
+    #[default]
+    Abort,
+    /// Put a placeholder in place of the image and carry on.
+    Skip,
+    /// Put a placeholder in place of the image and try downloading it again
+    /// once the rest of the book has been downloaded.
+    Retry,
+}
+
+impl FromStr for FailedImages {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(Self::Abort),
+            "skip" => Ok(Self::Skip),
+            "retry" => Ok(Self::Retry),
+            _ => Err(err_msg(format!("Unknown failed images policy {s}, expected one of: abort, skip, retry"))),
+        }
+    }
+}
+
+/// Image that could not be embedded in the book.
+#[derive(Debug)]
+pub struct MissingImage {
+    pub url: Url,
+    /// Page the image appears on, `None` for the cover.
+    pub page_url: Option<Url>,
+    pub reason: String,
+    /// Id of the element standing in for the image, when it was replaced by a placeholder.
+    pub placeholder_id: Option<String>,
+    /// Alternative text of the image.
+    pub alt: String,
+}
+
+/// User preferences affecting how transforms are constructed.
+#[derive(Clone, Debug, Default)]
+pub struct TransformOptions {
+    /// What the `boilerplate` transform does with author’s notes.
+    pub author_notes: AuthorNotes,
+    /// How the `images` transform adjusts images before embedding them.
+    pub images: ImageOptions,
+    /// What the `images` transform does with images it cannot download.
+    pub failed_images: FailedImages,
+}
+
+/// Resources shared by transforms while processing a single page.
+pub struct TransformContext<'a> {
+    pub client: &'a CachedClient,
+    pub images: &'a mut ImageManager,
+    /// Images that could not be embedded so far, shared by all pages of the book.
+    pub missing_images: &'a mut Vec<MissingImage>,
+    pub page_url: &'a Url,
+}
+
+/// A single step of the content cleaning pipeline.
+///
+/// Transforms only inspect the document and schedule `DomOperation`s,
+/// which the `Pipeline` then performs before running the next transform.
+pub trait Transform {
+    /// Name under which the transform is registered in `TransformRegistry`.
+    fn name(&self) -> &'static str;
+
+    /// Computes the changes that should be made to the document.
+    fn operations(&self, doc: &Html, context: &mut TransformContext) -> Result<Vec<DomOperation<NodeId>>, Error>;
+}
+
+/// Transform that handles each element matching a selector independently.
+pub trait SelectorTransform {
+    fn name(&self) -> &'static str;
+
+    /// Elements for which `element_operations` will be called.
+    fn selector(&self) -> &Selector;
+
+    /// Computes the changes for a single matching element.
+    fn element_operations(&self, elem: ElementRef, context: &mut TransformContext) -> Result<Vec<DomOperation<NodeId>>, Error>;
+}
+
+impl<T: SelectorTransform> Transform for T {
+    fn name(&self) -> &'static str {
+        SelectorTransform::name(self)
+    }
+
+    fn operations(&self, doc: &Html, context: &mut TransformContext) -> Result<Vec<DomOperation<NodeId>>, Error> {
+        let mut ops = Vec::new();
+        for elem in doc.select(self.selector()) {
+            ops.append(&mut self.element_operations(elem, context)?);
+        }
+        Ok(ops)
+    }
+}
+
+/// Creates a transform configured according to the options.
+pub type TransformConstructor = fn(&TransformOptions) -> Box<dyn Transform>;
+
+/// Collection of transforms that can be referred to by name when building a `Pipeline`.
+pub struct TransformRegistry {
+    constructors: HashMap<&'static str, TransformConstructor>,
+}
+
+impl TransformRegistry {
+    /// Creates a registry containing only the built-in transforms.
+    pub fn new() -> Self {
+        let mut registry = Self {
+            constructors: HashMap::new(),
+        };
+        registry.register("cloudflare-emails", |_| Box::new(CloudflareEmails));
+        registry.register("boilerplate", |options| Box::new(Boilerplate::new(options.author_notes)));
+        registry.register("figures", |_| Box::new(Figures));
+        registry.register("images", |options| Box::new(Images::new(options.images.clone(), options.failed_images)));
+        registry.register("useless-spans", |_| Box::new(UselessSpans));
+        registry
+    }
+
+    /// Makes a transform available under given name, replacing any previous one.
+    pub fn register(&mut self, name: &'static str, constructor: TransformConstructor) {
+        self.constructors.insert(name, constructor);
+    }
+
+    /// Lists names of all registered transforms, sorted alphabetically.
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names: Vec<_> = self.constructors.keys().cloned().collect();
+        names.sort_unstable();
+        names
+    }
+
+    pub fn create(&self, name: &str, options: &TransformOptions) -> Result<Box<dyn Transform>, Error> {
+        let constructor = self.constructors.get(name).ok_or_else(|| {
+            err_msg(format!("Unknown transform {name}, available transforms: {}", self.names().join(", ")))
+        })?;
+        Ok(constructor(options))
+    }
+}
+
+impl Default for TransformRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Ordered sequence of transforms applied to chapter content.
+pub struct Pipeline {
+    transforms: Vec<Box<dyn Transform>>,
+}
+
+impl Pipeline {
+    pub fn new(transforms: Vec<Box<dyn Transform>>) -> Self {
+        Self { transforms }
+    }
+
+    /// Builds a pipeline from transforms registered under given names, keeping their order.
+    pub fn from_names<S: AsRef<str>>(registry: &TransformRegistry, names: &[S], options: &TransformOptions) -> Result<Self, Error> {
+        let transforms = names
+            .iter()
+            .map(|name| registry.create(name.as_ref(), options))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::new(transforms))
+    }
+
+    /// Runs all transforms in order, performing the operations of each before running the next one.
+    pub fn apply(&self, doc: &mut Html, context: &mut TransformContext) -> Result<(), Error> {
+        for transform in &self.transforms {
+            let ops = transform
+                .operations(doc, context)
+                .context(format!("Transform {} failed", transform.name()))?;
+            doc.perform_operations(ops);
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    /// Elements of the chapter body that will end up as paragraphs in the book.
+    pub static ref CONTENT_ELEMENT_SELECTOR: Selector = Selector::parse("p, h1").unwrap();
+    /// Elements of the chapter body whose images will be embedded in the book.
+    static ref IMAGE_CONTAINER_SELECTOR: Selector = Selector::parse("p, h1, figure").unwrap();
+    static ref FIGURE_SELECTOR: Selector = Selector::parse("figure, div.wp-caption").unwrap();
+    static ref CAPTION_SELECTOR: Selector = Selector::parse("figcaption, .wp-caption-text").unwrap();
+    static ref PLACEHOLDER_SELECTOR: Selector = Selector::parse("span.missing-image[id]").unwrap();
+    static ref LINK_SELECTOR: Selector = Selector::parse("a").unwrap();
+    static ref IMAGE_SELECTOR: Selector = Selector::parse("img").unwrap();
+    static ref CLOUDFLARE_EMAIL_SELECTOR: Selector = Selector::parse("a.__cf_email__[data-cfemail]").unwrap();
+    static ref USELESS_SPAN_SELECTOR: Selector = Selector::parse(r#"span:not([class]):not([style]), span.short_text[id="result_box"], span.short_text[id="result_box"] span.hps"#).unwrap();
+}
+
+/// Cloudflare mangles anything even vaguely resembling an email into a string that's decoded by
+/// javascript on the client. For example, 'Point_Me_@_The_Sky' turns into:
+///   '<a href="/cdn-cgi/l/email-protection" class="__cf_email__" data-cfemail="...">[email&nbsp;protected]</a>_The_Sky'
+pub struct CloudflareEmails;
+
+impl SelectorTransform for CloudflareEmails {
+    fn name(&self) -> &'static str {
+        "cloudflare-emails"
+    }
+
+    fn selector(&self) -> &Selector {
+        &CLOUDFLARE_EMAIL_SELECTOR
+    }
+
+    fn element_operations(&self, elem: ElementRef, _context: &mut TransformContext) -> Result<Vec<DomOperation<NodeId>>, Error> {
+        let data = elem.value().attr("data-cfemail").unwrap();
+        let bytes = hex::decode(data).context("mangled email data is not a hex string")?;
+        if bytes.len() < 4 {
+            return Err(err_msg("mangled email data not long enough"));
+        }
+        let key = bytes[0];
+        let decoded = bytes[1..]
+            .iter()
+            .map(|byte| byte ^ key)
+            .collect::<Vec<u8>>();
+
+        Ok(vec![DomOperation::ReplaceElement {
+            node_id: elem.id(),
+            replacement: NodeOrText::AppendText(String::from_utf8(decoded).context("decoded email isn't a UTF-8 string")?.into()),
+        }])
+    }
+}
+
+/// Creates a copy of given image element with cleaned up attributes.
+fn clean_up_image(img: &ElementRef, new_src: String) -> Vec<DomOperation<NodeId>> {
+    let img_id = img.id();
+
+    vec![
+        // Since we will not download all the differently size versions,
+        // let’s ignore srcset & other related attributes.
+        // The other sizes are auto-generated by WordPress anyway.
+        DomOperation::RemoveAttribute {
+            node_id: img_id,
+            attr_name: html_attr_name("srcset"),
+        },
+        DomOperation::RemoveAttribute {
+            node_id: img_id,
+            attr_name: html_attr_name("sizes"),
+        },
+        DomOperation::RemoveAttribute {
+            node_id: img_id,
+            attr_name: html_attr_name("loading"),
+        },
+        // Replace src.
+        DomOperation::SetAttribute {
+            node_id: img_id,
+            attr_name: html_attr_name("src"),
+            value: new_src,
+        },
+    ]
+}
+
+/// Turns WordPress image blocks and `wp-caption` divs into plain `<figure>`s with a `<figcaption>`,
+/// dropping the links to attachment pages around the images.
+/// Images without alternative text get the caption as one, or at least an empty `alt`.
+pub struct Figures;
+
+impl SelectorTransform for Figures {
+    fn name(&self) -> &'static str {
+        "figures"
+    }
+
+    fn selector(&self) -> &Selector {
+        &FIGURE_SELECTOR
+    }
+
+    fn element_operations(&self, figure: ElementRef, _context: &mut TransformContext) -> Result<Vec<DomOperation<NodeId>>, Error> {
+        if figure.select(&IMAGE_SELECTOR).next().is_none() {
+            return Ok(vec![]);
+        }
+
+        let mut ops = vec![DomOperation::RenameElement {
+            node_id: figure.id(),
+            name: html_elem_name("figure"),
+            attrs: vec![],
+        }];
+
+        let caption = figure.select(&CAPTION_SELECTOR).next();
+        let caption_text = caption
+            .map(|caption| caption.text().collect::<String>().split_whitespace().collect::<Vec<_>>().join(" "))
+            .unwrap_or_default();
+        if let Some(caption) = caption {
+            ops.push(DomOperation::RenameElement {
+                node_id: caption.id(),
+                name: html_elem_name("figcaption"),
+                attrs: vec![],
+            });
+        }
+
+        for img in figure.select(&IMAGE_SELECTOR) {
+            if img.value().attr("alt").is_none_or(|alt| alt.trim().is_empty()) {
+                ops.push(DomOperation::SetAttribute {
+                    node_id: img.id(),
+                    attr_name: html_attr_name("alt"),
+                    value: caption_text.clone(),
+                });
+            }
+        }
+
+        for link in figure.select(&LINK_SELECTOR) {
+            if link.select(&IMAGE_SELECTOR).next().is_some() {
+                ops.push(DomOperation::DissolveElement { node_id: link.id() });
+            }
+        }
+
+        Ok(ops)
+    }
+}
+
+/// Reasons an image could not be embedded.
+pub enum ImageError {
+    Download(Error),
+    Unsupported(Error),
+}
+
+/// Downloads an image, adjusts it according to the options and adds it to the images of the book,
+/// unless an identical one is already there. Returns the path of the image inside the book.
+pub fn embed_image(
+    client: &CachedClient,
+    images: &mut ImageManager,
+    options: &ImageOptions,
+    image_url: &Url,
+) -> Result<String, ImageError> {
+    let res = client.fetch::<TypedBytes>(image_url, false).map_err(ImageError::Download)?;
+    if res.is_cached() {
+        println!("Found image in cache for {image_url}");
+    } else {
+        println!("Downloaded image from {image_url}");
+    }
+
+    let TypedBytes { bytes: contents, content_type } = res.contents().clone();
+
+    let media_type = MediaType::detect(&contents, content_type.as_deref(), image_url).map_err(ImageError::Unsupported)?;
+    let (contents, media_type) = match options.process(&contents, media_type) {
+        Ok(Some(processed)) => processed,
+        Ok(None) => (contents, media_type),
+        Err(error) => {
+            println!("Warning: Embedding {image_url} unprocessed: {error}");
+            (contents, media_type)
+        }
+    };
+
+    Ok(if let Some((path, _content_type)) = images.get(&contents) {
+        // Pale re-uploads the same header image multiple times.
+        // Let’s use the first image if one with the same contents already exists
+        // to avoid bloating the EPUB.
+        println!("Will re-use {path} instead of {image_url} since it is the same.");
+
+        path.clone()
+    } else {
+        // Use prefix otherwise epub_builder will produce invalid ids (starting with a number).
+        let mut url_path = format!("images{}", image_url.path());
+        if MediaType::from_url(image_url) != Some(media_type) {
+            // Readers may rely on the suffix, make sure it matches the contents.
+            url_path = format!("{url_path}.{}", media_type.extension());
+        }
+        images.insert(contents, (url_path.clone(), media_type.content_type()));
+
+        url_path
+    })
+}
+
+/// Turns an image element into a visible note that the image is missing.
+fn placeholder_operations(img: &ElementRef, id: &str, label: &str) -> Vec<DomOperation<NodeId>> {
+    vec![
+        DomOperation::RenameElement {
+            node_id: img.id(),
+            name: html_elem_name("span"),
+            attrs: vec![
+                Attribute {
+                    name: html_attr_name("class"),
+                    value: "missing-image".into(),
+                },
+                Attribute {
+                    name: html_attr_name("id"),
+                    value: id.into(),
+                },
+            ],
+        },
+        DomOperation::AppendText {
+            parent_id: img.id(),
+            text: format!("[Image unavailable: {label}]"),
+        },
+    ]
+}
+
+/// Replaces placeholders of images that were eventually downloaded, given by placeholder id,
+/// by the images with given paths and alternative texts.
+pub fn restore_placeholders(body_text: &str, restored: &HashMap<String, (String, String)>) -> String {
+    let mut doc = Html::parse_fragment(body_text);
+    let mut ops = Vec::new();
+
+    for placeholder in doc.select(&PLACEHOLDER_SELECTOR) {
+        if let Some((path, alt)) = restored.get(placeholder.value().attr("id").unwrap()) {
+            ops.push(DomOperation::RemoveChildren { node_id: placeholder.id() });
+            ops.push(DomOperation::RenameElement {
+                node_id: placeholder.id(),
+                name: html_elem_name("img"),
+                attrs: vec![
+                    Attribute {
+                        name: html_attr_name("src"),
+                        value: path.as_str().into(),
+                    },
+                    Attribute {
+                        name: html_attr_name("alt"),
+                        value: alt.as_str().into(),
+                    },
+                ],
+            });
+        }
+    }
+
+    if ops.is_empty() {
+        return body_text.to_string();
+    }
+
+    doc.perform_operations(ops);
+    doc.root_element().inner_xml()
+}
+
+/// Removes unnecessary attributes on images in content paragraphs and figures, changes their sources to point inside the EPUB,
+/// and if the paragraph containing the image also contains junk, remove it.
+/// Images are resized and converted according to the options before being embedded,
+/// the ones that cannot be downloaded are handled according to the failed images policy.
+#[derive(Default)]
+pub struct Images {
+    options: ImageOptions,
+    failed_images: FailedImages,
+}
+
+impl Images {
+    pub fn new(options: ImageOptions, failed_images: FailedImages) -> Self {
+        Self { options, failed_images }
+    }
+}
+
+impl Transform for Images {
+    fn name(&self) -> &'static str {
+        "images"
+    }
+
+    fn operations(&self, doc: &Html, context: &mut TransformContext) -> Result<Vec<DomOperation<NodeId>>, Error> {
+        let mut ops = Vec::new();
+
+        for paragraph in doc.select(&IMAGE_CONTAINER_SELECTOR) {
+            if paragraph.ancestors().filter_map(ElementRef::wrap).any(|ancestor| ancestor.value().name() == "figure") {
+                // Already handled together with the figure.
+                continue;
+            }
+
+            let img_elems = paragraph.select(&IMAGE_SELECTOR);
+
+            let is_figure = paragraph.value().name() == "figure";
+            let has_single_image = !is_figure && img_elems.clone().count() == 1;
+            let should_replace_paragraph_contents = has_single_image && if let Some(link) = paragraph.select(&LINK_SELECTOR).next() {
+                // Some Pale chapters (e.g. 18.10) have image paragraphs contaminated with “Next/Previous Chapter” links.
+                // If we detect that happened and there is no text in the paragraph outside the link, let’s keep only the image.
+                paragraph.text().collect::<String>().trim() == link.text().collect::<String>().trim()
+            } else {
+                false
+            };
+
+            for img in img_elems {
+                if let Some(src) = img.value().attr("src") {
+                    let mut image_url = context.page_url.join(src).context("Could not resolve image URL")?;
+                    // Remove `w` and `h` parameters from query string to download full-size image.
+                    image_url.set_query(None);
+
+                    let alt = img.value().attr("alt").unwrap_or("").to_string();
+                    match embed_image(context.client, context.images, &self.options, &image_url) {
+                        Ok(path) => {
+                            let mut new_img_changes = clean_up_image(&img, path);
+                            ops.append(&mut new_img_changes);
+                        }
+                        Err(ImageError::Unsupported(error)) => {
+                            println!("Warning: Skipping image: {error}");
+                            ops.push(DomOperation::RemoveElement { node_id: img.id() });
+                            context.missing_images.push(MissingImage {
+                                url: image_url,
+                                page_url: Some(context.page_url.clone()),
+                                reason: error.to_string(),
+                                placeholder_id: None,
+                                alt,
+                            });
+                            continue;
+                        }
+                        Err(ImageError::Download(error)) => {
+                            if self.failed_images == FailedImages::Abort {
+                                return Err(error);
+                            }
+                            println!("Warning: Using a placeholder for image {image_url}: {error}");
+                            let id = format!("missing-image-{}", context.missing_images.len() + 1);
+                            let label = if alt.trim().is_empty() {
+                                image_url.path().rsplit('/').next().unwrap_or("").to_string()
+                            } else {
+                                alt.trim().to_string()
+                            };
+                            ops.append(&mut placeholder_operations(&img, &id, &label));
+                            context.missing_images.push(MissingImage {
+                                url: image_url,
+                                page_url: Some(context.page_url.clone()),
+                                reason: error.to_string(),
+                                placeholder_id: Some(id),
+                                alt,
+                            });
+                        }
+                    }
+                    if should_replace_paragraph_contents {
+                        // Remove everything and then put just the image back.
+                        ops.push(
+                            DomOperation::RemoveChildren {
+                                node_id: paragraph.id(),
+                            }
+                        );
+                        ops.push(
+                            DomOperation::AppendChild {
+                                parent_id: paragraph.id(),
+                                child_id: img.id(),
+                            }
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(ops)
+    }
+}
+
+/// Removes spans that are not used for styling the content
+/// such as various junk inserted by WordPress’s editor.
+pub struct UselessSpans;
+
+impl SelectorTransform for UselessSpans {
+    fn name(&self) -> &'static str {
+        "useless-spans"
+    }
+
+    fn selector(&self) -> &Selector {
+        &USELESS_SPAN_SELECTOR
+    }
+
+    fn element_operations(&self, span: ElementRef, _context: &mut TransformContext) -> Result<Vec<DomOperation<NodeId>>, Error> {
+        Ok(vec![DomOperation::DissolveElement {
+            node_id: span.id(),
+        }])
+    }
+}
+
+/// Runs a transform with only the given resources available for download.
+#[cfg(test)]
+pub(crate) fn apply_offline(transform: Box<dyn Transform>, doc: &mut Html, cached: &[(&str, &[u8])]) -> ImageManager {
+    let client = CachedClient::offline(cached);
+    let mut images = HashMap::new();
+    let mut missing_images = Vec::new();
+    let page_url = Url::parse("https://example.com/").unwrap();
+    let mut context = TransformContext {
+        client: &client,
+        images: &mut images,
+        missing_images: &mut missing_images,
+        page_url: &page_url,
+    };
+    Pipeline::new(vec![transform]).apply(doc, &mut context).unwrap();
+    images
+}
+
+#[test]
+fn test_useless_span_removal() {
+    // Likely created by copying from Translator.
+    // https://pactwebserial.wordpress.com/2014/04/03/collateral-4-12/
+    let mut example = Html::parse_fragment(r#"<p>“<span id="result_box" class="short_text" lang="ga"><span class="hps">Comhroinn</span> </span><span id="result_box" class="short_text" lang="ga"><span class="hps">liom</span></span><em><span id="result_box" class="short_text" lang="ga">,</span></em>” the woman rasped.</p>"#);
+    apply_offline(Box::new(UselessSpans), &mut example, &[]);
+
+    assert_eq!(
+        Html::parse_fragment("<p>“Comhroinn liom<em>,</em>” the woman rasped.</p>"),
+        Html::parse_fragment(&example.root_element().inner_html()),
+    );
+}
+
+#[test]
+fn test_cloudflare_email_decoding() {
+    let mut example = Html::parse_fragment(r#"<p>Point_Me_<a href="/cdn-cgi/l/email-protection" class="__cf_email__" data-cfemail="2a6a7e4b">[email&nbsp;protected]</a>_The_Sky</p>"#);
+    apply_offline(Box::new(CloudflareEmails), &mut example, &[]);
+
+    assert_eq!(
+        Html::parse_fragment("<p>Point_Me_@Ta_The_Sky</p>"),
+        Html::parse_fragment(&example.root_element().inner_html()),
+    );
+}
+
+#[test]
+fn test_image_paragraph_contamination() {
+    // https://palewebserial.wordpress.com/2022/09/17/the-gallows-18-10/
+    let mut example = Html::parse_fragment(r#"<p>Text before.</p><p><img src="/header.png?w=300" srcset="/header.png?w=300 300w" loading="lazy"><a href="/next">Next Chapter</a></p><p>Text after <a href="/other">link</a>.</p>"#);
+    let images = apply_offline(Box::new(Images::default()), &mut example, &[("https://example.com/header.png", b"header")]);
+
+    assert_eq!(
+        Html::parse_fragment(r#"<p>Text before.</p><p><img src="images/header.png"></p><p>Text after <a href="/other">link</a>.</p>"#),
+        Html::parse_fragment(&example.root_element().inner_html()),
+    );
+    assert_eq!(images.get(&b"header"[..]), Some(&("images/header.png".to_string(), "image/png")));
+}
+
+#[test]
+fn test_image_format_detection() {
+    let mut example = Html::parse_fragment(r#"<p><img src="/avatar"></p><p>Text <img src="/scan.bmp"></p>"#);
+    let images = apply_offline(
+        Box::new(Images::default()),
+        &mut example,
+        &[("https://example.com/avatar", b"GIF89a\x01\0\x01\0"), ("https://example.com/scan.bmp", b"BM\x36\0\0\0")],
+    );
+
+    assert_eq!(
+        Html::parse_fragment(r#"<p><img src="images/avatar.gif"></p><p>Text </p>"#),
+        Html::parse_fragment(&example.root_element().inner_html()),
+    );
+    assert_eq!(images.get(&b"GIF89a\x01\0\x01\0"[..]), Some(&("images/avatar.gif".to_string(), "image/gif")));
+    assert_eq!(images.len(), 1);
+}
+
+#[test]
+fn test_figures() {
+    let mut example = Html::parse_fragment(concat!(
+        r#"<div id="attachment_12" class="wp-caption aligncenter" style="width: 310px"><a href="/map/"><img class="size-medium" src="/map.png" alt=""></a><p class="wp-caption-text">Map of  Brockton Bay</p></div>"#,
+        r#"<figure class="wp-block-image size-large"><img src="/cast.png" alt="Cast"><figcaption class="wp-element-caption">The <em>Undersiders</em></figcaption></figure>"#,
+        r#"<figure class="wp-block-image"><img src="/divider.png"></figure>"#,
+    ));
+    apply_offline(Box::new(Figures), &mut example, &[]);
+
+    assert_eq!(
+        Html::parse_fragment(concat!(
+            r#"<figure><img class="size-medium" src="/map.png" alt="Map of Brockton Bay"><figcaption>Map of  Brockton Bay</figcaption></figure>"#,
+            r#"<figure><img src="/cast.png" alt="Cast"><figcaption>The <em>Undersiders</em></figcaption></figure>"#,
+            r#"<figure><img src="/divider.png" alt=""></figure>"#,
+        )),
+        Html::parse_fragment(&example.root_element().inner_html()),
+    );
+}
+
+#[test]
+fn test_missing_image_placeholder() {
+    let mut example = Html::parse_fragment(r#"<p>Before <img src="https://example.com/map.png" alt="Map"> after</p>"#);
+    let client = CachedClient::offline(&[]);
+    let mut images = HashMap::new();
+    let mut missing_images = Vec::new();
+    let page_url = Url::parse("https://example.com/").unwrap();
+    let mut context = TransformContext {
+        client: &client,
+        images: &mut images,
+        missing_images: &mut missing_images,
+        page_url: &page_url,
+    };
+
+    let aborting = Pipeline::new(vec![Box::new(Images::new(ImageOptions::default(), FailedImages::Abort))]);
+    assert!(aborting.apply(&mut example.clone(), &mut context).is_err());
+
+    let skipping = Pipeline::new(vec![Box::new(Images::new(ImageOptions::default(), FailedImages::Skip))]);
+    skipping.apply(&mut example, &mut context).unwrap();
+
+    assert_eq!(
+        Html::parse_fragment(r#"<p>Before <span class="missing-image" id="missing-image-1">[Image unavailable: Map]</span> after</p>"#),
+        Html::parse_fragment(&example.root_element().inner_html()),
+    );
+    assert_eq!(missing_images.len(), 1);
+    assert_eq!(missing_images[0].placeholder_id.as_deref(), Some("missing-image-1"));
+
+    let restored = HashMap::from([("missing-image-1".to_string(), ("images/map.png".to_string(), "Map".to_string()))]);
+    let body_text = restore_placeholders(&example.root_element().inner_xml(), &restored);
+    assert_eq!(
+        Html::parse_fragment(r#"<p>Before <img src="images/map.png" alt="Map"> after</p>"#),
+        Html::parse_fragment(&Html::parse_fragment(&body_text).root_element().inner_html()),
+    );
+}
+
+#[test]
+fn test_pipeline_from_names() {
+    let registry = TransformRegistry::new();
+    let options = TransformOptions::default();
+    assert!(Pipeline::from_names(&registry, DEFAULT_TRANSFORMS, &options).is_ok());
+    assert!(Pipeline::from_names(&registry, &["no-such-transform"], &options).is_err());
+}
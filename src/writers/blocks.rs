@@ -0,0 +1,392 @@
+use ego_tree::NodeRef;
+use scraper::{ElementRef, Html, Node};
+#[cfg(test)]
+use xml_utils::XmlSerializable;
+
+/// Text-level content of a block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Inline {
+    Text(String),
+    Emphasis(Vec<Inline>),
+    Strong(Vec<Inline>),
+    Strikethrough(Vec<Inline>),
+    Link { href: String, children: Vec<Inline> },
+    Image { src: String, alt: String },
+    LineBreak,
+}
+
+/// Horizontal alignment of a paragraph, from the `center` and `right` classes or a centred `text-align`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/// Structure of a page, for output formats other than (X)HTML.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Block {
+    Paragraph {
+        inlines: Vec<Inline>,
+        /// Indentation level from the `indent-one` and `indent-two` classes.
+        indent: usize,
+        align: Align,
+    },
+    /// Centred symbol separating scenes, like ■ or ☙.
+    SceneBreak,
+    Heading { level: usize, inlines: Vec<Inline> },
+    Figure { src: String, alt: String, caption: Vec<Inline> },
+    List { ordered: bool, items: Vec<Vec<Block>> },
+    /// Group of blocks, with the class of the element containing them, e.g. `author-note` or `endnotes`.
+    Section { class: String, blocks: Vec<Block> },
+}
+
+/// Symbols that separate scenes when a paragraph contains nothing else.
+const SCENE_BREAK_GLYPHS: &[char] = &['■', '□', '☙', '❧', '⊙', '◊', '♦', '•', '*', '#', '~', '⁂', '§'];
+
+/// Collects the text of the inlines, dropping the formatting.
+pub fn plain_text(inlines: &[Inline]) -> String {
+    let mut text = String::new();
+    for inline in inlines {
+        match inline {
+            Inline::Text(t) => text.push_str(t),
+            Inline::Emphasis(children) | Inline::Strong(children) | Inline::Strikethrough(children) | Inline::Link { children, .. } => {
+                text.push_str(&plain_text(children));
+            }
+            Inline::Image { alt, .. } => text.push_str(alt),
+            Inline::LineBreak => text.push('\n'),
+        }
+    }
+    text
+}
+
+/// Collects the addresses of the images in the blocks, in order of appearance.
+pub fn image_sources(blocks: &[Block]) -> Vec<String> {
+    fn inline_sources(inlines: &[Inline], sources: &mut Vec<String>) {
+        for inline in inlines {
+            match inline {
+                Inline::Image { src, .. } => sources.push(src.clone()),
+                Inline::Emphasis(children) | Inline::Strong(children) | Inline::Strikethrough(children) | Inline::Link { children, .. } => {
+                    inline_sources(children, sources);
+                }
+                Inline::Text(_) | Inline::LineBreak => {}
+            }
+        }
+    }
+
+    let mut sources = Vec::new();
+    for block in blocks {
+        match block {
+            Block::Paragraph { inlines, .. } | Block::Heading { inlines, .. } => inline_sources(inlines, &mut sources),
+            Block::Figure { src, caption, .. } => {
+                sources.push(src.clone());
+                inline_sources(caption, &mut sources);
+            }
+            Block::List { items, .. } => sources.extend(items.iter().flat_map(|item| image_sources(item))),
+            Block::Section { blocks, .. } => sources.extend(image_sources(blocks)),
+            Block::SceneBreak => {}
+        }
+    }
+    sources
+}
+
+fn has_class(elem: ElementRef, class: &str) -> bool {
+    elem.value().classes().any(|c| c == class)
+}
+
+fn is_block_element(name: &str) -> bool {
+    matches!(
+        name,
+        "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "aside" | "section" | "div" | "article" | "figure" | "ol" | "ul" | "blockquote" | "footer" | "header"
+    )
+}
+
+/// Parses the inline content of the nodes, collapsing whitespace as browsers would.
+fn parse_inlines<'a, I: Iterator<Item = NodeRef<'a, Node>>>(nodes: I) -> Vec<Inline> {
+    let mut inlines = Vec::new();
+    for node in nodes {
+        match node.value() {
+            Node::Text(text) => {
+                let collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                if collapsed.is_empty() {
+                    if !text.is_empty() {
+                        inlines.push(Inline::Text(" ".to_string()));
+                    }
+                    continue;
+                }
+                let mut t = String::new();
+                if text.starts_with(char::is_whitespace) {
+                    t.push(' ');
+                }
+                t.push_str(&collapsed);
+                if text.ends_with(char::is_whitespace) {
+                    t.push(' ');
+                }
+                inlines.push(Inline::Text(t));
+            }
+            Node::Element(elem) => {
+                let children = || parse_inlines(node.children());
+                match elem.name() {
+                    "em" | "i" | "cite" => inlines.push(Inline::Emphasis(children())),
+                    "strong" | "b" => inlines.push(Inline::Strong(children())),
+                    "del" | "s" | "strike" => inlines.push(Inline::Strikethrough(children())),
+                    "a" => match elem.attr("href") {
+                        Some(href) => inlines.push(Inline::Link { href: href.to_string(), children: children() }),
+                        None => inlines.extend(children()),
+                    },
+                    "img" => inlines.push(Inline::Image {
+                        src: elem.attr("src").unwrap_or("").to_string(),
+                        alt: elem.attr("alt").unwrap_or("").to_string(),
+                    }),
+                    "br" => inlines.push(Inline::LineBreak),
+                    _ => inlines.extend(children()),
+                }
+            }
+            _ => {}
+        }
+    }
+    merge_text(inlines)
+}
+
+/// Joins adjacent text and collapses the spaces between them.
+fn merge_text(inlines: Vec<Inline>) -> Vec<Inline> {
+    let mut merged: Vec<Inline> = Vec::new();
+    for inline in inlines {
+        match (merged.last_mut(), inline) {
+            (Some(Inline::Text(previous)), Inline::Text(text)) => {
+                if previous.ends_with(' ') && text.starts_with(' ') {
+                    previous.push_str(&text[1..]);
+                } else {
+                    previous.push_str(&text);
+                }
+            }
+            (_, inline) => merged.push(inline),
+        }
+    }
+    merged
+}
+
+/// Removes whitespace at the start and end of a block.
+fn trim_inlines(mut inlines: Vec<Inline>) -> Vec<Inline> {
+    fn trim_edge(inlines: &mut Vec<Inline>, start: bool) {
+        let index = if start { 0 } else { inlines.len().wrapping_sub(1) };
+        match inlines.get_mut(index) {
+            Some(Inline::Text(text)) => {
+                *text = if start { text.trim_start().to_string() } else { text.trim_end().to_string() };
+                if text.is_empty() {
+                    inlines.remove(index);
+                    trim_edge(inlines, start);
+                }
+            }
+            Some(Inline::Emphasis(children)) | Some(Inline::Strong(children)) | Some(Inline::Strikethrough(children)) | Some(Inline::Link { children, .. }) => {
+                trim_edge(children, start);
+            }
+            _ => {}
+        }
+    }
+    trim_edge(&mut inlines, true);
+    trim_edge(&mut inlines, false);
+    inlines
+}
+
+/// Whether the paragraph only contains a symbol separating scenes.
+fn is_centered(elem: ElementRef) -> bool {
+    has_class(elem, "center")
+        || has_class(elem, "aligncenter")
+        || elem.value().attr("style").is_some_and(|style| style.replace(' ', "").contains("text-align:center"))
+}
+
+/// Whether the paragraph separates scenes: it is made of separator glyphs,
+/// or it is centred and has a few symbols but no words.
+fn is_scene_break(elem: ElementRef, inlines: &[Inline]) -> bool {
+    let text = plain_text(inlines);
+    let text = text.trim();
+    if text.is_empty() || inlines.iter().any(|inline| matches!(inline, Inline::Image { .. })) {
+        return false;
+    }
+    text.chars().all(|c| c.is_whitespace() || SCENE_BREAK_GLYPHS.contains(&c))
+        || (is_centered(elem) && text.chars().count() <= 5 && !text.chars().any(char::is_alphanumeric))
+}
+
+fn parse_paragraph(elem: ElementRef) -> Option<Block> {
+    let inlines = trim_inlines(parse_inlines(elem.children()));
+    if inlines.is_empty() {
+        return None;
+    }
+    if is_scene_break(elem, &inlines) {
+        return Some(Block::SceneBreak);
+    }
+    let indent = if has_class(elem, "indent-two") {
+        2
+    } else if has_class(elem, "indent-one") {
+        1
+    } else {
+        0
+    };
+    let align = if is_centered(elem) {
+        Align::Center
+    } else if has_class(elem, "right") {
+        Align::Right
+    } else {
+        Align::Left
+    };
+    Some(Block::Paragraph { inlines, indent, align })
+}
+
+/// Parses the children of a node into blocks. Inline content between blocks is gathered into paragraphs.
+fn parse_children<'a, I: Iterator<Item = NodeRef<'a, Node>>>(nodes: I) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let mut loose: Vec<NodeRef<Node>> = Vec::new();
+
+    let flush = |loose: &mut Vec<NodeRef<'a, Node>>, blocks: &mut Vec<Block>| {
+        let inlines = trim_inlines(parse_inlines(loose.drain(..)));
+        if !inlines.is_empty() {
+            blocks.push(Block::Paragraph { inlines, indent: 0, align: Align::Left });
+        }
+    };
+
+    for node in nodes {
+        let Some(elem) = ElementRef::wrap(node) else {
+            loose.push(node);
+            continue;
+        };
+        let name = elem.value().name();
+        if !is_block_element(name) {
+            loose.push(node);
+            continue;
+        }
+        flush(&mut loose, &mut blocks);
+
+        match name {
+            "p" => blocks.extend(parse_paragraph(elem)),
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                let inlines = trim_inlines(parse_inlines(elem.children()));
+                if !inlines.is_empty() {
+                    blocks.push(Block::Heading { level: name[1..].parse().unwrap(), inlines });
+                }
+            }
+            "figure" => {
+                let img = elem.descendants().filter_map(ElementRef::wrap).find(|e| e.value().name() == "img");
+                let caption = elem
+                    .children()
+                    .filter_map(ElementRef::wrap)
+                    .find(|e| e.value().name() == "figcaption")
+                    .map(|caption| trim_inlines(parse_inlines(caption.children())))
+                    .unwrap_or_default();
+                if let Some(img) = img {
+                    blocks.push(Block::Figure {
+                        src: img.value().attr("src").unwrap_or("").to_string(),
+                        alt: img.value().attr("alt").unwrap_or("").to_string(),
+                        caption,
+                    });
+                }
+            }
+            "ol" | "ul" => {
+                let items = elem
+                    .children()
+                    .filter_map(ElementRef::wrap)
+                    .filter(|item| item.value().name() == "li")
+                    .map(|item| parse_children(item.children()))
+                    .collect();
+                blocks.push(Block::List { ordered: name == "ol", items });
+            }
+            _ => {
+                let class = elem.value().attr("class").unwrap_or("").to_string();
+                let children = parse_children(elem.children());
+                if class.is_empty() {
+                    blocks.extend(children);
+                } else if !children.is_empty() {
+                    blocks.push(Block::Section { class, blocks: children });
+                }
+            }
+        }
+    }
+    flush(&mut loose, &mut blocks);
+
+    blocks
+}
+
+/// Parses the body of a page into blocks.
+pub fn parse_blocks(body_text: &str) -> Vec<Block> {
+    let doc = Html::parse_fragment(body_text);
+    parse_children(doc.root_element().children())
+}
+
+#[test]
+fn test_parse_blocks() {
+    // Bodies reach the writers as written by the XML serializer, after a stage that parsed them as HTML.
+    let body_text = Html::parse_fragment(concat!(
+        "<p>It was <em>a dark</em>\n and <strong>stormy</strong> night.</p>\n",
+        "<p class=\"center\">■</p>\n",
+        "<p class=\"indent-one\">Indented<br>twice</p>\n",
+        "<figure><img src=\"images/map.png\" alt=\"Map\"><figcaption>The <em>city</em></figcaption></figure>\n",
+        "<aside class=\"author-note\">\n<p>Thanks.</p>\n</aside>\n",
+        "<section class=\"endnotes\">\n<h2>Links</h2>\n<ol>\n<li id=\"endnote-1\"><a href=\"#endnote-ref-1\">↩</a> https://example.com/</li>\n</ol>\n</section>\n",
+    ))
+    .root_element()
+    .inner_xml();
+    let blocks = parse_blocks(&body_text);
+
+    let text = |t: &str| Inline::Text(t.to_string());
+    assert_eq!(
+        blocks,
+        vec![
+            Block::Paragraph {
+                inlines: vec![
+                    text("It was "),
+                    Inline::Emphasis(vec![text("a dark")]),
+                    text(" and "),
+                    Inline::Strong(vec![text("stormy")]),
+                    text(" night."),
+                ],
+                indent: 0,
+                align: Align::Left,
+            },
+            Block::SceneBreak,
+            Block::Paragraph {
+                inlines: vec![text("Indented"), Inline::LineBreak, text("twice")],
+                indent: 1,
+                align: Align::Left,
+            },
+            Block::Figure {
+                src: "images/map.png".to_string(),
+                alt: "Map".to_string(),
+                caption: vec![text("The "), Inline::Emphasis(vec![text("city")])],
+            },
+            Block::Section {
+                class: "author-note".to_string(),
+                blocks: vec![Block::Paragraph { inlines: vec![text("Thanks.")], indent: 0, align: Align::Left }],
+            },
+            Block::Section {
+                class: "endnotes".to_string(),
+                blocks: vec![
+                    Block::Heading { level: 2, inlines: vec![text("Links")] },
+                    Block::List {
+                        ordered: true,
+                        items: vec![vec![Block::Paragraph {
+                            inlines: vec![
+                                Inline::Link { href: "#endnote-ref-1".to_string(), children: vec![text("↩")] },
+                                text(" https://example.com/"),
+                            ],
+                            indent: 0,
+                            align: Align::Left,
+                        }]],
+                    },
+                ],
+            },
+        ],
+    );
+}
+
+#[test]
+fn test_scene_breaks() {
+    let text = |t: &str| Inline::Text(t.to_string());
+    assert_eq!(
+        parse_blocks("<p>* * *</p><p style=\"text-align: center;\">-=-</p><p>“…”</p>"),
+        vec![
+            Block::SceneBreak,
+            Block::SceneBreak,
+            Block::Paragraph { inlines: vec![text("“…”")], indent: 0, align: Align::Left },
+        ],
+    );
+}
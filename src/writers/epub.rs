@@ -0,0 +1,141 @@
+use easy_error::{Error, ResultExt};
+use epub2;
+use epub_directory::unpack_epub;
+use epub_builder::{EpubBuilder, EpubContent, EpubVersion, ReferenceType, ZipLibrary};
+use media_type::MediaType;
+use metadata::BookMetadata;
+use std::path::Path;
+
+use super::{BookWriter, Page, PageKind, STYLESHEET};
+
+/// Writes the book as EPUB, or as the unpacked contents of one.
+pub struct EpubWriter {
+    builder: EpubBuilder<ZipLibrary>,
+    metadata: BookMetadata,
+    version: EpubVersion,
+    unpacked: bool,
+}
+
+impl EpubWriter {
+    pub fn new(metadata: BookMetadata, version: EpubVersion, unpacked: bool) -> Result<Self, Error> {
+        let mut builder = EpubBuilder::new(ZipLibrary::new().context("Could not create ZipLibrary")?).context("Could not create EpubBuilder")?;
+
+        builder
+        .epub_version(version)
+        .stylesheet(STYLESHEET.as_bytes()).context("Could not set stylesheet")?
+        .metadata("author", &metadata.author).context("Could not set author metadata")?
+        .metadata("title", &metadata.title).context("Could not set title metadata")?
+        .metadata("lang", &metadata.language).context("Could not set language metadata")?
+        .metadata("description", &metadata.description).context("Could not set description metadata")?;
+
+        Ok(EpubWriter { builder, metadata, version, unpacked })
+    }
+}
+
+fn xhtml_document(title: &str, body_text: &str, version: EpubVersion) -> String {
+    let escaped_title = html_escape::encode_text(title);
+    let (doctype, body_text) = match version {
+        EpubVersion::V20 => (epub2::XHTML11_DOCTYPE, epub2::downgrade_markup(body_text)),
+        _ => ("", body_text.to_string()),
+    };
+    "<?xml version='1.0' encoding='utf-8' ?>".to_string() + doctype + "<html xmlns='http://www.w3.org/1999/xhtml'><head><title>" + &escaped_title + "</title><meta http-equiv='Content-Type' content ='text/html; charset=utf-8' />\n<link rel='stylesheet' type='text/css' href='stylesheet.css' />\n</head><body><h1>" + &escaped_title + "</h1>\n" + &body_text + "</body></html>"
+}
+
+impl BookWriter for EpubWriter {
+    fn extension(&self) -> Option<&'static str> {
+        if self.unpacked { None } else { Some("epub") }
+    }
+
+    fn add_cover(&mut self, data: &[u8], media_type: MediaType) -> Result<(), Error> {
+        self.builder.add_cover_image(format!("cover.{}", media_type.extension()), data, media_type.content_type())
+            .context("Could not add cover image")?;
+        Ok(())
+    }
+
+    fn add_page(&mut self, page: Page) -> Result<(), Error> {
+        let document = xhtml_document(&page.title, &page.body_text, self.version);
+
+        // Title here should not need to be escaped but unfortunately,
+        // without this the nav.xhtml will contain unescaped &.
+        // And we cannot just escape it ourseves or it will be double escaped in toc.ncx.
+        // Let’s replace it with small ampersand Unicode character.
+        // https://github.com/lise-henry/epub-builder/pull/41
+        let title_clean = page.title.replace("&", "﹠");
+        let mut content = EpubContent::new(page.file, document.as_bytes()).title(title_clean).level(page.level as i32);
+        if page.level == 1 {
+            content = content.reftype(match page.kind {
+                PageKind::Chapter => ReferenceType::Text,
+                PageKind::Notes => ReferenceType::Notes,
+            });
+        }
+        self.builder.add_content(content).context("Could not add page")?;
+        Ok(())
+    }
+
+    fn add_resource(&mut self, path: &str, data: &[u8], content_type: &str) -> Result<(), Error> {
+        self.builder.add_resource(path, data, content_type).context(format!("Could not add image {path}"))?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>, path: &Path) -> Result<(), Error> {
+        let mut epub = Vec::new();
+        self.builder.generate(&mut epub).context("Could not generate ebook")?;
+        let epub = self.metadata.apply_to_epub(&epub)?;
+        if self.unpacked {
+            return unpack_epub(&epub, path);
+        }
+        std::fs::write(path, epub).context("Could not write file")?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_epub2_identifier() {
+    use std::io::Read;
+    use zip::ZipArchive;
+
+    let metadata = BookMetadata {
+        title: "Worm".to_string(),
+        author: "Wildbow".to_string(),
+        author_file_as: None,
+        description: String::new(),
+        language: "en-US".to_string(),
+        identifier: "urn:uuid:cb7e3b0a-4d28-4c58-a2a6-9b1f0e8f9d4c".to_string(),
+        date: "2013-11-19".to_string(),
+        series: None,
+        subjects: vec![],
+        publisher: None,
+        source: None,
+    };
+    let mut writer = Box::new(EpubWriter::new(metadata, EpubVersion::V20, false).unwrap());
+    writer
+        .add_page(Page {
+            file: "chapter_1-1.xhtml".to_string(),
+            title: "Gestation 1.1".to_string(),
+            body_text: "<p>Story.</p>".to_string(),
+            kind: PageKind::Chapter,
+            level: 1,
+            arc: None,
+            url: None,
+            fetched_at: None,
+        })
+        .unwrap();
+    let path = std::env::temp_dir().join(format!("epub2-identifier-{}.epub", std::process::id()));
+    writer.finish(&path).unwrap();
+    let mut archive = ZipArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+    let mut read = |name: &str| {
+        let mut text = String::new();
+        archive.by_name(name).unwrap().read_to_string(&mut text).unwrap();
+        text
+    };
+    let (opf, ncx) = (read("OEBPS/content.opf"), read("OEBPS/toc.ncx"));
+    std::fs::remove_file(&path).unwrap();
+
+    let between = |text: &str, start: &str, end: &str| {
+        let from = text.find(start).unwrap() + start.len();
+        text[from..from + text[from..].find(end).unwrap()].to_string()
+    };
+    let identifier = between(&opf, "<dc:identifier id=\"epub-id-1\">", "</dc:identifier>");
+    assert_eq!(identifier, "urn:uuid:cb7e3b0a-4d28-4c58-a2a6-9b1f0e8f9d4c");
+    assert_eq!(between(&ncx, "<meta name=\"dtb:uid\" content=\"", "\""), identifier);
+}
@@ -0,0 +1,329 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use easy_error::{Error, ResultExt};
+use html_escape::{encode_double_quoted_attribute as attr, encode_text as text};
+use media_type::MediaType;
+use metadata::BookMetadata;
+use std::path::Path;
+
+use super::blocks::{parse_blocks, Block, Inline};
+use super::{BookWriter, Page, PageKind};
+
+/// FB2 genres for the subjects of the books. Subjects without a counterpart, like “Web serial”, are left out.
+const GENRES: &[(&str, &str)] = &[
+    ("Biopunk", "sf_cyberpunk"),
+    ("Crime", "det_crime"),
+    ("Horror", "sf_horror"),
+    ("Mystery", "detective"),
+    ("Science fiction", "sf"),
+    ("Superheroes", "sf_heroic"),
+    ("Thriller", "thriller"),
+    ("Urban fantasy", "sf_fantasy"),
+];
+
+/// Writes the book as FictionBook 2, with the chapters grouped in a section per arc and the images embedded.
+pub struct Fb2Writer {
+    metadata: BookMetadata,
+    cover: Option<(String, Vec<u8>, &'static str)>,
+    pages: Vec<Page>,
+    /// Images by their paths in the pages, with their content types.
+    binaries: Vec<(String, Vec<u8>, String)>,
+}
+
+/// Identifier of the binary holding the image at given path. It has to be a valid XML identifier.
+fn binary_id(path: &str) -> String {
+    path.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' }).collect()
+}
+
+/// Identifier of the section containing the page stored in given file.
+fn section_id(file: &str) -> String {
+    binary_id(file.strip_suffix(".xhtml").unwrap_or(file))
+}
+
+/// Builds FB2 markup from the blocks of a page.
+struct Fb2Renderer<'a> {
+    binaries: &'a [(String, Vec<u8>, String)],
+    output: String,
+}
+
+impl<'a> Fb2Renderer<'a> {
+    fn image(&self, src: &str) -> Option<String> {
+        self.binaries.iter().find(|(path, _, _)| path == src).map(|(path, _, _)| format!("<image l:href=\"#{}\"/>", binary_id(path)))
+    }
+
+    fn inlines(&self, inlines: &[Inline]) -> String {
+        let mut markup = String::new();
+        for inline in inlines {
+            match inline {
+                Inline::Text(t) => markup.push_str(&text(t)),
+                Inline::Emphasis(children) => markup.push_str(&format!("<emphasis>{}</emphasis>", self.inlines(children))),
+                Inline::Strong(children) => markup.push_str(&format!("<strong>{}</strong>", self.inlines(children))),
+                Inline::Strikethrough(children) => markup.push_str(&format!("<strikethrough>{}</strikethrough>", self.inlines(children))),
+                Inline::Link { href, children } => {
+                    let (file, fragment) = href.split_once('#').unwrap_or((href, ""));
+                    let target = if file.contains(':') {
+                        Some(href.to_string())
+                    } else if file.ends_with(".xhtml") && fragment.is_empty() {
+                        Some(format!("#{}", section_id(file)))
+                    } else {
+                        // Identifiers inside the pages are not kept.
+                        None
+                    };
+                    match target {
+                        Some(target) => markup.push_str(&format!("<a l:href=\"{}\">{}</a>", attr(&target), self.inlines(children))),
+                        None => markup.push_str(&self.inlines(children)),
+                    }
+                }
+                Inline::Image { src, alt } => match self.image(src) {
+                    Some(image) => markup.push_str(&image),
+                    None => markup.push_str(&text(alt)),
+                },
+                // Breaks only occur inside paragraphs when nested in other inlines.
+                Inline::LineBreak => markup.push(' '),
+            }
+        }
+        markup
+    }
+
+    /// Writes a paragraph, split at line breaks since FB2 has no such element.
+    fn paragraph(&mut self, inlines: &[Inline], prefix: &str) {
+        for (i, line) in inlines.split(|inline| *inline == Inline::LineBreak).enumerate() {
+            let marker = if i == 0 { prefix } else { "" };
+            self.output.push_str(&format!("<p>{}{}</p>\n", text(marker), self.inlines(line)));
+        }
+    }
+
+    fn blocks(&mut self, blocks: &[Block]) {
+        for block in blocks {
+            match block {
+                Block::Paragraph { inlines, indent, .. } => {
+                    // Indented passages are quoted, FB2 having no indentation or alignment.
+                    self.output.push_str(&"<cite>\n".repeat(*indent));
+                    self.paragraph(inlines, "");
+                    self.output.push_str(&"</cite>\n".repeat(*indent));
+                }
+                Block::SceneBreak => self.output.push_str("<subtitle>* * *</subtitle>\n"),
+                Block::Heading { inlines, .. } => self.output.push_str(&format!("<subtitle>{}</subtitle>\n", self.inlines(inlines))),
+                Block::Figure { src, alt, caption } => {
+                    match self.image(src) {
+                        Some(image) => self.output.push_str(&format!("{image}\n")),
+                        None => self.output.push_str(&format!("<p>{}</p>\n", text(alt))),
+                    }
+                    if !caption.is_empty() {
+                        self.paragraph(&[Inline::Emphasis(caption.clone())], "");
+                    }
+                }
+                Block::List { ordered, items } => {
+                    for (i, item) in items.iter().enumerate() {
+                        let marker = if *ordered { format!("{}. ", i + 1) } else { "• ".to_string() };
+                        match item.split_first() {
+                            Some((Block::Paragraph { inlines, .. }, rest)) => {
+                                self.paragraph(inlines, &marker);
+                                self.blocks(rest);
+                            }
+                            _ => {
+                                self.output.push_str(&format!("<p>{}</p>\n", text(marker.trim_end())));
+                                self.blocks(item);
+                            }
+                        }
+                    }
+                }
+                Block::Section { class, blocks } => {
+                    let quoted = class.split_whitespace().any(|c| c == "author-note");
+                    if quoted {
+                        self.output.push_str("<cite>\n");
+                    }
+                    self.blocks(blocks);
+                    if quoted {
+                        self.output.push_str("</cite>\n");
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Fb2Writer {
+    pub fn new(metadata: BookMetadata) -> Self {
+        Fb2Writer {
+            metadata,
+            cover: None,
+            pages: Vec::new(),
+            binaries: Vec::new(),
+        }
+    }
+
+    fn description(&self) -> String {
+        let metadata = &self.metadata;
+        let mut info = String::from("<description>\n<title-info>\n");
+        let mut genres: Vec<&str> = metadata.subjects.iter().filter_map(|subject| GENRES.iter().find(|(name, _)| name == subject).map(|(_, genre)| *genre)).collect();
+        if genres.is_empty() {
+            genres.push("prose_contemporary");
+        }
+        for genre in genres {
+            info.push_str(&format!("<genre>{genre}</genre>\n"));
+        }
+        let author = match metadata.author_file_as.as_ref().and_then(|file_as| file_as.split_once(", ")) {
+            // Filed under another name, so the credited one is a pen name.
+            Some((last, first)) => format!(
+                "<first-name>{}</first-name><last-name>{}</last-name><nickname>{}</nickname>",
+                text(first),
+                text(last),
+                text(&metadata.author),
+            ),
+            None => match metadata.author.rsplit_once(' ') {
+                Some((first, last)) => format!("<first-name>{}</first-name><last-name>{}</last-name>", text(first), text(last)),
+                None => format!("<nickname>{}</nickname>", text(&metadata.author)),
+            },
+        };
+        info.push_str(&format!("<author>{author}</author>\n"));
+        info.push_str(&format!("<book-title>{}</book-title>\n", text(&metadata.title)));
+        if !metadata.description.is_empty() {
+            info.push_str(&format!("<annotation><p>{}</p></annotation>\n", text(&metadata.description)));
+        }
+        info.push_str(&format!("<date value=\"{0}\">{0}</date>\n", attr(&metadata.date)));
+        if let Some((ref id, _, _)) = self.cover {
+            info.push_str(&format!("<coverpage><image l:href=\"#{id}\"/></coverpage>\n"));
+        }
+        let language = metadata.language.split('-').next().unwrap_or(&metadata.language);
+        info.push_str(&format!("<lang>{}</lang>\n", text(language)));
+        if let Some((ref series, ref index)) = metadata.series {
+            info.push_str(&format!("<sequence name=\"{}\" number=\"{}\"/>\n", attr(series), attr(index)));
+        }
+        info.push_str("</title-info>\n");
+
+        info.push_str("<document-info>\n<author><nickname>rust-wildbow-scraper</nickname></author>\n<program-used>rust-wildbow-scraper</program-used>\n");
+        let today = chrono::Local::now().format("%Y-%m-%d");
+        info.push_str(&format!("<date value=\"{today}\">{today}</date>\n"));
+        if let Some(ref source) = metadata.source {
+            info.push_str(&format!("<src-url>{}</src-url>\n", text(source)));
+        }
+        info.push_str(&format!("<id>{}</id>\n<version>1.0</version>\n</document-info>\n", text(&metadata.identifier)));
+        if let Some(ref publisher) = metadata.publisher {
+            info.push_str(&format!("<publish-info>\n<publisher>{}</publisher>\n</publish-info>\n", text(publisher)));
+        }
+        info.push_str("</description>\n");
+        info
+    }
+
+    fn document(&self) -> String {
+        let mut doc = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<FictionBook xmlns=\"http://www.gribuser.ru/xml/fictionbook/2.0\" xmlns:l=\"http://www.w3.org/1999/xlink\">\n");
+        doc.push_str(&self.description());
+
+        let mut renderer = Fb2Renderer {
+            binaries: &self.binaries,
+            output: format!("<body>\n<title><p>{}</p></title>\n", text(&self.metadata.title)),
+        };
+        let mut current_arc: Option<&str> = None;
+        for page in &self.pages {
+            let arc = match page.kind {
+                PageKind::Chapter => page.arc.as_deref(),
+                PageKind::Notes => None,
+            };
+            if arc != current_arc {
+                if current_arc.is_some() {
+                    renderer.output.push_str("</section>\n");
+                }
+                if let Some(arc) = arc {
+                    renderer.output.push_str(&format!("<section>\n<title><p>{}</p></title>\n", text(arc)));
+                }
+                current_arc = arc;
+            }
+            renderer.output.push_str(&format!("<section id=\"{}\">\n<title><p>{}</p></title>\n", section_id(&page.file), text(&page.title)));
+            let blocks = parse_blocks(&page.body_text);
+            if blocks.is_empty() {
+                // Sections need some content.
+                renderer.output.push_str("<empty-line/>\n");
+            }
+            renderer.blocks(&blocks);
+            renderer.output.push_str("</section>\n");
+        }
+        if current_arc.is_some() {
+            renderer.output.push_str("</section>\n");
+        }
+        renderer.output.push_str("</body>\n");
+        doc.push_str(&renderer.output);
+
+        let binaries = self.cover.iter().map(|(id, data, content_type)| (id.clone(), data, *content_type))
+            .chain(self.binaries.iter().map(|(path, data, content_type)| (binary_id(path), data, content_type.as_str())));
+        for (id, data, content_type) in binaries {
+            doc.push_str(&format!("<binary id=\"{id}\" content-type=\"{content_type}\">{}</binary>\n", BASE64.encode(data)));
+        }
+        doc.push_str("</FictionBook>\n");
+        doc
+    }
+}
+
+impl BookWriter for Fb2Writer {
+    fn extension(&self) -> Option<&'static str> {
+        Some("fb2")
+    }
+
+    fn add_cover(&mut self, data: &[u8], media_type: MediaType) -> Result<(), Error> {
+        self.cover = Some((format!("cover.{}", media_type.extension()), data.to_vec(), media_type.content_type()));
+        Ok(())
+    }
+
+    fn add_page(&mut self, page: Page) -> Result<(), Error> {
+        self.pages.push(page);
+        Ok(())
+    }
+
+    fn add_resource(&mut self, path: &str, data: &[u8], content_type: &str) -> Result<(), Error> {
+        self.binaries.push((path.to_string(), data.to_vec(), content_type.to_string()));
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, path: &Path) -> Result<(), Error> {
+        std::fs::write(path, self.document()).context("Could not write file")?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_fb2_document() {
+    let mut writer = Fb2Writer::new(BookMetadata {
+        title: "Worm".to_string(),
+        author: "Wildbow".to_string(),
+        author_file_as: Some("McCrae, John".to_string()),
+        description: "Brockton Bay & more".to_string(),
+        language: "en-US".to_string(),
+        identifier: "urn:uuid:1".to_string(),
+        date: "2013-11-19".to_string(),
+        series: Some(("Parahumans".to_string(), "1".to_string())),
+        subjects: vec!["Superheroes".to_string(), "Web serial".to_string()],
+        publisher: Some("Wildbow".to_string()),
+        source: None,
+    });
+    let page = |file: &str, title: &str, arc: Option<&str>, body_text: &str, kind| Page {
+        file: file.to_string(),
+        title: title.to_string(),
+        body_text: body_text.to_string(),
+        kind,
+        level: 1,
+        arc: arc.map(str::to_string),
+        url: None,
+        fetched_at: None,
+    };
+    writer.add_cover(b"\xff\xd8", MediaType::Jpeg).unwrap();
+    writer.add_page(page("chapter_1-1.xhtml", "Gestation 1.1", Some("Gestation"), "<p>One <em>two</em><br/>three</p><p class=\"center\">■</p><p class=\"indent-one\"><img src=\"images/map.png\" alt=\"Map\"></p>", PageKind::Chapter)).unwrap();
+    writer.add_page(page("chapter_1-2.xhtml", "Gestation 1.2", Some("Gestation"), "<p><a href=\"chapter_1-1.xhtml\">Back</a></p>", PageKind::Chapter)).unwrap();
+    writer.add_page(page("chapter_2-1.xhtml", "Insinuation 2.1", Some("Insinuation"), "", PageKind::Chapter)).unwrap();
+    writer.add_page(page("author_notes.xhtml", "Author’s Notes", None, "<p>Thanks</p>", PageKind::Notes)).unwrap();
+    writer.add_resource("images/map.png", b"\x89PNG", "image/png").unwrap();
+
+    let doc = writer.document();
+    assert!(doc.contains("<genre>sf_heroic</genre>\n<author><first-name>John</first-name><last-name>McCrae</last-name><nickname>Wildbow</nickname></author>\n<book-title>Worm</book-title>\n"));
+    assert!(doc.contains("<annotation><p>Brockton Bay &amp; more</p></annotation>\n<date value=\"2013-11-19\">2013-11-19</date>\n<coverpage><image l:href=\"#cover.jpg\"/></coverpage>\n<lang>en</lang>\n<sequence name=\"Parahumans\" number=\"1\"/>\n"));
+    assert!(doc.contains(concat!(
+        "<body>\n<title><p>Worm</p></title>\n",
+        "<section>\n<title><p>Gestation</p></title>\n",
+        "<section id=\"chapter_1-1\">\n<title><p>Gestation 1.1</p></title>\n",
+        "<p>One <emphasis>two</emphasis></p>\n<p>three</p>\n<subtitle>* * *</subtitle>\n<cite>\n<p><image l:href=\"#images_map.png\"/></p>\n</cite>\n</section>\n",
+        "<section id=\"chapter_1-2\">\n<title><p>Gestation 1.2</p></title>\n<p><a l:href=\"#chapter_1-1\">Back</a></p>\n</section>\n</section>\n",
+        "<section>\n<title><p>Insinuation</p></title>\n<section id=\"chapter_2-1\">\n<title><p>Insinuation 2.1</p></title>\n<empty-line/>\n</section>\n</section>\n",
+        "<section id=\"author_notes\">\n<title><p>Author’s Notes</p></title>\n<p>Thanks</p>\n</section>\n</body>\n",
+        "<binary id=\"cover.jpg\" content-type=\"image/jpeg\">/9g=</binary>\n",
+        "<binary id=\"images_map.png\" content-type=\"image/png\">iVBORw==</binary>\n",
+    )));
+}
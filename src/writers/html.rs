@@ -0,0 +1,258 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use dom_manipulation::{DomOperation, MutableDom};
+use easy_error::{Error, ResultExt};
+use media_type::MediaType;
+use metadata::BookMetadata;
+use scraper::{Html, Selector};
+use std::collections::HashMap;
+use std::path::Path;
+use xml_utils::html_attr_name;
+
+use super::{BookWriter, Page, STYLESHEET};
+
+/// Styles for reading the whole book on a single page in a browser.
+const PAGE_STYLESHEET: &str = "
+        body {
+            max-width: 40em;
+            margin: 0 auto;
+            padding: 1em;
+            line-height: 1.5;
+        }
+        header.title-page {
+            text-align: center;
+        }
+        img.cover {
+            max-width: 100%;
+            max-height: 90vh;
+        }
+        section.page {
+            border-top: 0.1em solid #888;
+            margin-top: 3em;
+        }
+    ";
+
+lazy_static! {
+    static ref ID_SELECTOR: Selector = Selector::parse("[id]").unwrap();
+    static ref LINK_SELECTOR: Selector = Selector::parse("a[href]").unwrap();
+    static ref IMAGE_SELECTOR: Selector = Selector::parse("img[src]").unwrap();
+}
+
+/// Writes the book as a single HTML file, with images inlined as data URIs and a table of contents at the start.
+pub struct HtmlWriter {
+    metadata: BookMetadata,
+    cover: Option<String>,
+    pages: Vec<Page>,
+    /// Data URIs of the resources by their paths.
+    resources: HashMap<String, String>,
+}
+
+fn data_uri(data: &[u8], content_type: &str) -> String {
+    format!("data:{content_type};base64,{}", BASE64.encode(data))
+}
+
+/// Identifier of the section containing the page stored in given file.
+fn page_id(file: &str) -> &str {
+    file.strip_suffix(".xhtml").unwrap_or(file)
+}
+
+impl HtmlWriter {
+    pub fn new(metadata: BookMetadata) -> Self {
+        HtmlWriter {
+            metadata,
+            cover: None,
+            pages: Vec::new(),
+            resources: HashMap::new(),
+        }
+    }
+
+    /// Adapts a page body for living next to the other pages in a single document:
+    /// identifiers are prefixed with the page identifier so they stay unique, links between pages
+    /// point to their sections, and images are inlined.
+    fn page_body(&self, page: &Page) -> String {
+        let id = page_id(&page.file);
+        let mut doc = Html::parse_fragment(&page.body_text);
+        let mut ops = Vec::new();
+
+        for elem in doc.select(&ID_SELECTOR) {
+            ops.push(DomOperation::SetAttribute {
+                node_id: elem.id(),
+                attr_name: html_attr_name("id"),
+                value: format!("{id}-{}", elem.value().attr("id").unwrap()),
+            });
+        }
+
+        for link in doc.select(&LINK_SELECTOR) {
+            let href = link.value().attr("href").unwrap();
+            let (file, fragment) = href.split_once('#').unwrap_or((href, ""));
+            let target = if file.is_empty() {
+                Some(id)
+            } else {
+                self.pages.iter().find(|other| other.file == file).map(|other| page_id(&other.file))
+            };
+            if let Some(target) = target {
+                let value = if fragment.is_empty() { format!("#{target}") } else { format!("#{target}-{fragment}") };
+                ops.push(DomOperation::SetAttribute {
+                    node_id: link.id(),
+                    attr_name: html_attr_name("href"),
+                    value,
+                });
+            }
+        }
+
+        for img in doc.select(&IMAGE_SELECTOR) {
+            if let Some(uri) = self.resources.get(img.value().attr("src").unwrap()) {
+                ops.push(DomOperation::SetAttribute {
+                    node_id: img.id(),
+                    attr_name: html_attr_name("src"),
+                    value: uri.clone(),
+                });
+            }
+        }
+
+        if ops.is_empty() {
+            return page.body_text.clone();
+        }
+        doc.perform_operations(ops);
+        doc.root_element().inner_html()
+    }
+
+    /// Lists the pages, nesting the lower levels in the entries above them.
+    fn table_of_contents(&self) -> String {
+        let mut toc = String::from("<nav id=\"toc\">\n<h2>Contents</h2>\n<ol>\n");
+        let mut level = 1;
+        for (i, page) in self.pages.iter().enumerate() {
+            let page_level = page.level.max(1);
+            while level < page_level {
+                toc.push_str("<ol>\n");
+                level += 1;
+            }
+            while level > page_level {
+                toc.push_str("</li>\n</ol>\n");
+                level -= 1;
+            }
+            if i > 0 && !toc.ends_with("<ol>\n") {
+                toc.push_str("</li>\n");
+            }
+            toc.push_str(&format!("<li><a href=\"#{}\">{}</a>", page_id(&page.file), html_escape::encode_text(&page.title)));
+        }
+        if !self.pages.is_empty() {
+            toc.push_str("</li>\n");
+        }
+        while level > 1 {
+            toc.push_str("</ol>\n</li>\n");
+            level -= 1;
+        }
+        toc.push_str("</ol>\n</nav>\n");
+        toc
+    }
+
+    fn document(&self) -> String {
+        let metadata = &self.metadata;
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html lang=\"{}\">\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<meta name=\"author\" content=\"{}\">\n<meta name=\"description\" content=\"{}\">\n<style>{STYLESHEET}{PAGE_STYLESHEET}</style>\n</head>\n<body>\n",
+            html_escape::encode_double_quoted_attribute(&metadata.language),
+            html_escape::encode_text(&metadata.title),
+            html_escape::encode_double_quoted_attribute(&metadata.author),
+            html_escape::encode_double_quoted_attribute(&metadata.description),
+        );
+
+        html.push_str("<header class=\"title-page\">\n");
+        if let Some(ref cover) = self.cover {
+            html.push_str(&format!("<img class=\"cover\" src=\"{cover}\" alt=\"Cover\">\n"));
+        }
+        html.push_str(&format!("<h1>{}</h1>\n", html_escape::encode_text(&metadata.title)));
+        html.push_str(&format!("<p class=\"author\">{}</p>\n", html_escape::encode_text(&metadata.author)));
+        if !metadata.description.is_empty() {
+            html.push_str(&format!("<p class=\"description\">{}</p>\n", html_escape::encode_text(&metadata.description)));
+        }
+        html.push_str("</header>\n");
+
+        html.push_str(&self.table_of_contents());
+
+        for page in &self.pages {
+            let heading = if page.level > 1 { "h2" } else { "h1" };
+            html.push_str(&format!(
+                "<section class=\"page\" id=\"{}\">\n<{heading}>{}</{heading}>\n{}</section>\n",
+                page_id(&page.file),
+                html_escape::encode_text(&page.title),
+                self.page_body(page),
+            ));
+        }
+
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+}
+
+impl BookWriter for HtmlWriter {
+    fn extension(&self) -> Option<&'static str> {
+        Some("html")
+    }
+
+    fn add_cover(&mut self, data: &[u8], media_type: MediaType) -> Result<(), Error> {
+        self.cover = Some(data_uri(data, media_type.content_type()));
+        Ok(())
+    }
+
+    fn add_page(&mut self, page: Page) -> Result<(), Error> {
+        self.pages.push(page);
+        Ok(())
+    }
+
+    fn add_resource(&mut self, path: &str, data: &[u8], content_type: &str) -> Result<(), Error> {
+        self.resources.insert(path.to_string(), data_uri(data, content_type));
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, path: &Path) -> Result<(), Error> {
+        std::fs::write(path, self.document()).context("Could not write file")?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_html_document() {
+    use writers::PageKind;
+
+    let mut writer = HtmlWriter::new(BookMetadata {
+        title: "Worm".to_string(),
+        author: "Wildbow".to_string(),
+        author_file_as: None,
+        description: "Brockton Bay & more".to_string(),
+        language: "en-US".to_string(),
+        identifier: String::new(),
+        date: "2013-11-19".to_string(),
+        series: None,
+        subjects: vec![],
+        publisher: None,
+        source: None,
+    });
+    let page = |file: &str, title: &str, body_text: &str, level| Page {
+        file: file.to_string(),
+        title: title.to_string(),
+        body_text: body_text.to_string(),
+        kind: PageKind::Chapter,
+        level,
+        arc: None,
+        url: None,
+        fetched_at: None,
+    };
+    writer.add_page(page("chapter_1-1.xhtml", "Gestation 1.1", r#"<p><a href="chapter_1-2.xhtml">Next</a> <img src="images/map.png" alt="Map"></p>"#, 1)).unwrap();
+    writer.add_page(page("chapter_1-2.xhtml", "Gestation 1.2", r##"<p><a href="#endnote-1" id="endnote-ref-1">Link [1]</a></p>"##, 1)).unwrap();
+    writer.add_page(page("comments_1-2.xhtml", "Comments: Gestation 1.2", "<p>First!</p>", 2)).unwrap();
+    writer.add_resource("images/map.png", b"\x89PNG", "image/png").unwrap();
+
+    let html = writer.document();
+    assert!(html.contains("<meta name=\"description\" content=\"Brockton Bay &amp; more\">"));
+    assert!(html.contains(concat!(
+        "<ol>\n<li><a href=\"#chapter_1-1\">Gestation 1.1</a></li>\n",
+        "<li><a href=\"#chapter_1-2\">Gestation 1.2</a><ol>\n<li><a href=\"#comments_1-2\">Comments: Gestation 1.2</a></li>\n</ol>\n</li>\n</ol>\n",
+    )));
+    assert!(html.contains("<section class=\"page\" id=\"chapter_1-1\">\n<h1>Gestation 1.1</h1>\n"));
+    assert!(html.contains("<a href=\"#chapter_1-2\">Next</a>"));
+    assert!(html.contains("src=\"data:image/png;base64,iVBORw==\""));
+    assert!(html.contains("href=\"#chapter_1-2-endnote-1\""));
+    assert!(html.contains("id=\"chapter_1-2-endnote-ref-1\""));
+    assert!(html.contains("<section class=\"page\" id=\"comments_1-2\">\n<h2>Comments: Gestation 1.2</h2>\n<p>First!</p>"));
+}
@@ -0,0 +1,191 @@
+use easy_error::{Error, ResultExt};
+use media_type::MediaType;
+use metadata::BookMetadata;
+use serde::Serialize;
+use std::path::Path;
+
+use super::blocks::{image_sources, parse_blocks};
+use super::text::{text_body, TextOptions};
+use super::{BookWriter, Page, PageKind};
+
+/// Writes the pages as JSON for tooling: either a single document with the book metadata and an array of pages,
+/// or JSON Lines with an object per page.
+pub struct JsonWriter {
+    metadata: BookMetadata,
+    lines: bool,
+    pages: Vec<Page>,
+}
+
+#[derive(Serialize)]
+struct BookRecord<'a> {
+    title: &'a str,
+    author: &'a str,
+    description: &'a str,
+    language: &'a str,
+    identifier: &'a str,
+    date: &'a str,
+    source: Option<&'a str>,
+    pages: Vec<PageRecord<'a>>,
+}
+
+#[derive(Serialize)]
+struct PageRecord<'a> {
+    index: usize,
+    title: &'a str,
+    arc: Option<&'a str>,
+    kind: &'static str,
+    file: &'a str,
+    source_url: Option<&'a str>,
+    fetched_at: Option<String>,
+    word_count: usize,
+    images: Vec<String>,
+    xhtml: &'a str,
+    text: String,
+}
+
+/// Counts the words in plain text, ignoring lone punctuation like scene-break markers and `[Image: ...]`
+/// placeholders.
+fn word_count(text: &str) -> usize {
+    let mut in_image = false;
+    let mut count = 0;
+    for word in text.split_whitespace() {
+        if word.starts_with("[Image") {
+            in_image = true;
+        }
+        if !in_image && word.chars().any(char::is_alphanumeric) {
+            count += 1;
+        }
+        if in_image && word.ends_with(']') {
+            in_image = false;
+        }
+    }
+    count
+}
+
+fn page_record(page: &Page, index: usize) -> PageRecord<'_> {
+    let text = text_body(&page.body_text, &TextOptions::default());
+    PageRecord {
+        index,
+        title: &page.title,
+        arc: page.arc.as_deref(),
+        kind: match page.kind {
+            PageKind::Chapter => "chapter",
+            PageKind::Notes => "notes",
+        },
+        file: &page.file,
+        source_url: page.url.as_ref().map(|url| url.as_str()),
+        fetched_at: page.fetched_at.map(|time| time.to_rfc3339()),
+        word_count: word_count(&text),
+        images: image_sources(&parse_blocks(&page.body_text)),
+        xhtml: &page.body_text,
+        text,
+    }
+}
+
+impl JsonWriter {
+    pub fn new(metadata: BookMetadata, lines: bool) -> Self {
+        JsonWriter { metadata, lines, pages: Vec::new() }
+    }
+
+    fn document(&self) -> Result<String, Error> {
+        let pages = self.pages.iter().enumerate().map(|(i, page)| page_record(page, i + 1));
+        if self.lines {
+            let mut document = String::new();
+            for page in pages {
+                document.push_str(&serde_json::to_string(&page).context("Could not encode page")?);
+                document.push('\n');
+            }
+            return Ok(document);
+        }
+
+        let metadata = &self.metadata;
+        let book = BookRecord {
+            title: &metadata.title,
+            author: &metadata.author,
+            description: &metadata.description,
+            language: &metadata.language,
+            identifier: &metadata.identifier,
+            date: &metadata.date,
+            source: metadata.source.as_deref(),
+            pages: pages.collect(),
+        };
+        Ok(serde_json::to_string_pretty(&book).context("Could not encode book")? + "\n")
+    }
+}
+
+impl BookWriter for JsonWriter {
+    fn extension(&self) -> Option<&'static str> {
+        Some(if self.lines { "jsonl" } else { "json" })
+    }
+
+    fn add_cover(&mut self, _data: &[u8], _media_type: MediaType) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn add_page(&mut self, page: Page) -> Result<(), Error> {
+        self.pages.push(page);
+        Ok(())
+    }
+
+    fn add_resource(&mut self, _path: &str, _data: &[u8], _content_type: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, path: &Path) -> Result<(), Error> {
+        std::fs::write(path, self.document()?).context("Could not write file")?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_json_document() {
+    use chrono::{TimeZone, Utc};
+    use reqwest::Url;
+
+    let metadata = BookMetadata {
+        title: "Worm".to_string(),
+        author: "Wildbow".to_string(),
+        author_file_as: None,
+        description: "Brockton \"Bay\"".to_string(),
+        language: "en-US".to_string(),
+        identifier: "urn:uuid:1".to_string(),
+        date: "2013-11-19".to_string(),
+        series: None,
+        subjects: vec![],
+        publisher: None,
+        source: None,
+    };
+    let page = Page {
+        file: "chapter_1-1.xhtml".to_string(),
+        title: "Gestation 1.1".to_string(),
+        body_text: "<p>Brief note.</p>\n<p class=\"center\">■</p>\n<p><img src=\"images/map.png\" alt=\"\"/></p>".to_string(),
+        kind: PageKind::Chapter,
+        level: 1,
+        arc: Some("Gestation".to_string()),
+        url: Some(Url::parse("https://parahumans.wordpress.com/2011/06/11/1-1/").unwrap()),
+        fetched_at: Some(Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap()),
+    };
+    let expected_page = concat!(
+        r#"{"index":1,"title":"Gestation 1.1","arc":"Gestation","kind":"chapter","file":"chapter_1-1.xhtml","#,
+        r#""source_url":"https://parahumans.wordpress.com/2011/06/11/1-1/","fetched_at":"2024-05-01T12:00:00+00:00","#,
+        r#""word_count":2,"images":["images/map.png"],"#,
+        r#""xhtml":"<p>Brief note.</p>\n<p class=\"center\">■</p>\n<p><img src=\"images/map.png\" alt=\"\"/></p>","#,
+        r#""text":"Brief note.\n\n* * *\n\n[Image]"}"#,
+    );
+
+    let mut lines = JsonWriter::new(metadata.clone(), true);
+    lines.add_page(page.clone()).unwrap();
+    lines.add_page(page.clone()).unwrap();
+    let document = lines.document().unwrap();
+    let lines: Vec<&str> = document.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], expected_page);
+
+    let mut json = JsonWriter::new(metadata, false);
+    json.add_page(page).unwrap();
+    let document: serde_json::Value = serde_json::from_str(&json.document().unwrap()).unwrap();
+    let expected_book = format!(
+        r#"{{"title":"Worm","author":"Wildbow","description":"Brockton \"Bay\"","language":"en-US","identifier":"urn:uuid:1","date":"2013-11-19","source":null,"pages":[{expected_page}]}}"#,
+    );
+    assert_eq!(document, serde_json::from_str::<serde_json::Value>(&expected_book).unwrap());
+}
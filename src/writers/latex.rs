@@ -0,0 +1,339 @@
+use easy_error::{Error, ResultExt};
+use media_type::MediaType;
+use metadata::BookMetadata;
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::blocks::{parse_blocks, Align, Block, Inline};
+use super::{png_image, BookWriter, Page, PageKind};
+
+/// Preamble of the book, for a 6×9 inch trade paperback. Needs LuaLaTeX or XeLaTeX for the Unicode text.
+const PREAMBLE: &str = r"\documentclass[11pt,openany]{book}
+\usepackage[paperwidth=6in,paperheight=9in,margin=0.75in]{geometry}
+\usepackage{fontspec}
+\usepackage{graphicx}
+\usepackage[normalem]{ulem}
+\usepackage[hidelinks]{hyperref}
+% Chapter titles already carry their numbers.
+\setcounter{secnumdepth}{-2}
+\newenvironment{indented}{\begin{list}{}{\leftmargin=2em\rightmargin=0pt}\item[]}{\end{list}}
+\newenvironment{authornote}{\begin{quote}\itshape}{\end{quote}}
+\newcommand{\scenebreak}{\par\bigskip{\centering *\quad*\quad*\par}\bigskip}
+\newcommand{\bookimage}[1]{\includegraphics[width=\linewidth,height=0.8\textheight,keepaspectratio]{#1}}
+";
+
+/// Writes the book as LaTeX source, in a directory with `book.tex` and the images, ready to be typeset
+/// into a PDF with `lualatex book.tex`. Arcs become parts, the pages chapters.
+pub struct LatexWriter {
+    metadata: BookMetadata,
+    cover: Option<(Vec<u8>, MediaType)>,
+    pages: Vec<Page>,
+    images: Vec<(String, Vec<u8>, Option<MediaType>)>,
+}
+
+/// Escapes the characters with a special meaning in LaTeX.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str(r"\textbackslash{}"),
+            '{' | '}' | '$' | '&' | '#' | '_' | '%' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '~' => escaped.push_str(r"\textasciitilde{}"),
+            '^' => escaped.push_str(r"\textasciicircum{}"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escapes an address for `\href`.
+fn escape_url(url: &str) -> String {
+    url.replace('\\', "/").replace('#', r"\#").replace('%', r"\%").replace('{', "%7B").replace('}', "%7D")
+}
+
+/// Label of the chapter containing the page stored in given file.
+fn label(file: &str) -> String {
+    file.strip_suffix(".xhtml").unwrap_or(file).to_string()
+}
+
+/// Drops line breaks at the start and end of a paragraph and merges runs of them,
+/// since `\newline` without a line before it stops LaTeX with “There's no line here to end”.
+fn trim_line_breaks(inlines: &[Inline]) -> Vec<Inline> {
+    let mut trimmed = Vec::new();
+    let mut has_line = false;
+    let mut pending_break = false;
+    for inline in inlines {
+        match inline {
+            Inline::LineBreak => pending_break = has_line,
+            // Spaces around the breaks would only start the next line.
+            Inline::Text(t) if t.trim().is_empty() && (pending_break || !has_line) => {}
+            inline => {
+                if pending_break {
+                    trimmed.push(Inline::LineBreak);
+                    pending_break = false;
+                }
+                trimmed.push(inline.clone());
+                has_line = true;
+            }
+        }
+    }
+    trimmed
+}
+
+/// Builds LaTeX markup from the blocks of a page.
+struct LatexRenderer<'a> {
+    /// File names of the images, by their paths in the pages.
+    images: &'a HashMap<String, String>,
+    output: String,
+}
+
+impl LatexRenderer<'_> {
+    fn inlines(&self, inlines: &[Inline]) -> String {
+        let mut markup = String::new();
+        for inline in inlines {
+            match inline {
+                Inline::Text(t) => markup.push_str(&escape(t)),
+                Inline::Emphasis(children) => markup.push_str(&format!(r"\emph{{{}}}", self.inlines(children))),
+                Inline::Strong(children) => markup.push_str(&format!(r"\textbf{{{}}}", self.inlines(children))),
+                Inline::Strikethrough(children) => markup.push_str(&format!(r"\sout{{{}}}", self.inlines(children))),
+                Inline::Link { href, children } => {
+                    let (file, fragment) = href.split_once('#').unwrap_or((href, ""));
+                    if file.contains(':') {
+                        markup.push_str(&format!(r"\href{{{}}}{{{}}}", escape_url(href), self.inlines(children)));
+                    } else if file.ends_with(".xhtml") && fragment.is_empty() {
+                        markup.push_str(&format!(r"\hyperref[{}]{{{}}}", label(file), self.inlines(children)));
+                    } else {
+                        // Identifiers inside the pages are not kept.
+                        markup.push_str(&self.inlines(children));
+                    }
+                }
+                Inline::Image { src, alt } => match self.images.get(src) {
+                    Some(file) => markup.push_str(&format!(r"\bookimage{{{file}}}")),
+                    None => markup.push_str(&escape(alt)),
+                },
+                Inline::LineBreak => markup.push_str("\\newline\n"),
+            }
+        }
+        markup
+    }
+
+    /// Markup of the contents of a paragraph-like block.
+    fn paragraph_inlines(&self, inlines: &[Inline]) -> String {
+        self.inlines(&trim_line_breaks(inlines))
+    }
+
+    fn environment(&mut self, name: &str, content: &str) {
+        self.output.push_str(&format!("\\begin{{{name}}}\n{content}\n\\end{{{name}}}\n\n"));
+    }
+
+    fn blocks(&mut self, blocks: &[Block]) {
+        for block in blocks {
+            match block {
+                Block::Paragraph { inlines, indent, align } => {
+                    let mut paragraph = self.paragraph_inlines(inlines);
+                    match align {
+                        Align::Left => {}
+                        Align::Center => paragraph = format!("\\begin{{center}}\n{paragraph}\n\\end{{center}}"),
+                        Align::Right => paragraph = format!("\\begin{{flushright}}\n{paragraph}\n\\end{{flushright}}"),
+                    }
+                    for _ in 0..*indent {
+                        paragraph = format!("\\begin{{indented}}\n{paragraph}\n\\end{{indented}}");
+                    }
+                    self.output.push_str(&paragraph);
+                    self.output.push_str("\n\n");
+                }
+                Block::SceneBreak => self.output.push_str("\\scenebreak\n\n"),
+                Block::Heading { inlines, .. } => self.output.push_str(&format!("\\section*{{{}}}\n\n", self.paragraph_inlines(inlines))),
+                Block::Figure { src, alt, caption } => {
+                    let mut figure = match self.images.get(src) {
+                        Some(file) => format!(r"\bookimage{{{file}}}"),
+                        None => escape(alt),
+                    };
+                    if !caption.is_empty() {
+                        figure.push_str(&format!("\\\\\n\\emph{{{}}}", self.paragraph_inlines(caption)));
+                    }
+                    self.environment("center", &figure);
+                }
+                Block::List { ordered, items } => {
+                    let name = if *ordered { "enumerate" } else { "itemize" };
+                    self.output.push_str(&format!("\\begin{{{name}}}\n"));
+                    for item in items {
+                        self.output.push_str("\\item ");
+                        self.blocks(item);
+                    }
+                    self.output.push_str(&format!("\\end{{{name}}}\n\n"));
+                }
+                Block::Section { class, blocks } => {
+                    let quoted = class.split_whitespace().any(|c| c == "author-note");
+                    if quoted {
+                        self.output.push_str("\\begin{authornote}\n");
+                    }
+                    self.blocks(blocks);
+                    if quoted {
+                        self.output.push_str("\\end{authornote}\n\n");
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl LatexWriter {
+    pub fn new(metadata: BookMetadata) -> Self {
+        LatexWriter {
+            metadata,
+            cover: None,
+            pages: Vec::new(),
+            images: Vec::new(),
+        }
+    }
+
+    /// Converts the images to types LaTeX can include, and names them after their position in the book
+    /// since their original paths may contain characters LaTeX trips over.
+    fn image_files(&self) -> Vec<(String, String, Vec<u8>)> {
+        let mut files = Vec::new();
+        for (path, data, media_type) in &self.images {
+            let image = match media_type {
+                Some(kind @ MediaType::Jpeg) | Some(kind @ MediaType::Png) => Some((data.clone(), *kind)),
+                _ => png_image(data, *media_type).map(|png| (png, MediaType::Png)),
+            };
+            if let Some((data, media_type)) = image {
+                files.push((path.clone(), format!("images/image-{}.{}", files.len() + 1, media_type.extension()), data));
+            }
+        }
+        files
+    }
+
+    fn cover_file(&self) -> Option<(String, Vec<u8>)> {
+        let (data, media_type) = self.cover.as_ref()?;
+        match media_type {
+            MediaType::Jpeg | MediaType::Png => Some((format!("cover.{}", media_type.extension()), data.clone())),
+            _ => png_image(data, Some(*media_type)).map(|png| ("cover.png".to_string(), png)),
+        }
+    }
+
+    fn document(&self, images: &HashMap<String, String>, cover: Option<&str>) -> String {
+        let metadata = &self.metadata;
+        let mut tex = String::from(PREAMBLE);
+        tex.push_str(&format!(
+            "\\hypersetup{{pdftitle={{{0}}}, pdfauthor={{{1}}}}}\n\\title{{{0}}}\n\\author{{{1}}}\n\\date{{}}\n\n\\begin{{document}}\n\n\\frontmatter\n",
+            escape(&metadata.title),
+            escape(&metadata.author),
+        ));
+        if let Some(cover) = cover {
+            tex.push_str(&format!("\\begin{{titlepage}}\n\\centering\n\\includegraphics[width=\\textwidth,height=\\textheight,keepaspectratio]{{{cover}}}\n\\end{{titlepage}}\n"));
+        }
+        tex.push_str("\\maketitle\n\\tableofcontents\n\n\\mainmatter\n\n");
+
+        let mut renderer = LatexRenderer { images, output: String::new() };
+        let mut current_arc: Option<&str> = None;
+        let mut in_back_matter = false;
+        for page in &self.pages {
+            match page.kind {
+                PageKind::Chapter => {
+                    if let Some(arc) = page.arc.as_deref().filter(|arc| Some(*arc) != current_arc) {
+                        renderer.output.push_str(&format!("\\part{{{}}}\n\n", escape(arc)));
+                        current_arc = Some(arc);
+                    }
+                }
+                PageKind::Notes if !in_back_matter => {
+                    renderer.output.push_str("\\backmatter\n\n");
+                    in_back_matter = true;
+                }
+                PageKind::Notes => {}
+            }
+            let command = if page.level > 1 { "section" } else { "chapter" };
+            renderer.output.push_str(&format!("\\{command}{{{}}}\\label{{{}}}\n\n", escape(&page.title), label(&page.file)));
+            renderer.blocks(&parse_blocks(&page.body_text));
+        }
+        tex.push_str(&renderer.output);
+        tex.push_str("\\end{document}\n");
+        tex
+    }
+}
+
+impl BookWriter for LatexWriter {
+    fn extension(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn add_cover(&mut self, data: &[u8], media_type: MediaType) -> Result<(), Error> {
+        self.cover = Some((data.to_vec(), media_type));
+        Ok(())
+    }
+
+    fn add_page(&mut self, page: Page) -> Result<(), Error> {
+        self.pages.push(page);
+        Ok(())
+    }
+
+    fn add_resource(&mut self, path: &str, data: &[u8], content_type: &str) -> Result<(), Error> {
+        self.images.push((path.to_string(), data.to_vec(), MediaType::from_content_type(content_type)));
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, path: &Path) -> Result<(), Error> {
+        std::fs::create_dir_all(path.join("images")).context(format!("Could not create directory {}", path.display()))?;
+        let image_files = self.image_files();
+        for (_, file, data) in &image_files {
+            std::fs::write(path.join(file), data).context(format!("Could not write image {file}"))?;
+        }
+        let cover = self.cover_file();
+        if let Some((ref file, ref data)) = cover {
+            std::fs::write(path.join(file), data).context("Could not write cover")?;
+        }
+        let images = image_files.into_iter().map(|(path, file, _)| (path, file)).collect();
+        let tex = self.document(&images, cover.as_ref().map(|(file, _)| file.as_str()));
+        std::fs::write(path.join("book.tex"), tex).context("Could not write file")?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_latex_document() {
+    let mut writer = LatexWriter::new(BookMetadata {
+        title: "Worm".to_string(),
+        author: "Wildbow".to_string(),
+        author_file_as: None,
+        description: String::new(),
+        language: "en-US".to_string(),
+        identifier: String::new(),
+        date: "2013-11-19".to_string(),
+        series: None,
+        subjects: vec![],
+        publisher: None,
+        source: None,
+    });
+    let page = |file: &str, title: &str, arc: Option<&str>, body_text: &str, kind| Page {
+        file: file.to_string(),
+        title: title.to_string(),
+        body_text: body_text.to_string(),
+        kind,
+        level: 1,
+        arc: arc.map(str::to_string),
+        url: None,
+        fetched_at: None,
+    };
+    writer.add_page(page("chapter_1-1.xhtml", "Gestation 1.1", Some("Gestation"), "<p>50% of <em>capes</em> &amp; #1_fan</p><p class=\"center\">■</p><p class=\"indent-one\">Indented</p>", PageKind::Chapter)).unwrap();
+    writer.add_page(page("chapter_1-2.xhtml", "Gestation 1.2", Some("Gestation"), "<figure><img src=\"images/a b.png\" alt=\"Map\"><figcaption>The city</figcaption></figure>", PageKind::Chapter)).unwrap();
+    writer.add_page(page("chapter_2-1.xhtml", "Insinuation 2.1", Some("Insinuation"), "<p><a href=\"chapter_1-1.xhtml\">Back</a> <a href=\"https://example.com/#top\">site</a></p>", PageKind::Chapter)).unwrap();
+    writer.add_page(page("chapter_2-2.xhtml", "Insinuation 2.2", Some("Insinuation"), "<p><br> <br>Dear <em>diary</em>,<br><br/>\n<br>Me<br></p>", PageKind::Chapter)).unwrap();
+    writer.add_page(page("author_notes.xhtml", "Author’s Notes", None, "<aside class=\"author-note\"><p>Thanks</p></aside>", PageKind::Notes)).unwrap();
+
+    let images = vec![("images/a b.png".to_string(), "images/image-1.png".to_string())].into_iter().collect();
+    let tex = writer.document(&images, None);
+    assert!(tex.starts_with(r"\documentclass"));
+    assert!(tex.contains(concat!(
+        "\\mainmatter\n\n\\part{Gestation}\n\n\\chapter{Gestation 1.1}\\label{chapter_1-1}\n\n",
+        "50\\% of \\emph{capes} \\& \\#1\\_fan\n\n\\scenebreak\n\n\\begin{indented}\nIndented\n\\end{indented}\n\n",
+        "\\chapter{Gestation 1.2}\\label{chapter_1-2}\n\n\\begin{center}\n\\bookimage{images/image-1.png}\\\\\n\\emph{The city}\n\\end{center}\n\n",
+        "\\part{Insinuation}\n\n\\chapter{Insinuation 2.1}\\label{chapter_2-1}\n\n",
+        "\\hyperref[chapter_1-1]{Back} \\href{https://example.com/\\#top}{site}\n\n",
+        "\\chapter{Insinuation 2.2}\\label{chapter_2-2}\n\nDear \\emph{diary},\\newline\nMe\n\n",
+        "\\backmatter\n\n\\chapter{Author’s Notes}\\label{author_notes}\n\n\\begin{authornote}\nThanks\n\n\\end{authornote}\n\n",
+        "\\end{document}\n",
+    )));
+}
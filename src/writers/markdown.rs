@@ -0,0 +1,401 @@
+use easy_error::{Error, ResultExt};
+use media_type::MediaType;
+use metadata::BookMetadata;
+use std::path::Path;
+
+use super::blocks::{parse_blocks, Block, Inline};
+use super::{BookWriter, Page};
+
+/// Writes the book as a directory with a Markdown file per page, an `index.md` listing them,
+/// and the images in `images/`. Files are overwritten in place, so the directory can be a git checkout,
+/// and those of an earlier build the book no longer has are removed.
+pub struct MarkdownWriter {
+    metadata: BookMetadata,
+    cover: Option<(Vec<u8>, MediaType)>,
+    pages: Vec<Page>,
+    resources: Vec<(String, Vec<u8>)>,
+}
+
+/// File listing the files written to the directory, so that a rebuild can remove the stale ones
+/// without touching files added by the user.
+const MANIFEST_FILE: &str = ".generated-files";
+
+/// Name of the Markdown file for the page stored in given XHTML file.
+fn markdown_file(file: &str) -> String {
+    format!("{}.md", file.strip_suffix(".xhtml").unwrap_or(file))
+}
+
+/// Quotes a string for YAML front matter. JSON strings are valid double-quoted YAML scalars.
+fn yaml_string(value: &str) -> String {
+    serde_json::to_string(value).expect("strings can always be encoded")
+}
+
+fn front_matter(fields: &[(&str, String)]) -> String {
+    let mut text = String::from("---\n");
+    for (name, value) in fields {
+        text.push_str(&format!("{name}: {value}\n"));
+    }
+    text.push_str("---\n\n");
+    text
+}
+
+/// Escapes the characters Markdown would take for formatting.
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '[' | ']' | '`' | '<' | '~') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escapes the start of a line that would otherwise become a heading, quote, list item or rule.
+fn escape_line_start(line: &str) -> String {
+    let mut chars = line.chars();
+    match chars.next() {
+        Some('#') | Some('>') => format!("\\{line}"),
+        Some(c @ ('-' | '+' | '=')) if matches!(chars.next(), None | Some(' ')) || line.starts_with(&format!("{c}{c}")) => format!("\\{line}"),
+        Some(c) if c.is_ascii_digit() => {
+            let digits = line.chars().take_while(char::is_ascii_digit).count();
+            let rest = &line[digits..];
+            if (rest.starts_with('.') || rest.starts_with(')')) && matches!(rest[1..].chars().next(), None | Some(' ')) {
+                format!("{}\\{rest}", &line[..digits])
+            } else {
+                line.to_string()
+            }
+        }
+        _ => line.to_string(),
+    }
+}
+
+/// Link destination, in angle brackets when it contains characters ending it early.
+fn destination(url: &str) -> String {
+    if url.contains(|c: char| c.is_whitespace() || c == '(' || c == ')') {
+        format!("<{}>", url.replace('<', "%3C").replace('>', "%3E"))
+    } else {
+        url.to_string()
+    }
+}
+
+/// Points links between pages to their Markdown files.
+fn rewrite_href(href: &str) -> String {
+    let (file, fragment) = href.split_once('#').unwrap_or((href, ""));
+    if !file.ends_with(".xhtml") || file.contains(':') {
+        return href.to_string();
+    }
+    let file = markdown_file(file);
+    if fragment.is_empty() {
+        file
+    } else {
+        format!("{file}#{fragment}")
+    }
+}
+
+/// Wraps the rendered inlines in a delimiter, keeping surrounding spaces outside as Markdown requires.
+fn delimited(inner: &str, delimiter: &str) -> String {
+    let trimmed = inner.trim();
+    if trimmed.is_empty() {
+        return inner.to_string();
+    }
+    let leading = &inner[..inner.len() - inner.trim_start().len()];
+    let trailing = &inner[inner.trim_end().len()..];
+    format!("{leading}{delimiter}{trimmed}{delimiter}{trailing}")
+}
+
+fn render_inlines(inlines: &[Inline]) -> String {
+    let mut text = String::new();
+    for inline in inlines {
+        match inline {
+            Inline::Text(t) => text.push_str(&escape_text(t)),
+            Inline::Emphasis(children) => text.push_str(&delimited(&render_inlines(children), "*")),
+            Inline::Strong(children) => text.push_str(&delimited(&render_inlines(children), "**")),
+            Inline::Strikethrough(children) => text.push_str(&delimited(&render_inlines(children), "~~")),
+            Inline::Link { href, children } => {
+                text.push_str(&format!("[{}]({})", render_inlines(children), destination(&rewrite_href(href))));
+            }
+            Inline::Image { src, alt } => text.push_str(&format!("![{}]({})", escape_text(alt), destination(src))),
+            Inline::LineBreak => text.push_str("\\\n"),
+        }
+    }
+    text
+}
+
+/// Renders inlines as the lines of a paragraph.
+fn render_paragraph(inlines: &[Inline]) -> String {
+    render_inlines(inlines).lines().map(|line| escape_line_start(line.trim_start())).collect::<Vec<_>>().join("\n")
+}
+
+/// Prepends `first` to the first line of the text and `rest` to the following ones.
+fn prefix_lines(text: &str, first: &str, rest: &str) -> String {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let prefix = if i == 0 { first } else { rest };
+            if line.is_empty() {
+                prefix.trim_end().to_string()
+            } else {
+                format!("{prefix}{line}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn render_block(block: &Block) -> String {
+    match block {
+        Block::Paragraph { inlines, indent, .. } => {
+            // Markdown has no indentation or alignment, indented passages are quoted instead.
+            let quote = "> ".repeat(*indent);
+            prefix_lines(&render_paragraph(inlines), &quote, &quote)
+        }
+        Block::SceneBreak => "* * *".to_string(),
+        Block::Heading { level, inlines } => format!("{} {}", "#".repeat(*level), render_inlines(inlines).replace("\\\n", " ")),
+        Block::Figure { src, alt, caption } => {
+            let mut text = format!("![{}]({})", escape_text(alt), destination(src));
+            if !caption.is_empty() {
+                text.push_str("\n\n");
+                text.push_str(&render_paragraph(&[Inline::Emphasis(caption.clone())]));
+            }
+            text
+        }
+        Block::List { ordered, items } => items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| {
+                let marker = if *ordered { format!("{}. ", i + 1) } else { "- ".to_string() };
+                let content = render_blocks(item);
+                if content.is_empty() {
+                    marker.trim_end().to_string()
+                } else {
+                    prefix_lines(&content, &marker, &" ".repeat(marker.len()))
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Block::Section { class, blocks } => {
+            let content = render_blocks(blocks);
+            if class.split_whitespace().any(|c| c == "author-note") {
+                prefix_lines(&content, "> ", "> ")
+            } else {
+                content
+            }
+        }
+    }
+}
+
+fn render_blocks(blocks: &[Block]) -> String {
+    blocks.iter().map(render_block).filter(|text| !text.is_empty()).collect::<Vec<_>>().join("\n\n")
+}
+
+/// Converts the XHTML body of a page to Markdown.
+fn markdown_body(body_text: &str) -> String {
+    render_blocks(&parse_blocks(body_text))
+}
+
+impl MarkdownWriter {
+    pub fn new(metadata: BookMetadata) -> Self {
+        MarkdownWriter {
+            metadata,
+            cover: None,
+            pages: Vec::new(),
+            resources: Vec::new(),
+        }
+    }
+
+    fn page_document(page: &Page, index: usize) -> String {
+        let mut fields = vec![("title", yaml_string(&page.title))];
+        if let Some(ref arc) = page.arc {
+            fields.push(("arc", yaml_string(arc)));
+        }
+        if let Some(ref url) = page.url {
+            fields.push(("source", yaml_string(url.as_str())));
+        }
+        fields.push(("index", index.to_string()));
+
+        let mut text = front_matter(&fields);
+        text.push_str(&format!("# {}\n\n", render_inlines(&[Inline::Text(page.title.clone())])));
+        let body = markdown_body(&page.body_text);
+        if !body.is_empty() {
+            text.push_str(&body);
+            text.push('\n');
+        }
+        text
+    }
+
+    fn index_document(&self) -> String {
+        let metadata = &self.metadata;
+        let mut fields = vec![
+            ("title", yaml_string(&metadata.title)),
+            ("author", yaml_string(&metadata.author)),
+            ("language", yaml_string(&metadata.language)),
+            ("date", yaml_string(&metadata.date)),
+            ("identifier", yaml_string(&metadata.identifier)),
+        ];
+        if let Some(ref source) = metadata.source {
+            fields.push(("source", yaml_string(source)));
+        }
+
+        let mut text = front_matter(&fields);
+        text.push_str(&format!("# {}\n\n", escape_text(&metadata.title)));
+        if let Some((_, media_type)) = self.cover {
+            text.push_str(&format!("![Cover](cover.{})\n\n", media_type.extension()));
+        }
+        if !metadata.description.is_empty() {
+            text.push_str(&format!("{}\n\n", render_paragraph(&[Inline::Text(metadata.description.clone())])));
+        }
+        text.push_str("## Contents\n\n");
+        for page in &self.pages {
+            text.push_str(&format!(
+                "{}- [{}]({})\n",
+                "  ".repeat(page.level.max(1) - 1),
+                escape_text(&page.title),
+                destination(&markdown_file(&page.file)),
+            ));
+        }
+        text
+    }
+}
+
+impl BookWriter for MarkdownWriter {
+    fn extension(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn add_cover(&mut self, data: &[u8], media_type: MediaType) -> Result<(), Error> {
+        self.cover = Some((data.to_vec(), media_type));
+        Ok(())
+    }
+
+    fn add_page(&mut self, page: Page) -> Result<(), Error> {
+        self.pages.push(page);
+        Ok(())
+    }
+
+    fn add_resource(&mut self, path: &str, data: &[u8], _content_type: &str) -> Result<(), Error> {
+        self.resources.push((path.to_string(), data.to_vec()));
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, path: &Path) -> Result<(), Error> {
+        std::fs::create_dir_all(path).context(format!("Could not create directory {}", path.display()))?;
+        let mut written = Vec::new();
+        let mut write = |name: &str, data: &[u8]| -> Result<(), Error> {
+            let file = path.join(name);
+            if let Some(parent) = file.parent() {
+                std::fs::create_dir_all(parent).context(format!("Could not create directory {}", parent.display()))?;
+            }
+            std::fs::write(&file, data).context(format!("Could not write file {}", file.display()))?;
+            written.push(name.to_string());
+            Ok(())
+        };
+
+        if let Some((ref data, media_type)) = self.cover {
+            write(&format!("cover.{}", media_type.extension()), data)?;
+        }
+        for (i, page) in self.pages.iter().enumerate() {
+            write(&markdown_file(&page.file), Self::page_document(page, i + 1).as_bytes())?;
+        }
+        for (name, data) in &self.resources {
+            write(name, data)?;
+        }
+        write("index.md", self.index_document().as_bytes())?;
+
+        let manifest = path.join(MANIFEST_FILE);
+        let previous = std::fs::read_to_string(&manifest).unwrap_or_default();
+        for name in previous.lines() {
+            // Only relative paths inside the directory are ever listed.
+            let inside = !name.is_empty() && !name.starts_with('/') && !name.split('/').any(|part| part == "..");
+            let file = path.join(name);
+            if inside && !written.iter().any(|written| written == name) && file.is_file() {
+                std::fs::remove_file(&file).context(format!("Could not remove stale file {}", file.display()))?;
+            }
+        }
+        written.sort();
+        std::fs::write(&manifest, written.join("\n") + "\n").context(format!("Could not write file {}", manifest.display()))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_markdown_body() {
+    assert_eq!(
+        markdown_body(concat!(
+            "<p>It was <em>a dark </em>and <strong>stormy</strong> night_.</p>\n",
+            "<p class=\"center\">■</p>\n",
+            "<p class=\"indent-one\">- Indented<br/>1. twice</p>\n",
+            "<p><a href=\"chapter_1-2.xhtml#endnote-1\">Next</a> <a href=\"https://example.com/a b\">site</a></p>\n",
+            "<figure><img src=\"images/map.png\" alt=\"Map\"/><figcaption>The city</figcaption></figure>\n",
+            "<aside class=\"author-note\">\n<p>Thanks.</p>\n<p>Really.</p>\n</aside>\n",
+            "<ol class=\"comments\">\n<li><p>First</p><ol class=\"comments\"><li><p>Reply</p></li></ol></li>\n</ol>\n",
+        )),
+        concat!(
+            "It was *a dark* and **stormy** night\\_.\n\n",
+            "* * *\n\n",
+            "> \\- Indented\\\n> 1\\. twice\n\n",
+            "[Next](chapter_1-2.md#endnote-1) [site](<https://example.com/a b>)\n\n",
+            "![Map](images/map.png)\n\n*The city*\n\n",
+            "> Thanks.\n>\n> Really.\n\n",
+            "1. First\n\n   1. Reply",
+        ),
+    );
+}
+
+#[test]
+fn test_markdown_directory() {
+    use reqwest::Url;
+    use writers::PageKind;
+
+    let dir = std::env::temp_dir().join(format!("markdown-writer-{}", std::process::id()));
+    let metadata = BookMetadata {
+        title: "Worm".to_string(),
+        author: "Wildbow".to_string(),
+        author_file_as: None,
+        description: "Brockton Bay".to_string(),
+        language: "en-US".to_string(),
+        identifier: "urn:uuid:1".to_string(),
+        date: "2013-11-19".to_string(),
+        series: None,
+        subjects: vec![],
+        publisher: None,
+        source: Some("https://parahumans.wordpress.com/".to_string()),
+    };
+    let page = |file: &str, title: &str| Page {
+        file: file.to_string(),
+        title: title.to_string(),
+        body_text: "<p>Brief note from the author.</p>".to_string(),
+        kind: PageKind::Chapter,
+        level: 1,
+        arc: Some("Gestation \"1\"".to_string()),
+        url: Some(Url::parse("https://parahumans.wordpress.com/2011/06/11/1-1/").unwrap()),
+        fetched_at: None,
+    };
+    let mut writer = Box::new(MarkdownWriter::new(metadata.clone()));
+    writer.add_page(page("chapter_gestation-1-1.xhtml", "Gestation 1.1")).unwrap();
+    writer.add_resource("images/2011/06/map.png", b"\x89PNG", "image/png").unwrap();
+    writer.finish(&dir).unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(dir.join("chapter_gestation-1-1.md")).unwrap(),
+        concat!(
+            "---\ntitle: \"Gestation 1.1\"\narc: \"Gestation \\\"1\\\"\"\n",
+            "source: \"https://parahumans.wordpress.com/2011/06/11/1-1/\"\nindex: 1\n---\n\n",
+            "# Gestation 1.1\n\nBrief note from the author.\n",
+        ),
+    );
+    let index = std::fs::read_to_string(dir.join("index.md")).unwrap();
+    assert!(index.contains("## Contents\n\n- [Gestation 1.1](chapter_gestation-1-1.md)\n"));
+    assert_eq!(std::fs::read(dir.join("images/2011/06/map.png")).unwrap(), b"\x89PNG");
+
+    // Rebuilding removes the files of the chapters and images that are gone, but not those of the user.
+    std::fs::write(dir.join("notes.md"), "Mine.").unwrap();
+    let mut writer = Box::new(MarkdownWriter::new(metadata));
+    writer.add_page(page("chapter_gestation-1-2.xhtml", "Gestation 1.2")).unwrap();
+    writer.finish(&dir).unwrap();
+    assert!(!dir.join("chapter_gestation-1-1.md").exists());
+    assert!(!dir.join("images/2011/06/map.png").exists());
+    assert!(dir.join("chapter_gestation-1-2.md").is_file());
+    assert!(dir.join("notes.md").is_file());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,695 @@
+use easy_error::{Error, ResultExt};
+use html_escape::{encode_double_quoted_attribute as attr, encode_text as text};
+use media_type::MediaType;
+use metadata::BookMetadata;
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::blocks::{parse_blocks, Align, Block, Inline};
+use super::{png_image, BookWriter, Page};
+
+/// Size of the uncompressed text records.
+const RECORD_SIZE: usize = 4096;
+/// Length of the MOBI header, not counting the PalmDOC header before it.
+const MOBI_HEADER_LENGTH: u32 = 232;
+/// Window of back references in PalmDOC compression.
+const MAX_DISTANCE: usize = 2047;
+const MAX_MATCH: usize = 10;
+
+const FLIS: &[u8] = b"FLIS\0\0\0\x08\0\x41\0\0\0\0\0\0\xff\xff\xff\xff\0\x01\0\x03\0\0\0\x03\0\0\0\x01\xff\xff\xff\xff";
+const EOF_RECORD: &[u8] = b"\xe9\x8e\x0d\x0a";
+
+/// EXTH record types.
+const EXTH_AUTHOR: u32 = 100;
+const EXTH_PUBLISHER: u32 = 101;
+const EXTH_DESCRIPTION: u32 = 103;
+const EXTH_SUBJECT: u32 = 105;
+const EXTH_DATE: u32 = 106;
+const EXTH_SOURCE: u32 = 112;
+const EXTH_ASIN: u32 = 113;
+const EXTH_COVER_OFFSET: u32 = 201;
+const EXTH_THUMB_OFFSET: u32 = 202;
+const EXTH_HAS_FAKE_COVER: u32 = 203;
+const EXTH_CDE_TYPE: u32 = 501;
+const EXTH_UPDATED_TITLE: u32 = 503;
+const EXTH_LANGUAGE: u32 = 524;
+
+/// Writes the book as MOBI, the format read by every Kindle device and app.
+pub struct MobiWriter {
+    metadata: BookMetadata,
+    cover: Option<(Vec<u8>, MediaType)>,
+    pages: Vec<Page>,
+    /// Images by their paths in the pages, with their types.
+    images: Vec<(String, Vec<u8>, Option<MediaType>)>,
+}
+
+/// Converts images to a format Kindles display: JPEG, PNG and GIF are fine, the rest is rendered to PNG.
+fn kindle_image(data: &[u8], media_type: Option<MediaType>) -> Option<Vec<u8>> {
+    match media_type {
+        Some(MediaType::Jpeg) | Some(MediaType::Png) | Some(MediaType::Gif) => Some(data.to_vec()),
+        _ => png_image(data, media_type),
+    }
+}
+
+/// Compresses a text record with the LZ77 variant of PalmDOC.
+fn palmdoc_compress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let key = |i: usize| [data[i], data[i + 1], data[i + 2]];
+    let mut positions: HashMap<[u8; 3], Vec<usize>> = HashMap::new();
+    let remember = |positions: &mut HashMap<[u8; 3], Vec<usize>>, start: usize, end: usize| {
+        for i in start..end.min(data.len().saturating_sub(2)) {
+            positions.entry(key(i)).or_default().push(i);
+        }
+    };
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut best = (0, 0);
+        let candidates = if i + 3 <= data.len() { positions.get(&key(i)) } else { None };
+        if let Some(candidates) = candidates {
+            for &candidate in candidates.iter().rev().take(64) {
+                let distance = i - candidate;
+                if distance > MAX_DISTANCE {
+                    break;
+                }
+                let length = (0..MAX_MATCH.min(data.len() - i)).take_while(|&k| data[candidate + k] == data[i + k]).count();
+                if length > best.0 {
+                    best = (length, distance);
+                }
+            }
+        }
+        if best.0 >= 3 {
+            let (length, distance) = best;
+            let pair = 0x8000 | (distance << 3) as u16 | (length - 3) as u16;
+            output.extend_from_slice(&pair.to_be_bytes());
+            remember(&mut positions, i, i + length);
+            i += length;
+            continue;
+        }
+
+        let byte = data[i];
+        if byte == b' ' && i + 1 < data.len() && (0x40..=0x7f).contains(&data[i + 1]) {
+            output.push(data[i + 1] ^ 0x80);
+            remember(&mut positions, i, i + 2);
+            i += 2;
+        } else if byte == 0 || (0x09..=0x7f).contains(&byte) {
+            output.push(byte);
+            remember(&mut positions, i, i + 1);
+            i += 1;
+        } else {
+            // Other bytes are copied as they are, in runs of up to 8 preceded by their count.
+            let run = data[i..].iter().take(8).take_while(|&&b| !(b == 0 || (0x09..=0x7f).contains(&b))).count();
+            output.push(run as u8);
+            output.extend_from_slice(&data[i..i + run]);
+            remember(&mut positions, i, i + run);
+            i += run;
+        }
+    }
+    output
+}
+
+/// Splits the text into compressed records. When a record ends inside a UTF-8 character,
+/// the rest of the character is repeated after it, followed by its size, as readers expect
+/// with the multibyte flag set in the header.
+fn text_records(text: &[u8]) -> Vec<Vec<u8>> {
+    text.chunks(RECORD_SIZE)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let end = i * RECORD_SIZE + chunk.len();
+            let overlap: Vec<u8> = text[end..].iter().take(3).take_while(|&&b| b & 0xc0 == 0x80).copied().collect();
+            let mut record = palmdoc_compress(chunk);
+            record.extend_from_slice(&overlap);
+            record.push(overlap.len() as u8);
+            record
+        })
+        .collect()
+}
+
+fn push_u16(data: &mut Vec<u8>, value: u16) {
+    data.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Identifier of the book in the MOBI header, a hash of the book identifier.
+fn unique_id(identifier: &str) -> u32 {
+    identifier.bytes().fold(0x811c9dc5, |hash: u32, byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+}
+
+/// Windows locale identifier of a language tag, which Kindles use to pick dictionaries.
+fn locale(language: &str) -> u32 {
+    match language {
+        "en-US" => 0x0409,
+        "en-GB" => 0x0809,
+        _ if language.starts_with("en") => 0x09,
+        _ => 0,
+    }
+}
+
+/// MOBI flavoured HTML for the book. Links point to byte offsets in the text with `filepos` attributes,
+/// which are only known once the whole text is written, so they are filled in at the end.
+struct MobiHtml<'a> {
+    html: String,
+    /// Offsets of the link targets, by the file names of the pages.
+    targets: HashMap<String, usize>,
+    /// Offsets of the placeholders in the links, with their targets.
+    links: Vec<(usize, String)>,
+    /// Record indexes of the images, by their paths.
+    image_indexes: &'a HashMap<String, usize>,
+}
+
+impl MobiHtml<'_> {
+    fn filepos(&mut self, target: &str) {
+        self.html.push_str("filepos=");
+        self.links.push((self.html.len(), target.to_string()));
+        self.html.push_str("0000000000");
+    }
+
+    fn link(&mut self, target: &str, title: &str) {
+        self.html.push_str("<a ");
+        self.filepos(target);
+        self.html.push_str(&format!(">{}</a>", text(title)));
+    }
+
+    fn image(&mut self, src: &str, alt: &str) {
+        match self.image_indexes.get(src) {
+            Some(index) => self.html.push_str(&format!("<img recindex=\"{:05}\" alt=\"{}\" />", index, attr(alt))),
+            None => self.html.push_str(&text(alt)),
+        }
+    }
+
+    fn inlines(&mut self, inlines: &[Inline]) {
+        for inline in inlines {
+            match inline {
+                Inline::Text(t) => self.html.push_str(&text(t)),
+                Inline::Emphasis(children) => self.wrapped("i", children),
+                Inline::Strong(children) => self.wrapped("b", children),
+                Inline::Strikethrough(children) => self.wrapped("s", children),
+                Inline::Link { href, children } => {
+                    let (file, fragment) = href.split_once('#').unwrap_or((href, ""));
+                    if file.contains(':') {
+                        self.html.push_str(&format!("<a href=\"{}\">", attr(href)));
+                    } else if file.ends_with(".xhtml") && fragment.is_empty() {
+                        self.html.push_str("<a ");
+                        self.filepos(file);
+                        self.html.push('>');
+                    } else {
+                        // Identifiers inside the pages are not kept.
+                        self.inlines(children);
+                        continue;
+                    }
+                    self.inlines(children);
+                    self.html.push_str("</a>");
+                }
+                Inline::Image { src, alt } => self.image(src, alt),
+                Inline::LineBreak => self.html.push_str("<br />"),
+            }
+        }
+    }
+
+    fn wrapped(&mut self, tag: &str, children: &[Inline]) {
+        self.html.push_str(&format!("<{tag}>"));
+        self.inlines(children);
+        self.html.push_str(&format!("</{tag}>"));
+    }
+
+    fn blocks(&mut self, blocks: &[Block]) {
+        for block in blocks {
+            match block {
+                Block::Paragraph { inlines, indent, align } => {
+                    self.html.push_str(&"<blockquote>".repeat(*indent));
+                    self.html.push_str(match align {
+                        Align::Left => "<p>",
+                        Align::Center => "<p align=\"center\">",
+                        Align::Right => "<p align=\"right\">",
+                    });
+                    self.inlines(inlines);
+                    self.html.push_str("</p>");
+                    self.html.push_str(&"</blockquote>".repeat(*indent));
+                }
+                Block::SceneBreak => self.html.push_str("<p align=\"center\">* * *</p>"),
+                Block::Heading { level, inlines } => {
+                    let level = (*level).clamp(2, 6);
+                    self.html.push_str(&format!("<h{level}>"));
+                    self.inlines(inlines);
+                    self.html.push_str(&format!("</h{level}>"));
+                }
+                Block::Figure { src, alt, caption } => {
+                    self.html.push_str("<p align=\"center\">");
+                    self.image(src, alt);
+                    self.html.push_str("</p>");
+                    if !caption.is_empty() {
+                        self.html.push_str("<p align=\"center\"><i>");
+                        self.inlines(caption);
+                        self.html.push_str("</i></p>");
+                    }
+                }
+                Block::List { ordered, items } => {
+                    let tag = if *ordered { "ol" } else { "ul" };
+                    self.html.push_str(&format!("<{tag}>"));
+                    for item in items {
+                        self.html.push_str("<li>");
+                        self.blocks(item);
+                        self.html.push_str("</li>");
+                    }
+                    self.html.push_str(&format!("</{tag}>"));
+                }
+                Block::Section { class, blocks } => {
+                    let quoted = class.split_whitespace().any(|c| c == "author-note");
+                    if quoted {
+                        self.html.push_str("<blockquote>");
+                    }
+                    self.blocks(blocks);
+                    if quoted {
+                        self.html.push_str("</blockquote>");
+                    }
+                }
+            }
+        }
+    }
+
+    fn target(&mut self, name: &str) {
+        self.targets.insert(name.to_string(), self.html.len());
+    }
+
+    /// Fills in the link offsets.
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = self.html.into_bytes();
+        for (offset, target) in self.links {
+            let position = self.targets.get(&target).copied().unwrap_or(0);
+            bytes[offset..offset + 10].copy_from_slice(format!("{position:010}").as_bytes());
+        }
+        bytes
+    }
+}
+
+impl MobiWriter {
+    pub fn new(metadata: BookMetadata) -> Self {
+        MobiWriter {
+            metadata,
+            cover: None,
+            pages: Vec::new(),
+            images: Vec::new(),
+        }
+    }
+
+    /// The text of the book: a title page, a table of contents, and the pages separated by page breaks.
+    fn text(&self, image_indexes: &HashMap<String, usize>) -> Vec<u8> {
+        let metadata = &self.metadata;
+        let mut html = MobiHtml {
+            html: String::from("<html><head><guide><reference type=\"toc\" title=\"Table of Contents\" "),
+            targets: HashMap::new(),
+            links: Vec::new(),
+            image_indexes,
+        };
+        html.filepos("toc");
+        html.html.push_str(" /><reference type=\"text\" title=\"Beginning\" ");
+        html.filepos(self.pages.first().map(|page| page.file.as_str()).unwrap_or("toc"));
+        html.html.push_str(" /></guide></head><body>");
+
+        html.html.push_str(&format!("<h1 align=\"center\">{}</h1><p align=\"center\">{}</p>", text(&metadata.title), text(&metadata.author)));
+        if !metadata.description.is_empty() {
+            html.html.push_str(&format!("<p>{}</p>", text(&metadata.description)));
+        }
+        html.html.push_str("<mbp:pagebreak />");
+
+        html.target("toc");
+        html.html.push_str("<h2>Contents</h2>");
+        for page in &self.pages {
+            let indent = page.level.max(1) - 1;
+            html.html.push_str(&"<blockquote>".repeat(indent));
+            html.html.push_str("<p>");
+            html.link(&page.file, &page.title);
+            html.html.push_str("</p>");
+            html.html.push_str(&"</blockquote>".repeat(indent));
+        }
+
+        for page in &self.pages {
+            html.html.push_str("<mbp:pagebreak />");
+            html.target(&page.file);
+            let heading = if page.level > 1 { "h2" } else { "h1" };
+            html.html.push_str(&format!("<{heading}>{}</{heading}>", text(&page.title)));
+            html.blocks(&parse_blocks(&page.body_text));
+        }
+        html.html.push_str("</body></html>");
+        html.into_bytes()
+    }
+
+    fn exth(&self, cover_index: Option<usize>) -> Vec<u8> {
+        let metadata = &self.metadata;
+        let mut records: Vec<(u32, Vec<u8>)> = vec![
+            (EXTH_AUTHOR, metadata.author.as_bytes().to_vec()),
+            (EXTH_DESCRIPTION, metadata.description.as_bytes().to_vec()),
+            (EXTH_DATE, metadata.date.as_bytes().to_vec()),
+            (EXTH_ASIN, metadata.identifier.trim_start_matches("urn:uuid:").as_bytes().to_vec()),
+            (EXTH_CDE_TYPE, b"EBOK".to_vec()),
+            (EXTH_UPDATED_TITLE, metadata.title.as_bytes().to_vec()),
+            (EXTH_LANGUAGE, metadata.language.as_bytes().to_vec()),
+        ];
+        if let Some(ref publisher) = metadata.publisher {
+            records.push((EXTH_PUBLISHER, publisher.as_bytes().to_vec()));
+        }
+        if let Some(ref source) = metadata.source {
+            records.push((EXTH_SOURCE, source.as_bytes().to_vec()));
+        }
+        for subject in &metadata.subjects {
+            records.push((EXTH_SUBJECT, subject.as_bytes().to_vec()));
+        }
+        if let Some(index) = cover_index {
+            records.push((EXTH_COVER_OFFSET, (index as u32).to_be_bytes().to_vec()));
+            records.push((EXTH_THUMB_OFFSET, (index as u32).to_be_bytes().to_vec()));
+            records.push((EXTH_HAS_FAKE_COVER, 0u32.to_be_bytes().to_vec()));
+        }
+
+        let length = 12 + records.iter().map(|(_, data)| 8 + data.len()).sum::<usize>();
+        let mut exth = b"EXTH".to_vec();
+        push_u32(&mut exth, length as u32);
+        push_u32(&mut exth, records.len() as u32);
+        for (kind, data) in records {
+            push_u32(&mut exth, kind);
+            push_u32(&mut exth, 8 + data.len() as u32);
+            exth.extend_from_slice(&data);
+        }
+        while !exth.len().is_multiple_of(4) {
+            exth.push(0);
+        }
+        exth
+    }
+
+    fn book(&self) -> Vec<u8> {
+        // Images are referred to by their position among the image records, starting with 1.
+        let mut images: Vec<Vec<u8>> = Vec::new();
+        let mut image_indexes = HashMap::new();
+        for (path, data, media_type) in &self.images {
+            if let Some(image) = kindle_image(data, *media_type) {
+                images.push(image);
+                image_indexes.insert(path.clone(), images.len());
+            }
+        }
+        let cover_index = self.cover.as_ref().and_then(|(cover, media_type)| kindle_image(cover, Some(*media_type))).map(|cover| {
+            images.push(cover);
+            images.len() - 1
+        });
+
+        let text = self.text(&image_indexes);
+        let text_records = text_records(&text);
+        let first_image = text_records.len() + 1;
+        let last_content = text_records.len() + images.len();
+        let flis = last_content + 1;
+        let fcis = last_content + 2;
+
+        let mut header = Vec::new();
+        // PalmDOC header, with PalmDOC compression.
+        push_u16(&mut header, 2);
+        push_u16(&mut header, 0);
+        push_u32(&mut header, text.len() as u32);
+        push_u16(&mut header, text_records.len() as u16);
+        push_u16(&mut header, RECORD_SIZE as u16);
+        push_u32(&mut header, 0);
+
+        let exth = self.exth(cover_index);
+        let full_name_offset = 16 + MOBI_HEADER_LENGTH as usize + exth.len();
+        header.extend_from_slice(b"MOBI");
+        push_u32(&mut header, MOBI_HEADER_LENGTH);
+        push_u32(&mut header, 2); // Book
+        push_u32(&mut header, 65001); // UTF-8
+        push_u32(&mut header, unique_id(&self.metadata.identifier));
+        push_u32(&mut header, 6); // File version
+        for _ in 0..10 {
+            // No indexes.
+            push_u32(&mut header, u32::MAX);
+        }
+        push_u32(&mut header, first_image as u32); // First non-book record
+        push_u32(&mut header, full_name_offset as u32);
+        push_u32(&mut header, self.metadata.title.len() as u32);
+        push_u32(&mut header, locale(&self.metadata.language));
+        push_u32(&mut header, 0); // Input language
+        push_u32(&mut header, 0); // Output language
+        push_u32(&mut header, 6); // Minimum reader version
+        push_u32(&mut header, if images.is_empty() { u32::MAX } else { first_image as u32 });
+        header.extend_from_slice(&[0; 16]); // No Huffman compression
+        push_u32(&mut header, 0x40); // EXTH present
+        header.extend_from_slice(&[0; 32]);
+        push_u32(&mut header, u32::MAX);
+        push_u32(&mut header, u32::MAX); // No DRM
+        header.extend_from_slice(&[0; 12]);
+        header.extend_from_slice(&[0; 8]);
+        push_u16(&mut header, 1); // First content record
+        push_u16(&mut header, last_content as u16);
+        push_u32(&mut header, 1);
+        push_u32(&mut header, fcis as u32);
+        push_u32(&mut header, 1);
+        push_u32(&mut header, flis as u32);
+        push_u32(&mut header, 1);
+        header.extend_from_slice(&[0; 8]);
+        push_u32(&mut header, u32::MAX);
+        push_u32(&mut header, 0);
+        push_u32(&mut header, u32::MAX);
+        push_u32(&mut header, u32::MAX);
+        push_u32(&mut header, 1); // Text records end with multibyte overlaps
+        push_u32(&mut header, u32::MAX); // No INDX record
+        debug_assert_eq!(header.len(), 16 + MOBI_HEADER_LENGTH as usize);
+        header.extend_from_slice(&exth);
+        header.extend_from_slice(self.metadata.title.as_bytes());
+        header.extend_from_slice(&[0, 0]);
+        while !header.len().is_multiple_of(4) {
+            header.push(0);
+        }
+
+        let mut fcis_record = b"FCIS\x00\x00\x00\x14\x00\x00\x00\x10\x00\x00\x00\x01\x00\x00\x00\x00".to_vec();
+        push_u32(&mut fcis_record, text.len() as u32);
+        fcis_record.extend_from_slice(b"\x00\x00\x00\x00\x00\x00\x00\x20\x00\x00\x00\x08\x00\x01\x00\x01\x00\x00\x00\x00");
+
+        let mut records = vec![header];
+        records.extend(text_records);
+        records.extend(images);
+        records.push(FLIS.to_vec());
+        records.push(fcis_record);
+        records.push(EOF_RECORD.to_vec());
+
+        self.palm_database(&records)
+    }
+
+    /// Wraps the records in a Palm database, which MOBI files are.
+    fn palm_database(&self, records: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut name: Vec<u8> = self.metadata.title.bytes().map(|b| if b.is_ascii_alphanumeric() { b } else { b'_' }).take(31).collect();
+        name.resize(32, 0);
+        data.extend_from_slice(&name);
+        push_u16(&mut data, 0); // Attributes
+        push_u16(&mut data, 0); // Version
+        let now = chrono::Utc::now().timestamp() as u32;
+        push_u32(&mut data, now); // Created
+        push_u32(&mut data, now); // Modified
+        data.extend_from_slice(&[0; 12]); // Backed up, modification number, app info
+        push_u32(&mut data, 0); // Sort info
+        data.extend_from_slice(b"BOOKMOBI");
+        push_u32(&mut data, (2 * records.len() - 1) as u32);
+        push_u32(&mut data, 0);
+        push_u16(&mut data, records.len() as u16);
+
+        let mut offset = data.len() + 8 * records.len() + 2;
+        for (i, record) in records.iter().enumerate() {
+            push_u32(&mut data, offset as u32);
+            push_u32(&mut data, 2 * i as u32); // Attributes and unique identifier
+            offset += record.len();
+        }
+        data.extend_from_slice(&[0, 0]);
+        for record in records {
+            data.extend_from_slice(record);
+        }
+        data
+    }
+}
+
+impl BookWriter for MobiWriter {
+    fn extension(&self) -> Option<&'static str> {
+        Some("mobi")
+    }
+
+    fn add_cover(&mut self, data: &[u8], media_type: MediaType) -> Result<(), Error> {
+        self.cover = Some((data.to_vec(), media_type));
+        Ok(())
+    }
+
+    fn add_page(&mut self, page: Page) -> Result<(), Error> {
+        self.pages.push(page);
+        Ok(())
+    }
+
+    fn add_resource(&mut self, path: &str, data: &[u8], content_type: &str) -> Result<(), Error> {
+        self.images.push((path.to_string(), data.to_vec(), MediaType::from_content_type(content_type)));
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, path: &Path) -> Result<(), Error> {
+        std::fs::write(path, self.book()).context("Could not write file")?;
+        Ok(())
+    }
+}
+
+/// Contents of a MOBI file, as read back by `parse_mobi`.
+#[cfg(test)]
+struct ParsedMobi {
+    name: String,
+    title: String,
+    text: Vec<u8>,
+    exth: Vec<(u32, Vec<u8>)>,
+    images: Vec<Vec<u8>>,
+}
+
+#[cfg(test)]
+fn palmdoc_decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let byte = data[i];
+        i += 1;
+        match byte {
+            0 | 0x09..=0x7f => output.push(byte),
+            0x01..=0x08 => {
+                output.extend_from_slice(&data[i..i + byte as usize]);
+                i += byte as usize;
+            }
+            0x80..=0xbf => {
+                let pair = u16::from_be_bytes([byte, data[i]]) as usize;
+                i += 1;
+                let distance = (pair >> 3) & 0x7ff;
+                for _ in 0..(pair & 7) + 3 {
+                    output.push(output[output.len() - distance]);
+                }
+            }
+            _ => {
+                output.push(b' ');
+                output.push(byte ^ 0x80);
+            }
+        }
+    }
+    output
+}
+
+/// Reads a MOBI file the way readers do, independently of how it is written.
+#[cfg(test)]
+fn parse_mobi(data: &[u8]) -> ParsedMobi {
+    let u16_at = |data: &[u8], offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]) as usize;
+    let u32_at = |data: &[u8], offset: usize| u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]) as usize;
+
+    assert_eq!(&data[60..68], b"BOOKMOBI");
+    let name = String::from_utf8(data[..32].iter().take_while(|&&b| b != 0).copied().collect()).unwrap();
+    let record_count = u16_at(data, 76);
+    let offsets: Vec<usize> = (0..record_count).map(|i| u32_at(data, 78 + 8 * i)).chain(std::iter::once(data.len())).collect();
+    let records: Vec<&[u8]> = offsets.windows(2).map(|w| &data[w[0]..w[1]]).collect();
+
+    let header = records[0];
+    assert_eq!(u16_at(header, 0), 2, "PalmDOC compression");
+    let text_length = u32_at(header, 4);
+    let text_record_count = u16_at(header, 8);
+    assert_eq!(&header[16..20], b"MOBI");
+    let header_length = u32_at(header, 20);
+    assert_eq!(u32_at(header, 28), 65001, "UTF-8");
+    let first_image = u32_at(header, 108);
+    let last_content = u16_at(header, 194);
+    let extra_flags = u32_at(header, 240);
+    let title = String::from_utf8(header[u32_at(header, 84)..][..u32_at(header, 88)].to_vec()).unwrap();
+
+    let exth_data = &header[16 + header_length..];
+    assert_eq!(&exth_data[..4], b"EXTH");
+    let mut exth = Vec::new();
+    let mut offset = 12;
+    for _ in 0..u32_at(exth_data, 8) {
+        let length = u32_at(exth_data, offset + 4);
+        exth.push((u32_at(exth_data, offset) as u32, exth_data[offset + 8..offset + length].to_vec()));
+        offset += length;
+    }
+
+    let mut text = Vec::new();
+    for record in &records[1..=text_record_count] {
+        let mut record = *record;
+        if extra_flags & 1 != 0 {
+            let trailing = (record[record.len() - 1] & 3) as usize + 1;
+            record = &record[..record.len() - trailing];
+        }
+        text.extend(palmdoc_decompress(record));
+    }
+    assert_eq!(text.len(), text_length);
+
+    let images = if first_image == u32::MAX as usize {
+        Vec::new()
+    } else {
+        records[first_image..=last_content].iter().map(|record| record.to_vec()).collect()
+    };
+
+    ParsedMobi { name, title, text, exth, images }
+}
+
+#[test]
+fn test_palmdoc_compression() {
+    let data = "It was a dark and stormy night; the rain fell in torrents — except at occasional intervals. ’’’ \x01\x02 It was a dark and stormy night.".repeat(20);
+    let compressed = palmdoc_compress(data.as_bytes());
+    assert!(compressed.len() < data.len() / 2);
+    assert_eq!(palmdoc_decompress(&compressed), data.as_bytes());
+
+    // 4096 is not a multiple of 3, so the first record ends inside a quote.
+    let quotes = "’".repeat(2000);
+    let records = text_records(quotes.as_bytes());
+    assert_eq!(&records[0][records[0].len() - 3..], b"\x80\x99\x02");
+    let text: Vec<u8> = records.iter().flat_map(|record| palmdoc_decompress(&record[..record.len() - (record[record.len() - 1] as usize + 1)])).collect();
+    assert_eq!(text, quotes.as_bytes());
+}
+
+#[test]
+fn test_mobi_round_trip() {
+    use writers::PageKind;
+
+    let mut writer = MobiWriter::new(BookMetadata {
+        title: "Worm".to_string(),
+        author: "Wildbow".to_string(),
+        author_file_as: None,
+        description: "Brockton Bay".to_string(),
+        language: "en-US".to_string(),
+        identifier: "urn:uuid:1".to_string(),
+        date: "2013-11-19".to_string(),
+        series: None,
+        subjects: vec!["Superheroes".to_string()],
+        publisher: Some("Wildbow".to_string()),
+        source: None,
+    });
+    let page = |file: &str, title: &str, body_text: &str| Page {
+        file: file.to_string(),
+        title: title.to_string(),
+        body_text: body_text.to_string(),
+        kind: PageKind::Chapter,
+        level: 1,
+        arc: None,
+        url: None,
+        fetched_at: None,
+    };
+    // Long enough for several text records, with curly quotes ending up split between them.
+    let long_text = "<p>“Taylor,” she said. It’s <em>fine</em>.</p>\n".repeat(300);
+    writer.add_page(page("chapter_1-1.xhtml", "Gestation 1.1", &format!("{long_text}<figure><img src=\"images/map.png\" alt=\"Map\"></figure>"))).unwrap();
+    writer.add_page(page("chapter_1-2.xhtml", "Gestation 1.2", "<p class=\"center\">■</p><p><a href=\"chapter_1-1.xhtml\">Back</a></p>")).unwrap();
+    let png = b"\x89PNG\r\n\x1a\nrest".to_vec();
+    let jpeg = b"\xff\xd8\xffrest".to_vec();
+    writer.add_resource("images/map.png", &png, "image/png").unwrap();
+    writer.add_cover(&jpeg, MediaType::Jpeg).unwrap();
+
+    let parsed = parse_mobi(&writer.book());
+    assert_eq!(parsed.name, "Worm");
+    assert_eq!(parsed.title, "Worm");
+    assert_eq!(parsed.images, vec![png, jpeg]);
+    assert!(parsed.exth.contains(&(EXTH_AUTHOR, b"Wildbow".to_vec())));
+    assert!(parsed.exth.contains(&(EXTH_COVER_OFFSET, vec![0, 0, 0, 1])));
+
+    let text = String::from_utf8(parsed.text).unwrap();
+    assert!(text.starts_with("<html><head><guide><reference type=\"toc\""));
+    assert!(text.contains("<p>“Taylor,” she said. It’s <i>fine</i>.</p>"));
+    assert!(text.contains("<p align=\"center\"><img recindex=\"00001\" alt=\"Map\" /></p>"));
+    assert!(text.contains("<p align=\"center\">* * *</p>"));
+
+    // Links point at the start of the page they lead to.
+    let link = "<p><a filepos=";
+    let back = &text[text.rfind(link).unwrap() + link.len()..][..10];
+    assert!(text[back.parse::<usize>().unwrap()..].starts_with("<h1>Gestation 1.1</h1><p>“Taylor,”"));
+}
@@ -0,0 +1,220 @@
+pub mod blocks;
+mod epub;
+mod fb2;
+mod html;
+mod json;
+mod latex;
+mod markdown;
+mod mobi;
+mod text;
+
+use cover::render_png;
+use easy_error::{err_msg, Error};
+use epub_builder::EpubVersion;
+use media_type::MediaType;
+use metadata::BookMetadata;
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+use std::path::Path;
+use std::str::FromStr;
+
+use self::epub::EpubWriter;
+use self::fb2::Fb2Writer;
+use self::html::HtmlWriter;
+use self::json::JsonWriter;
+use self::latex::LatexWriter;
+use self::markdown::MarkdownWriter;
+use self::mobi::MobiWriter;
+use self::text::TextWriter;
+pub use self::text::{TextFiles, TextOptions};
+
+/// Format the books are written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Epub,
+    /// The unpacked contents of an EPUB, for inspection and hand editing.
+    EpubDirectory,
+    /// Single self-contained HTML file, readable in any browser.
+    Html,
+    /// Directory with a Markdown file per page, for keeping the text under version control.
+    Markdown,
+    /// UTF-8 plain text, for text-to-speech and text analysis.
+    Text,
+    /// FictionBook 2, for FB2-centric reading apps.
+    Fb2,
+    /// MOBI, for Kindle devices and apps.
+    Mobi,
+    /// LaTeX source for typesetting print copies.
+    Latex,
+    /// JSON document with the pages, for tooling.
+    Json,
+    /// JSON Lines with an object per page.
+    JsonLines,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "epub" => Ok(Self::Epub),
+            "epub-dir" => Ok(Self::EpubDirectory),
+            "html" => Ok(Self::Html),
+            "markdown" => Ok(Self::Markdown),
+            "text" => Ok(Self::Text),
+            "fb2" => Ok(Self::Fb2),
+            "mobi" => Ok(Self::Mobi),
+            "latex" => Ok(Self::Latex),
+            "json" => Ok(Self::Json),
+            "jsonl" => Ok(Self::JsonLines),
+            _ => Err(err_msg(format!("Unknown output format {s}, expected one of: epub, epub-dir, html, markdown, text, fb2, mobi, latex, json, jsonl"))),
+        }
+    }
+}
+
+/// What a page of the book contains, for formats distinguishing the story from the rest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageKind {
+    Chapter,
+    /// Author’s notes, comments and other pages added by the scraper.
+    Notes,
+}
+
+/// A page of the book: a chapter, or one added by the scraper like the author’s notes appendix.
+#[derive(Clone, Debug)]
+pub struct Page {
+    /// Name of the file containing the page in EPUB. Links between pages refer to them by these names.
+    pub file: String,
+    pub title: String,
+    /// Contents of the page as XHTML, without the title heading.
+    pub body_text: String,
+    pub kind: PageKind,
+    /// Depth in the table of contents, starting with 1.
+    pub level: usize,
+    /// Arc the chapter belongs to.
+    pub arc: Option<String>,
+    /// Address the chapter was downloaded from.
+    pub url: Option<Url>,
+    /// When the chapter was downloaded.
+    pub fetched_at: Option<DateTime<Utc>>,
+}
+
+/// Receives the contents of a book as it is scraped, and writes it in some output format.
+/// Pages are added in reading order, resources like images can come at any time before `finish`.
+pub trait BookWriter {
+    /// Extension of the files produced, without the dot, or `None` when the book is written as a directory.
+    fn extension(&self) -> Option<&'static str>;
+
+    fn add_cover(&mut self, data: &[u8], media_type: MediaType) -> Result<(), Error>;
+
+    fn add_page(&mut self, page: Page) -> Result<(), Error>;
+
+    /// Adds a file the pages refer to by `path`, e.g. an image.
+    fn add_resource(&mut self, path: &str, data: &[u8], content_type: &str) -> Result<(), Error>;
+
+    /// Writes the book to `path`.
+    fn finish(self: Box<Self>, path: &Path) -> Result<(), Error>;
+}
+
+/// Settings of the individual output formats.
+#[derive(Clone, Debug)]
+pub struct WriterOptions {
+    pub epub_version: EpubVersion,
+    pub text: TextOptions,
+}
+
+/// Creates a writer for given output format.
+pub fn new_writer(format: OutputFormat, metadata: BookMetadata, options: &WriterOptions) -> Result<Box<dyn BookWriter>, Error> {
+    Ok(match format {
+        OutputFormat::Epub => Box::new(EpubWriter::new(metadata, options.epub_version, false)?),
+        OutputFormat::EpubDirectory => Box::new(EpubWriter::new(metadata, options.epub_version, true)?),
+        OutputFormat::Html => Box::new(HtmlWriter::new(metadata)),
+        OutputFormat::Markdown => Box::new(MarkdownWriter::new(metadata)),
+        OutputFormat::Text => Box::new(TextWriter::new(metadata, options.text.clone())),
+        OutputFormat::Fb2 => Box::new(Fb2Writer::new(metadata)),
+        OutputFormat::Mobi => Box::new(MobiWriter::new(metadata)),
+        OutputFormat::Latex => Box::new(LatexWriter::new(metadata)),
+        OutputFormat::Json => Box::new(JsonWriter::new(metadata, false)),
+        OutputFormat::JsonLines => Box::new(JsonWriter::new(metadata, true)),
+    })
+}
+
+/// Renders an image as PNG, for formats not supporting its type. SVG is rasterized, the rest decoded.
+/// Returns `None` for images that cannot be read.
+fn png_image(data: &[u8], media_type: Option<MediaType>) -> Option<Vec<u8>> {
+    if media_type == Some(MediaType::Svg) {
+        return render_png(&String::from_utf8_lossy(data)).ok();
+    }
+    let img = image::load_from_memory(data).ok()?;
+    let mut png = Vec::new();
+    img.write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png).ok()?;
+    Some(png)
+}
+
+/// Styles shared by the output formats using CSS.
+pub const STYLESHEET: &str = "
+        .indent-one {
+            margin-left: 2em;
+        }
+        .indent-two {
+            margin-left: 4em;
+        }
+        .aligncenter, .center {
+            text-align: center;
+        }
+        .right {
+            text-align: right;
+        }
+        a.endnote-ref {
+            text-decoration: none;
+        }
+        section.endnotes, .section.endnotes {
+            font-size: 0.8em;
+            word-wrap: break-word;
+        }
+        figure, .figure {
+            margin: 1em 0;
+            text-align: center;
+        }
+        figure img, .figure img {
+            max-width: 100%;
+            height: auto;
+        }
+        figcaption, .figcaption {
+            font-size: 0.9em;
+            font-style: italic;
+        }
+        .missing-image {
+            color: #888;
+            font-style: italic;
+        }
+        .size-full {
+            width: 100%;
+            height: auto;
+            object-fit: contain;
+        }
+        aside.author-note, .aside.author-note {
+            margin: 2em 0 0 0;
+            padding: 0.5em 1em;
+            border-left: 0.25em solid #888;
+            font-size: 0.9em;
+            font-style: italic;
+        }
+        ol.comments {
+            list-style: none;
+            padding-left: 0;
+        }
+        ol.comments ol.comments {
+            padding-left: 1em;
+            border-left: 0.1em solid #888;
+        }
+        .comment-meta {
+            font-weight: bold;
+            margin-bottom: 0;
+        }
+        .comment-date {
+            font-weight: normal;
+            font-size: 0.8em;
+        }
+    ";
@@ -0,0 +1,264 @@
+use easy_error::{err_msg, Error, ResultExt};
+use media_type::MediaType;
+use metadata::BookMetadata;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::blocks::{parse_blocks, Align, Block, Inline};
+use super::{BookWriter, Page};
+
+/// Whether plain text goes into a single file or a file per page.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TextFiles {
+    #[default]
+    Single,
+    /// A directory with a file per page, numbered in reading order.
+    Chapters,
+}
+
+impl FromStr for TextFiles {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "single" => Ok(Self::Single),
+            "chapters" => Ok(Self::Chapters),
+            _ => Err(err_msg(format!("Unknown text files mode {s}, expected one of: single, chapters"))),
+        }
+    }
+}
+
+/// Settings of the plain text output.
+#[derive(Clone, Debug)]
+pub struct TextOptions {
+    /// Maximum line length in characters, 0 keeps each paragraph on a single line.
+    pub width: usize,
+    pub files: TextFiles,
+    /// Line written in place of scene breaks.
+    pub scene_break: String,
+    /// Template of the line starting each page, with `{title}`, `{arc}` and `{index}` placeholders. Empty for none.
+    pub header: String,
+}
+
+impl Default for TextOptions {
+    fn default() -> Self {
+        TextOptions {
+            width: 0,
+            files: TextFiles::Single,
+            scene_break: "* * *".to_string(),
+            header: "{title}".to_string(),
+        }
+    }
+}
+
+/// Writes the book as UTF-8 plain text, for text-to-speech engines and text analysis.
+pub struct TextWriter {
+    metadata: BookMetadata,
+    options: TextOptions,
+    pages: Vec<Page>,
+}
+
+fn inline_text(inlines: &[Inline]) -> String {
+    let mut text = String::new();
+    for inline in inlines {
+        match inline {
+            Inline::Text(t) => text.push_str(t),
+            Inline::Emphasis(children) | Inline::Strong(children) | Inline::Strikethrough(children) | Inline::Link { children, .. } => {
+                text.push_str(&inline_text(children));
+            }
+            Inline::Image { alt, .. } if alt.is_empty() => text.push_str("[Image]"),
+            Inline::Image { alt, .. } => text.push_str(&format!("[Image: {alt}]")),
+            Inline::LineBreak => text.push('\n'),
+        }
+    }
+    text
+}
+
+/// Breaks a line at spaces so that it fits in `width` characters, words longer than that get a line of their own.
+fn wrap_line(line: &str, width: usize) -> Vec<String> {
+    if width == 0 || line.chars().count() <= width {
+        return vec![line.to_string()];
+    }
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    for word in line.split(' ').filter(|word| !word.is_empty()) {
+        if !current.is_empty() && current.chars().count() + 1 + word.chars().count() > width {
+            lines.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(word);
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+    lines
+}
+
+/// Lays out the text of a paragraph: wrapped to fit the width after the indentation, and aligned.
+fn layout(text: &str, indent: usize, align: Align, width: usize) -> String {
+    let available = if width == 0 { 0 } else { width.saturating_sub(indent).max(1) };
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        for wrapped in wrap_line(line.trim(), available) {
+            let padding = match align {
+                Align::Left => 0,
+                Align::Center => available.saturating_sub(wrapped.chars().count()) / 2,
+                Align::Right => available.saturating_sub(wrapped.chars().count()),
+            };
+            lines.push(format!("{}{wrapped}", " ".repeat(indent + padding)));
+        }
+    }
+    lines.join("\n")
+}
+
+fn render_block(block: &Block, indent: usize, options: &TextOptions) -> String {
+    match block {
+        Block::Paragraph { inlines, indent: level, align } => layout(&inline_text(inlines), indent + 4 * level, *align, options.width),
+        Block::SceneBreak => layout(&options.scene_break, indent, Align::Center, options.width),
+        Block::Heading { inlines, .. } => layout(&inline_text(inlines), indent, Align::Left, options.width),
+        Block::Figure { alt, caption, .. } => {
+            let mut text = if alt.is_empty() { "[Image]".to_string() } else { format!("[Image: {alt}]") };
+            if !caption.is_empty() {
+                text.push('\n');
+                text.push_str(&inline_text(caption));
+            }
+            layout(&text, indent, Align::Center, options.width)
+        }
+        Block::List { ordered, items } => items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| {
+                let marker = if *ordered { format!("{}. ", i + 1) } else { "- ".to_string() };
+                let content = render_blocks(item, indent + marker.chars().count(), options);
+                // Put the marker in the indentation of the first line.
+                let skip = (indent + marker.chars().count()).min(content.len() - content.trim_start_matches(' ').len());
+                format!("{}{marker}{}", " ".repeat(indent), &content[skip..])
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Block::Section { blocks, .. } => render_blocks(blocks, indent, options),
+    }
+}
+
+fn render_blocks(blocks: &[Block], indent: usize, options: &TextOptions) -> String {
+    blocks.iter().map(|block| render_block(block, indent, options)).filter(|text| !text.trim().is_empty()).collect::<Vec<_>>().join("\n\n")
+}
+
+/// Converts the XHTML body of a page to plain text, with paragraphs separated by blank lines.
+pub fn text_body(body_text: &str, options: &TextOptions) -> String {
+    render_blocks(&parse_blocks(body_text), 0, options)
+}
+
+impl TextWriter {
+    pub fn new(metadata: BookMetadata, options: TextOptions) -> Self {
+        TextWriter { metadata, options, pages: Vec::new() }
+    }
+
+    fn page_text(&self, page: &Page, index: usize) -> String {
+        let mut text = String::new();
+        if !self.options.header.is_empty() {
+            let header = self.options.header
+                .replace("{title}", &page.title)
+                .replace("{arc}", page.arc.as_deref().unwrap_or(""))
+                .replace("{index}", &index.to_string());
+            text.push_str(&layout(&header, 0, Align::Left, self.options.width));
+            text.push_str("\n\n");
+        }
+        text.push_str(&text_body(&page.body_text, &self.options));
+        text.push('\n');
+        text
+    }
+
+    fn book_text(&self) -> String {
+        let mut text = format!("{}\nby {}\n", self.metadata.title, self.metadata.author);
+        if !self.metadata.description.is_empty() {
+            text.push('\n');
+            text.push_str(&layout(&self.metadata.description, 0, Align::Left, self.options.width));
+            text.push('\n');
+        }
+        for (i, page) in self.pages.iter().enumerate() {
+            text.push_str("\n\n\n");
+            text.push_str(&self.page_text(page, i + 1));
+        }
+        text
+    }
+}
+
+impl BookWriter for TextWriter {
+    fn extension(&self) -> Option<&'static str> {
+        match self.options.files {
+            TextFiles::Single => Some("txt"),
+            TextFiles::Chapters => None,
+        }
+    }
+
+    fn add_cover(&mut self, _data: &[u8], _media_type: MediaType) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn add_page(&mut self, page: Page) -> Result<(), Error> {
+        self.pages.push(page);
+        Ok(())
+    }
+
+    fn add_resource(&mut self, _path: &str, _data: &[u8], _content_type: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, path: &Path) -> Result<(), Error> {
+        match self.options.files {
+            TextFiles::Single => {
+                std::fs::write(path, self.book_text()).context("Could not write file")?;
+            }
+            TextFiles::Chapters => {
+                std::fs::create_dir_all(path).context(format!("Could not create directory {}", path.display()))?;
+                for (i, page) in self.pages.iter().enumerate() {
+                    let stem = page.file.strip_suffix(".xhtml").unwrap_or(&page.file);
+                    let file = path.join(format!("{:04}-{stem}.txt", i + 1));
+                    std::fs::write(&file, self.page_text(page, i + 1)).context(format!("Could not write file {}", file.display()))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_text_body() {
+    let body_text = concat!(
+        "<p>It was <em>a dark</em> and stormy night; the rain fell in torrents.</p>\n",
+        "<p class=\"center\">■</p>\n",
+        "<p class=\"indent-one\">Indented<br/>twice</p>\n",
+        "<figure><img src=\"images/map.png\" alt=\"Map\"/><figcaption>The city</figcaption></figure>\n",
+        "<ol><li>First</li><li>Second</li></ol>\n",
+    );
+
+    assert_eq!(
+        text_body(body_text, &TextOptions::default()),
+        concat!(
+            "It was a dark and stormy night; the rain fell in torrents.\n\n",
+            "* * *\n\n",
+            "    Indented\n    twice\n\n",
+            "[Image: Map]\nThe city\n\n",
+            "1. First\n2. Second",
+        ),
+    );
+
+    let options = TextOptions {
+        width: 20,
+        scene_break: "#".to_string(),
+        ..TextOptions::default()
+    };
+    assert_eq!(
+        text_body(body_text, &options),
+        concat!(
+            "It was a dark and\nstormy night; the\nrain fell in\ntorrents.\n\n",
+            "         #\n\n",
+            "    Indented\n    twice\n\n",
+            "    [Image: Map]\n      The city\n\n",
+            "1. First\n2. Second",
+        ),
+    );
+}
@@ -6,7 +6,7 @@ use scraper::{ElementRef, Node};
 use scraper::{Html, Selector};
 use xml5ever::serialize::{serialize, SerializeOpts, TraversalScope};
 
-pub(crate) trait XmlSerializable {
+pub trait XmlSerializable {
     fn serialize_xml(&self, traversal_scope: TraversalScope) -> String;
 
     /// Returns the XML of this element.
@@ -25,14 +25,30 @@ impl<'a> XmlSerializable for ElementRef<'a> {
         let opts = SerializeOpts { traversal_scope };
         let mut buf = Vec::new();
         serialize(&mut buf, self, opts).unwrap();
-        String::from_utf8(buf)
+        let xml = String::from_utf8(buf)
             .unwrap()
             // The serializer does not support XML fragments,
             // we need to remove redundant namespace attributes.
-            .replace(r#" xmlns="http://www.w3.org/1999/xhtml""#, "")
+            .replace(r#" xmlns="http://www.w3.org/1999/xhtml""#, "");
+        self_close_void_elements(&xml)
     }
 }
 
+/// HTML elements that cannot have any content.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track", "wbr",
+];
+
+/// Turns `<br></br>` written by the XML serializer into `<br/>`.
+/// The output is parsed as HTML again by later stages, and the HTML parser reads `</br>` as another `<br>`.
+fn self_close_void_elements(xml: &str) -> String {
+    let mut xml = xml.to_string();
+    for name in VOID_ELEMENTS {
+        xml = xml.replace(&format!("></{name}>"), "/>");
+    }
+    xml
+}
+
 /// Creates a qualified name for a HTML element.
 pub fn html_elem_name(name: &str) -> QualName {
     QualName::new(None, ns!(html), LocalName::from(name))
@@ -44,7 +60,7 @@ pub fn html_attr_name(name: &str) -> QualName {
 }
 
 /// Helper trait that will allow us to call `text_filter` on `ElementRef`.
-pub(crate) trait FilterableTree<'a, P> {
+pub trait FilterableTree<'a, P> {
     fn text_filter(&self, is_allowed: P) -> TextFiltered<'a, P>
     where
         Self: Sized,
@@ -89,16 +105,12 @@ where
             match edge {
                 Edge::Open(node) => {
                     match node.value() {
-                        Node::Element(ref element) => {
-                            if !(self.is_allowed)(element) || self.is_in_filtered > 0 {
-                                // If we enter a forbidden element, or dive in deeper inside it.
-                                self.is_in_filtered += 1;
-                            }
+                        Node::Element(ref element) if !(self.is_allowed)(element) || self.is_in_filtered > 0 => {
+                            // If we enter a forbidden element, or dive in deeper inside it.
+                            self.is_in_filtered += 1;
                         }
-                        Node::Text(ref text) => {
-                            if self.is_in_filtered == 0 {
-                                return Some(&**text);
-                            }
+                        Node::Text(ref text) if self.is_in_filtered == 0 => {
+                            return Some(&**text);
                         }
                         _ => {
                             // Other types of nodes do not affect what is yielded
@@ -117,6 +129,15 @@ where
     }
 }
 
+#[test]
+fn test_void_elements_survive_reparsing() {
+    let mut xml = "<p>One<br>two <img src=\"a.png\"> three</p><hr>".to_string();
+    for _ in 0..3 {
+        xml = Html::parse_fragment(&xml).root_element().inner_xml();
+    }
+    assert_eq!(xml, "<p>One<br/>two <img src=\"a.png\"/> three</p><hr/>");
+}
+
 #[test]
 fn test_filter_text_iterator() {
     let doc = Html::parse_fragment(